@@ -20,30 +20,50 @@ PlasmoidItem {
     // ------------------------------------------------------------------
     // Service availability watcher
     // ------------------------------------------------------------------
+    // The service normally runs on the session bus; `kairpodsd --system` serves
+    // all users from the system bus instead.
     DBus.DBusServiceWatcher {
         id: serviceWatcher
         busType: DBus.BusType.Session
         watchedService: "org.kairpods"
+    }
 
-        onRegisteredChanged: {
-            if (registered) {
-                console.log("kAirPods service is available")
-                managerProps.updateAll()
-            } else {
-                console.log("kAirPods service is not available")
-                devices = {}
-                selectedDevice = ""
-                connectedCount = 0
-            }
+    DBus.DBusServiceWatcher {
+        id: systemServiceWatcher
+        busType: DBus.BusType.System
+        watchedService: "org.kairpods"
+    }
+
+    readonly property bool serviceAvailable: serviceWatcher.registered || systemServiceWatcher.registered
+    readonly property bool onSystemBus: !serviceWatcher.registered && systemServiceWatcher.registered
+
+    onServiceAvailableChanged: {
+        if (serviceAvailable) {
+            console.log("kAirPods service is available")
+            managerProps.updateAll()
+        } else {
+            console.log("kAirPods service is not available")
+            devices = {}
+            selectedDevice = ""
+            connectedCount = 0
         }
     }
 
+    onOnSystemBusChanged: {
+        if (serviceAvailable)
+            managerProps.updateAll()
+    }
+
+    function bus() {
+        return onSystemBus ? DBus.SystemBus : DBus.SessionBus
+    }
+
     // ------------------------------------------------------------------
     // D-Bus properties bridge (org.kairpods.manager)
     // ------------------------------------------------------------------
     DBus.Properties {
         id: managerProps
-        busType: DBus.BusType.Session
+        busType: root.onSystemBus ? DBus.BusType.System : DBus.BusType.Session
         service: "org.kairpods"
         path: "/org/kairpods/manager"
         iface: "org.kairpods.manager"
@@ -104,8 +124,8 @@ PlasmoidItem {
     // D-Bus helpers (fire-and-forget – updates come via PropertiesChanged)
     // ------------------------------------------------------------------
     function sendCommand(action, params) {
-        if (!selectedDevice || !serviceWatcher.registered) return
-        DBus.SessionBus.asyncCall({
+        if (!selectedDevice || !serviceAvailable) return
+        bus().asyncCall({
             service: "org.kairpods",
             path: "/org/kairpods/manager",
             iface: "org.kairpods.manager",
@@ -115,8 +135,8 @@ PlasmoidItem {
     }
 
     function connectDevice(address) {
-        if (!serviceWatcher.registered) return
-        DBus.SessionBus.asyncCall({
+        if (!serviceAvailable) return
+        bus().asyncCall({
             service: "org.kairpods",
             path: "/org/kairpods/manager",
             iface: "org.kairpods.manager",
@@ -126,8 +146,8 @@ PlasmoidItem {
     }
 
     function disconnectDevice(address) {
-        if (!serviceWatcher.registered) return
-        DBus.SessionBus.asyncCall({
+        if (!serviceAvailable) return
+        bus().asyncCall({
             service: "org.kairpods",
             path: "/org/kairpods/manager",
             iface: "org.kairpods.manager",
@@ -170,7 +190,7 @@ PlasmoidItem {
         // Service unavailable view
         ColumnLayout {
             anchors.centerIn: parent
-            visible: !serviceWatcher.registered
+            visible: !root.serviceAvailable
             spacing: Kirigami.Units.largeSpacing
 
             Kirigami.Icon {
@@ -205,7 +225,7 @@ PlasmoidItem {
         // Normal device view
         FullView {
             anchors.fill: parent
-            visible: serviceWatcher.registered
+            visible: root.serviceAvailable
 
             devices: root.devices
             selectedDevice: root.selectedDevice
@@ -228,7 +248,7 @@ PlasmoidItem {
     }
 
     Component.onCompleted: {
-        if (serviceWatcher.registered) {
+        if (serviceAvailable) {
             managerProps.updateAll()
         }
     }
@@ -8,6 +8,7 @@ PROJECT_ROOT="$(dirname "$SCRIPT_DIR")"
 
 # Service identifiers
 SERVICE_ID="kairpodsd"
+CTL_ID="kairpodsctl"
 PLASMOID_ID="org.kairpods.plasma"
 OLD_SERVICE_ID="kde-airpods-service"
 OLD_PLASMOID_ID="org.kde.plasma.airpods"
@@ -16,6 +17,7 @@ OLD_PLASMOID_ID="org.kde.plasma.airpods"
 BUILD_MODE="release"
 INSTALL_SERVICE=true
 INSTALL_WIDGET=true
+SYSTEM_BUS=false
 PREFIX="/usr"
 DEBUG=false
 
@@ -55,6 +57,7 @@ Options:
     -x, --verbose       Enable verbose output for debugging
     --no-service        Skip service installation
     --no-widget         Skip widget installation
+    --system-bus        Run the service on the system bus, shared by all users
     --prefix PATH       Installation prefix (default: /usr)
     --uninstall         Uninstall kAirPods
 
@@ -89,6 +92,10 @@ while [[ $# -gt 0 ]]; do
             INSTALL_WIDGET=false
             shift
             ;;
+        --system-bus)
+            SYSTEM_BUS=true
+            shift
+            ;;
         --prefix)
             PREFIX="$2"
             shift 2
@@ -116,11 +123,21 @@ uninstall_kairpods() {
         systemctl --user disable "$SERVICE_ID" || true
     fi
 
+    if systemctl is-enabled "$SERVICE_ID" &>/dev/null; then
+        log_step "Stopping and disabling system service..."
+        sudo systemctl stop "$SERVICE_ID" || true
+        sudo systemctl disable "$SERVICE_ID" || true
+    fi
+
     # Remove service files
     log_step "Removing service files..."
-    sudo rm -f "$PREFIX/bin/$SERVICE_ID"
+    sudo rm -f "$PREFIX/bin/$SERVICE_ID" "$PREFIX/bin/$CTL_ID"
     rm -f "$HOME/.config/systemd/user/${SERVICE_ID}.service"
+    sudo rm -f "/etc/systemd/system/${SERVICE_ID}.service" \
+        "/usr/share/dbus-1/system.d/org.kairpods.conf" \
+        "/usr/share/polkit-1/actions/org.kairpods.policy"
     systemctl --user daemon-reload
+    sudo systemctl daemon-reload
 
     # Remove widget
     if kpackagetool6 --type Plasma/Applet --list | grep -q "$PLASMOID_ID"; then
@@ -381,17 +398,19 @@ if [[ "$INSTALL_SERVICE" == "true" ]]; then
 
     if [[ "$BUILD_MODE" == "release" ]]; then
         cargo build --release --locked
-        BINARY_PATH="target/release/$SERVICE_ID"
+        BINARY_DIR="target/release"
     else
         cargo build
-        BINARY_PATH="target/debug/$SERVICE_ID"
+        BINARY_DIR="target/debug"
     fi
+    BINARY_PATH="$BINARY_DIR/$SERVICE_ID"
 
     log_info "✓ Service built successfully"
 
     # Install service binary
     log_step "Installing service binary..."
     sudo install -Dm755 "$BINARY_PATH" "$PREFIX/bin/$SERVICE_ID"
+    sudo install -Dm755 "$BINARY_DIR/$CTL_ID" "$PREFIX/bin/$CTL_ID"
     log_info "✓ Service binary installed"
 
     # Set capabilities if bluetooth group doesn't exist
@@ -408,34 +427,67 @@ if [[ "$INSTALL_SERVICE" == "true" ]]; then
         fi
     fi
 
-    # Install systemd user service
-    log_step "Installing systemd service..."
-    mkdir -p "$HOME/.config/systemd/user/"
-    # If PREFIX is not /usr, update the service file path
-    if [[ "$PREFIX" != "/usr" ]]; then
-        sed "s:/usr/bin:$PREFIX/bin:g" "systemd/user/${SERVICE_ID}.service" > "$HOME/.config/systemd/user/${SERVICE_ID}.service"
-        chmod 644 "$HOME/.config/systemd/user/${SERVICE_ID}.service"
+    if [[ "$SYSTEM_BUS" == "true" ]]; then
+        # Install systemd system service with its D-Bus and polkit policies
+        log_step "Installing system bus service..."
+        sudo install -Dm644 "dbus/org.kairpods.conf" "/usr/share/dbus-1/system.d/org.kairpods.conf"
+        # Only members of this group may reach the service, see the D-Bus policy
+        if ! getent group kairpods >/dev/null 2>&1; then
+            sudo groupadd --system kairpods
+        fi
+        if ! groups "$USER" | grep -q '\bkairpods\b'; then
+            sudo usermod -aG kairpods "$USER"
+            log_warn "Added $USER to the kairpods group, log out and back in to use the service"
+        fi
+        sudo install -Dm644 "polkit/org.kairpods.policy" "/usr/share/polkit-1/actions/org.kairpods.policy"
+        sed "s:/usr/bin:$PREFIX/bin:g" "systemd/system/${SERVICE_ID}.service" |
+            sudo tee "/etc/systemd/system/${SERVICE_ID}.service" > /dev/null
+        sudo systemctl daemon-reload
+        log_info "✓ System service installed"
+
+        # A per-user instance would compete for the same devices
+        if systemctl --user is-enabled "$SERVICE_ID" &>/dev/null; then
+            systemctl --user disable --now "$SERVICE_ID" || true
+        fi
+
+        cd "$PROJECT_ROOT"
+        log_step "Starting service..."
+        sudo systemctl enable --now "$SERVICE_ID"
+        sudo systemctl restart "$SERVICE_ID"
+        SYSTEMCTL=(sudo systemctl)
+        JOURNALCTL="journalctl -u $SERVICE_ID -f"
     else
-        install -Dm644 "systemd/user/${SERVICE_ID}.service" "$HOME/.config/systemd/user/"
+        # Install systemd user service
+        log_step "Installing systemd service..."
+        mkdir -p "$HOME/.config/systemd/user/"
+        # If PREFIX is not /usr, update the service file path
+        if [[ "$PREFIX" != "/usr" ]]; then
+            sed "s:/usr/bin:$PREFIX/bin:g" "systemd/user/${SERVICE_ID}.service" > "$HOME/.config/systemd/user/${SERVICE_ID}.service"
+            chmod 644 "$HOME/.config/systemd/user/${SERVICE_ID}.service"
+        else
+            install -Dm644 "systemd/user/${SERVICE_ID}.service" "$HOME/.config/systemd/user/"
+        fi
+        systemctl --user daemon-reload
+        log_info "✓ Systemd service installed"
+
+        # Return to project root
+        cd "$PROJECT_ROOT"
+
+        # Enable and start service
+        log_step "Starting service..."
+        systemctl --user enable --now "$SERVICE_ID"
+        systemctl --user restart "$SERVICE_ID"
+        SYSTEMCTL=(systemctl --user)
+        JOURNALCTL="journalctl --user -u $SERVICE_ID -f"
     fi
-    systemctl --user daemon-reload
-    log_info "✓ Systemd service installed"
-
-    # Return to project root
-    cd "$PROJECT_ROOT"
-
-    # Enable and start service
-    log_step "Starting service..."
-    systemctl --user enable --now "$SERVICE_ID"
-    systemctl --user restart "$SERVICE_ID"
 
     # Check service status
     sleep 1
-    if systemctl --user is-active --quiet "$SERVICE_ID"; then
+    if "${SYSTEMCTL[@]}" is-active --quiet "$SERVICE_ID"; then
         log_info "✓ Service is running"
     else
         log_error "Service failed to start. Check logs with:"
-        echo "  journalctl --user -u $SERVICE_ID -f"
+        echo "  $JOURNALCTL"
         echo -e "\n${YELLOW}Common issues:${NC}"
         echo "- Ensure you're in the bluetooth group (see above)"
         echo "- Make sure Bluetooth is enabled"
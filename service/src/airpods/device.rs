@@ -5,13 +5,13 @@
 
 use core::fmt;
 use std::{
-   collections::HashMap,
+   collections::{BTreeMap, HashMap},
    mem,
    sync::{
       Arc, Weak,
-      atomic::{AtomicBool, Ordering},
+      atomic::{AtomicBool, AtomicUsize, Ordering},
    },
-   time::Duration,
+   time::{Duration, Instant},
 };
 
 use bluer::Address;
@@ -27,20 +27,37 @@ use tokio::{
 
 use crate::{
    airpods::{
+      hearing::HearingAidProfile,
       parser,
       protocol::{
-         BatteryInfo, EarDetectionStatus, FeatureBitmap, FeatureCmd, FeatureId, HDR_ACK_FEATURES,
-         HDR_ACK_HANDSHAKE, HDR_BATTERY_STATE, HDR_EAR_DETECTION, HDR_METADATA, HDR_NOISE_CTL,
-         NoiseControlMode, PKT_HANDSHAKE, PKT_REQUEST_NOTIFY, PKT_SET_FEATURES,
-         build_control_packet,
+         BatteryInfo, Component, DeviceModel, EarDetectionStatus, EarTipFit, FEATURE_SWEEP,
+         FeatureBitmap, FeatureCmd, FeatureId, FeatureProbe, FitTestResult, HDR_ACK_FEATURES,
+         HDR_ACK_HANDSHAKE, HDR_BATTERY_STATE, HDR_EAR_DETECTION, HDR_FIT_TEST_RESULT,
+         HDR_HEARING_AID, HDR_METADATA, HDR_NOISE_CTL, HDR_TRANSPARENCY, NoiseControlMode,
+         PKT_HANDSHAKE, PKT_REQUEST_NOTIFY, PKT_SET_FEATURES, PKT_START_FIT_TEST, Setting,
+         TransparencySettings, build_control_packet, parse_control_packet,
       },
    },
    battery_study::{BatteryStudy, BatteryTracker},
    bluetooth::l2cap::{self, L2CapReceiver, L2CapSender, Packet},
+   config::{DeviceProfile, TransparencyProfile},
    error::{AirPodsError, Result},
    event::{AirPodsEvent, EventSender},
 };
 
+/// Delay after the notification request before applying the profile and discovering features
+const FEATURE_DISCOVERY_DELAY: Duration = Duration::from_secs(3);
+/// Maximum time to wait for the answer to a single feature query
+const FEATURE_QUERY_TIMEOUT: Duration = Duration::from_millis(250);
+/// Pause between consecutive feature queries
+const FEATURE_QUERY_PACING: Duration = Duration::from_millis(25);
+/// Maximum time to wait for the ear-tip fit test to complete
+const FIT_TEST_TIMEOUT: Duration = Duration::from_secs(15);
+/// Interval at which the state of a connected device is persisted
+const STATE_SAVE_INTERVAL: Duration = Duration::from_secs(300);
+/// How long the profile waits for the battery status before being applied anyway
+const PROFILE_BATTERY_TIMEOUT: Duration = Duration::from_secs(20);
+
 /// Internal state for an active L2CAP connection.
 #[derive(Debug)]
 struct ConnectionState {
@@ -54,20 +71,132 @@ impl Drop for ConnectionState {
    }
 }
 
+/// State of the AAP connection, as driven by the Bluetooth manager.
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub enum AAPState {
+   #[default]
+   Disconnected,
+   Connecting,
+   Connected,
+   Failed(&'static str),
+   WaitingToReconnect,
+}
+
+impl AAPState {
+   pub const fn to_str(self) -> &'static str {
+      match self {
+         Self::Disconnected => "disconnected",
+         Self::Connecting => "connecting",
+         Self::Connected => "connected",
+         Self::Failed(_) => "failed",
+         Self::WaitingToReconnect => "waiting_to_reconnect",
+      }
+   }
+}
+
+/// Connection diagnostics of a device, published by the Bluetooth manager on
+/// every transition.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct ConnectionInfo {
+   pub state: AAPState,
+   pub bluetooth_connected: bool,
+   pub adapter: SmolStr,
+   /// Failed attempts since the last successful connection.
+   pub retry_count: u32,
+   pub last_error: Option<String>,
+   /// Unix timestamp of the next reconnect attempt, while waiting to reconnect.
+   pub next_retry: Option<u64>,
+}
+
+impl ConnectionInfo {
+   pub fn to_json(&self) -> serde_json::Value {
+      json!({
+         "state": self.state.to_str(),
+         "bluetooth_connected": self.bluetooth_connected,
+         "adapter": self.adapter.as_str(),
+         "retry_count": self.retry_count,
+         "last_error": self.last_error,
+         "next_retry": self.next_retry,
+      })
+   }
+}
+
+/// Outcome of applying a [`DeviceProfile`], by setting name.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct ProfileReport {
+   pub applied: Vec<SmolStr>,
+   /// Settings the device already had.
+   pub skipped: Vec<SmolStr>,
+   pub failed: Vec<(SmolStr, String)>,
+}
+
+impl ProfileReport {
+   pub fn to_json(&self) -> serde_json::Value {
+      json!({
+         "applied": self.applied,
+         "skipped": self.skipped,
+         "failed": self
+            .failed
+            .iter()
+            .map(|(setting, error)| json!({ "setting": setting, "error": error }))
+            .collect::<Vec<_>>(),
+      })
+   }
+
+   fn record(&mut self, name: SmolStr, result: Result<()>) {
+      match result {
+         Ok(()) => self.applied.push(name),
+         Err(e) => self.failed.push((name, e.to_string())),
+      }
+   }
+}
+
+/// Silences the events of the changes echoed by the device while settings are applied.
+struct SettingsBatch<'a>(&'a AirPodsInner);
+
+impl<'a> SettingsBatch<'a> {
+   fn start(inner: &'a AirPodsInner) -> Self {
+      inner.settings_batches.fetch_add(1, Ordering::Relaxed);
+      Self(inner)
+   }
+}
+
+impl Drop for SettingsBatch<'_> {
+   fn drop(&mut self) {
+      self.0.settings_batches.fetch_sub(1, Ordering::Relaxed);
+   }
+}
+
 /// Internal shared state for an `AirPods` device.
 #[derive(Debug, Default)]
 struct AirPodsInner {
    address: Address,
    address_str: SmolStr,
+   adapter: SmolStr,
    name: parking_lot::Mutex<SmolStr>,
    battery: AtomicCell<Option<BatteryInfo>>,
    is_connected: AtomicBool,
    ear_detection: AtomicCell<Option<EarDetectionStatus>>,
    noise_mode: AtomicCell<Option<NoiseControlMode>>,
+   primary_bud: AtomicCell<Option<Component>>,
+   transparency: AtomicCell<Option<TransparencySettings>>,
+   hearing_aid: parking_lot::Mutex<Option<HearingAidProfile>>,
+   model: AtomicCell<Option<DeviceModel>>,
+   connection: parking_lot::Mutex<ConnectionInfo>,
    features: FeatureBitmap,
    features_present: FeatureBitmap,
+   feature_values: parking_lot::Mutex<BTreeMap<FeatureId, u32>>,
+   /// Pending feature queries, answered by the next value reported for their feature.
+   feature_queries: parking_lot::Mutex<HashMap<FeatureId, Vec<oneshot::Sender<u32>>>>,
+   fit_test: parking_lot::Mutex<Option<oneshot::Sender<FitTestResult>>>,
+   /// Number of setting batches in progress, during which echoed changes emit no event.
+   settings_batches: AtomicUsize,
    conn: RwLock<Option<ConnectionState>>,
    battery_tracker: parking_lot::Mutex<BatteryTracker>,
+   study: Option<BatteryStudy>,
+   state_saved: AtomicCell<Option<Instant>>,
+   profile: parking_lot::Mutex<Option<DeviceProfile>>,
+   profile_applied: AtomicBool,
 }
 
 /// Represents a connected `AirPods` device.
@@ -134,12 +263,19 @@ impl<T: PartialEq> UpdateOp<T> {
 
 impl AirPods {
    /// Creates a new `AirPods` device instance.
-   pub fn new(address: Address, name: String, battery_study: Option<BatteryStudy>) -> Self {
+   pub fn new(
+      address: Address,
+      name: String,
+      adapter: SmolStr,
+      battery_study: Option<BatteryStudy>,
+   ) -> Self {
       Self(Arc::new(AirPodsInner {
          address,
          address_str: address.to_smolstr(),
+         adapter,
          name: parking_lot::Mutex::new(name.into()),
-         battery_tracker: parking_lot::Mutex::new(BatteryTracker::new(battery_study)),
+         battery_tracker: parking_lot::Mutex::new(BatteryTracker::new(battery_study.clone())),
+         study: battery_study,
          ..Default::default()
       }))
    }
@@ -154,6 +290,11 @@ impl AirPods {
       &self.0.address_str
    }
 
+   /// Gets the name of the Bluetooth adapter the Airpod is connected through.
+   pub fn adapter(&self) -> &SmolStr {
+      &self.0.adapter
+   }
+
    /// Gets the name of the Airpod.
    pub fn name(&self) -> SmolStr {
       self.0.name.lock().clone()
@@ -212,6 +353,84 @@ impl AirPods {
       UpdateOp::apply_atomic(&self.0.noise_mode, mode.into())
    }
 
+   /// Gets the transparency customization of the Airpod.
+   ///
+   /// Falls back to the last applied settings if the device has not reported any yet.
+   pub fn transparency_settings(&self) -> Option<TransparencySettings> {
+      self.0.transparency.load().or_else(|| {
+         self
+            .0
+            .study
+            .as_ref()?
+            .get_transparency(self.address())
+            .ok()
+            .flatten()
+      })
+   }
+
+   /// Sets the transparency customization of the Airpod.
+   pub fn update_transparency_settings(
+      &self,
+      settings: impl Into<Option<TransparencySettings>>,
+   ) -> UpdateOp<TransparencySettings> {
+      UpdateOp::apply_atomic(&self.0.transparency, settings.into())
+   }
+
+   /// Gets the active hearing-aid profile of the Airpod.
+   pub fn hearing_aid_profile(&self) -> Option<HearingAidProfile> {
+      *self.0.hearing_aid.lock()
+   }
+
+   /// Sets the active hearing-aid profile of the Airpod.
+   pub fn update_hearing_aid_profile(
+      &self,
+      profile: impl Into<Option<HearingAidProfile>>,
+   ) -> UpdateOp<HearingAidProfile> {
+      let mut lock = self.0.hearing_aid.lock();
+      let new = profile.into();
+      UpdateOp::new(mem::replace(&mut *lock, new), new)
+   }
+
+   /// Gets the model and firmware revision of the Airpod.
+   pub fn model(&self) -> Option<DeviceModel> {
+      self.0.model.load()
+   }
+
+   /// Sets the model and firmware revision of the Airpod.
+   pub fn set_model(&self, model: DeviceModel) {
+      self.0.model.store(Some(model));
+   }
+
+   /// Gets the bud currently holding the host link and the microphone.
+   pub fn primary_bud(&self) -> Option<Component> {
+      self.0.primary_bud.load()
+   }
+
+   /// Gets the bud relaying through the primary one.
+   pub fn secondary_bud(&self) -> Option<Component> {
+      self.primary_bud().and_then(Component::other_bud)
+   }
+
+   /// Sets the primary bud of the Airpod.
+   pub fn update_primary_bud(&self, bud: impl Into<Option<Component>>) -> UpdateOp<Component> {
+      UpdateOp::apply_atomic(&self.0.primary_bud, bud.into())
+   }
+
+   /// Gets the connection diagnostics of the device.
+   pub fn connection_info(&self) -> ConnectionInfo {
+      self.0.connection.lock().clone()
+   }
+
+   /// Updates the connection diagnostics, returning whether they changed.
+   pub fn update_connection_info(&self, info: ConnectionInfo) -> bool {
+      let mut lock = self.0.connection.lock();
+      if *lock == info {
+         return false;
+      }
+      *lock = info;
+      true
+   }
+
    /// Converts the device state to a JSON representation.
    pub fn to_json(&self) -> serde_json::Value {
       let mut info = json!({
@@ -238,12 +457,37 @@ impl AirPods {
          info["ear_detection"] = ear.to_json();
       }
 
+      if let Some(transparency) = self.transparency_settings() {
+         info["transparency"] = transparency.to_json();
+      }
+
+      if let Some(primary) = self.primary_bud() {
+         info["primary_bud"] = json!(primary.to_str());
+         info["secondary_bud"] = json!(self.secondary_bud().map(Component::to_str));
+      }
+
       let features_dict: HashMap<_, _> = self
          .features()
          .into_iter()
          .map(|(k, v)| (k.to_str(), v))
          .collect();
       info["features"] = json!(features_dict);
+
+      let values_dict: HashMap<_, _> = self
+         .feature_values()
+         .into_iter()
+         .map(|(k, v)| (k.to_str(), v))
+         .collect();
+      info["feature_values"] = json!(values_dict);
+
+      info["connection"] = self.connection_info().to_json();
+
+      if let Some(model) = self.model() {
+         info["model"] = json!({
+            "product_id": model.product_id,
+            "version": model.version,
+         });
+      }
       info
    }
 
@@ -260,6 +504,35 @@ impl AirPods {
          .collect()
    }
 
+   /// Gets the raw values reported for every feature the device has answered.
+   pub fn feature_values(&self) -> Vec<(FeatureId, u32)> {
+      self
+         .0
+         .feature_values
+         .lock()
+         .iter()
+         .map(|(&k, &v)| (k, v))
+         .collect()
+   }
+
+   /// Records a raw feature value reported by the device and wakes up the queries
+   /// pending for it.
+   fn record_feature_value(&self, feature: FeatureId, value: u32) {
+      self.0.features_present.set(feature, true);
+      self.0.feature_values.lock().insert(feature, value);
+
+      if let Some(queries) = self.0.feature_queries.lock().remove(&feature) {
+         for tx in queries {
+            let _ = tx.send(value);
+         }
+      }
+   }
+
+   /// Sets the profile applied when the device connects.
+   pub fn set_profile(&self, profile: Option<DeviceProfile>) {
+      *self.0.profile.lock() = profile;
+   }
+
    pub fn set_feature_enabled(&self, feature: FeatureId, enabled: bool) -> bool {
       self.0.features_present.set(feature, true);
       self.0.features.set(feature, enabled)
@@ -277,7 +550,7 @@ impl AirPods {
       let mut jset = JoinSet::new();
 
       // Perform handshake
-      let (receiver, sender) = self.start_connection(&mut jset).await?;
+      let (receiver, sender) = self.start_connection(&mut jset, event_tx).await?;
 
       // Start packet processor with direct access to fields
       let jhandle = self.start_packet_processor(receiver, event_tx.clone());
@@ -300,6 +573,7 @@ impl AirPods {
    pub async fn disconnect(&self) {
       // Save battery study data before disconnecting
       self.save_battery_study();
+      self.save_state();
 
       self.0.is_connected.store(false, Ordering::Relaxed);
       let _ = self.0.conn.write().await.take();
@@ -309,6 +583,7 @@ impl AirPods {
    async fn notify_disconnected(&self, event_tx: &EventSender) {
       // Save battery study data before disconnecting
       self.save_battery_study();
+      self.save_state();
 
       self.0.is_connected.store(false, Ordering::Relaxed);
       let _ = self.0.conn.write().await.take();
@@ -319,6 +594,7 @@ impl AirPods {
    async fn start_connection(
       &self,
       jset: &mut JoinSet<()>,
+      event_tx: &EventSender,
    ) -> Result<(L2CapReceiver, L2CapSender)> {
       async fn wait_for_ack<T>(tx: &mut oneshot::Receiver<T>) -> Result<T> {
          time::timeout(Duration::from_secs(5), tx)
@@ -396,9 +672,226 @@ impl AirPods {
                 }
             }
         });
+      jset.spawn({
+         let weak = WeakAirPods::new(self);
+         let sender = sender.clone();
+         let event_tx = event_tx.clone();
+         async move {
+            time::sleep(FEATURE_DISCOVERY_DELAY).await;
+            Self::apply_profile(&weak, &sender, &event_tx).await;
+            Self::discover_features(weak, sender).await;
+         }
+      });
       Ok((receiver, sender))
    }
 
+   /// Brings the device to its profile once the battery status came in.
+   ///
+   /// Settings are applied one by one so that one rejected by the firmware does
+   /// not hold back the others; the outcome is emitted as `ProfileApplied`.
+   async fn apply_profile(weak: &WeakAirPods, sender: &L2CapSender, event_tx: &EventSender) {
+      let Some(this) = weak.upgrade() else {
+         return;
+      };
+      let mac = this.address();
+      let Some(profile) = this.0.profile.lock().clone().filter(|p| !p.is_empty()) else {
+         return;
+      };
+      if profile.once_per_session && this.0.profile_applied.load(Ordering::Relaxed) {
+         debug!("{mac}: Profile already applied in this session");
+         return;
+      }
+      drop(this);
+
+      let deadline = Instant::now() + PROFILE_BATTERY_TIMEOUT;
+      let this = loop {
+         let Some(this) = weak.upgrade() else {
+            return;
+         };
+         if this.battery_info().is_some() {
+            break this;
+         }
+         if Instant::now() >= deadline {
+            warn!("{mac}: No battery status, applying the profile anyway");
+            break this;
+         }
+         drop(this);
+         time::sleep(Duration::from_millis(500)).await;
+      };
+      info!("{mac}: Applying profile");
+
+      let mut report = ProfileReport::default();
+      let noise_mode = this.noise_mode();
+      let batch = SettingsBatch::start(&this.0);
+      for setting in profile.settings() {
+         let (feature, value) = setting.control();
+         let name = match setting {
+            Setting::NoiseMode(_) => SmolStr::new_static("noise_mode"),
+            Setting::Feature(feature, _) => SmolStr::new_static(feature.to_str()),
+         };
+         if profile.only_if_different
+            && matches!(this.query_feature(sender, feature).await, Ok(Some(current)) if current == value)
+         {
+            report.skipped.push(name);
+            continue;
+         }
+         report.record(name, this.send_confirmed(sender, setting).await);
+      }
+      if profile.transparency != TransparencyProfile::default() {
+         let current = this.transparency_settings().unwrap_or_default();
+         let settings = profile.transparency(current);
+         let name = SmolStr::new_static("transparency");
+         if profile.only_if_different && this.0.transparency.load() == Some(settings) {
+            report.skipped.push(name);
+         } else {
+            report.record(name, this.set_transparency(settings).await);
+         }
+      }
+      drop(batch);
+      if let Some(mode) = this.noise_mode()
+         && Some(mode) != noise_mode
+      {
+         event_tx.emit(&this, AirPodsEvent::NoiseControlChanged(mode));
+      }
+      // Retried on the next connection if nothing could be applied
+      if !report.applied.is_empty() || !report.skipped.is_empty() {
+         this.0.profile_applied.store(true, Ordering::Relaxed);
+      }
+
+      for (setting, error) in &report.failed {
+         warn!("{mac}: Profile setting {setting} failed: {error}");
+      }
+      info!(
+         "{mac}: Profile applied ({} changed, {} unchanged, {} failed)",
+         report.applied.len(),
+         report.skipped.len(),
+         report.failed.len()
+      );
+      event_tx.emit(&this, AirPodsEvent::ProfileApplied(report));
+   }
+
+   /// Queries feature IDs one by one and records which ones the firmware answers.
+   ///
+   /// The first connect of a model and firmware sweeps the IDs of `FEATURE_SWEEP` and
+   /// caches the answered set; later connects only re-query the cached IDs to refresh
+   /// their values.
+   /// A sweep is only cached once it ran to the end and got answers, so a device too
+   /// busy to answer or a dropped link is swept again on the next connect.
+   async fn discover_features(weak: WeakAirPods, sender: L2CapSender) {
+      let Some(this) = weak.upgrade() else {
+         return;
+      };
+      let mac = this.address();
+      let model = this.model();
+      let cached = model
+         .and_then(|model| {
+            this
+               .0
+               .study
+               .as_ref()?
+               .get_feature_probe(model)
+               .inspect_err(|e| debug!("{mac}: Failed to read feature cache: {e}"))
+               .ok()
+               .flatten()
+         })
+         .filter(|probe| !probe.answered.is_empty());
+      drop(this);
+
+      let ids: Vec<u8> = if let Some(probe) = &cached {
+         info!(
+            "{mac}: Using cached feature set ({} features), skipping full sweep",
+            probe.answered.len()
+         );
+         probe.answered.iter().map(|&(id, _)| id).collect()
+      } else {
+         info!("{mac}: Starting feature discovery sweep");
+         FEATURE_SWEEP.collect()
+      };
+
+      let mut answered = Vec::new();
+      for id in ids {
+         let Some(this) = weak.upgrade() else {
+            return;
+         };
+         let feature = FeatureId::from_id(id);
+         match this.query_feature(&sender, feature).await {
+            Ok(Some(value)) => {
+               debug!("{mac}: Feature {feature} answered with {value:#x}");
+               answered.push((id, value));
+            },
+            Ok(None) => {},
+            Err(e) => {
+               warn!("{mac}: Feature discovery aborted, not caching it: {e}");
+               return;
+            },
+         }
+         drop(this);
+         time::sleep(FEATURE_QUERY_PACING).await;
+      }
+      info!(
+         "{mac}: Feature discovery completed, {} features answered",
+         answered.len()
+      );
+
+      if cached.is_some() {
+         return;
+      }
+      if answered.is_empty() {
+         warn!("{mac}: No feature answered the discovery sweep, not caching it");
+         return;
+      }
+      if let Some(model) = model
+         && let Some(this) = weak.upgrade()
+         && let Some(study) = &this.0.study
+      {
+         let probe = FeatureProbe {
+            answered,
+            ..Default::default()
+         };
+         if let Err(e) = study.put_feature_probe(model, probe) {
+            warn!("{mac}: Failed to cache feature discovery for {model}: {e}");
+         }
+      }
+   }
+
+   /// Sends a feature query and waits for the device to answer it.
+   ///
+   /// Queries for different features may run concurrently, as the discovery sweep
+   /// does with settings being applied. Returns `None` if the device did not answer
+   /// within the timeout.
+   async fn query_feature(&self, sender: &L2CapSender, feature: FeatureId) -> Result<Option<u32>> {
+      let (tx, rx) = oneshot::channel();
+      self
+         .0
+         .feature_queries
+         .lock()
+         .entry(feature)
+         .or_default()
+         .push(tx);
+      let result = sender.send(&FeatureCmd::Query.build(feature.id())).await;
+      let value = match result {
+         Ok(()) => time::timeout(FEATURE_QUERY_TIMEOUT, rx)
+            .await
+            .ok()
+            .and_then(|r| r.ok()),
+         Err(_) => {
+            drop(rx);
+            None
+         },
+      };
+
+      // Forget this query if it went unanswered, along with any other abandoned one
+      let mut queries = self.0.feature_queries.lock();
+      if let Some(pending) = queries.get_mut(&feature) {
+         pending.retain(|tx| !tx.is_closed());
+         if pending.is_empty() {
+            queries.remove(&feature);
+         }
+      }
+      drop(queries);
+      result.map(|()| value)
+   }
+
    fn start_packet_processor(
       &self,
       mut rx: l2cap::L2CapReceiver,
@@ -445,6 +938,90 @@ impl AirPods {
       }
    }
 
+   pub async fn set_transparency(&self, settings: TransparencySettings) -> Result<()> {
+      settings.validate()?;
+
+      let conn = self.0.conn.read().await;
+      if let Some(conn) = conn.as_ref() {
+         conn.sender.send(&settings.build()).await?;
+         self.update_transparency_settings(settings);
+         if let Some(study) = &self.0.study
+            && let Err(e) = study.put_transparency(self.address(), &settings)
+         {
+            warn!("Failed to persist transparency settings: {e}");
+         }
+         Ok(())
+      } else {
+         Err(AirPodsError::DeviceNotConnected)
+      }
+   }
+
+   pub async fn set_hearing_aid_profile(&self, profile: HearingAidProfile) -> Result<()> {
+      profile.validate()?;
+
+      let conn = self.0.conn.read().await;
+      if let Some(conn) = conn.as_ref() {
+         conn.sender.send(&profile.build()).await?;
+         self.update_hearing_aid_profile(profile);
+         Ok(())
+      } else {
+         Err(AirPodsError::DeviceNotConnected)
+      }
+   }
+
+   /// Runs the ear-tip fit test and returns the result for each bud, primary first.
+   ///
+   /// Both buds must be in ear, and the test plays a chime while it measures the seal.
+   pub async fn run_fit_test(&self) -> Result<Vec<(Component, EarTipFit)>> {
+      let supported = match self.model() {
+         Some(model) => model.supports_fit_test(),
+         None => !self
+            .battery_info()
+            .is_some_and(|b| b.headphone.is_available()),
+      };
+      if !supported {
+         return Err(AirPodsError::FeatureNotSupported("fit test".to_string()));
+      }
+      let Some(ear) = self.ear_detection() else {
+         return Err(AirPodsError::PreconditionFailed(
+            "ear detection state unknown",
+         ));
+      };
+      if !ear.is_left_in_ear() || !ear.is_right_in_ear() {
+         return Err(AirPodsError::PreconditionFailed("both buds must be in ear"));
+      }
+
+      let (tx, rx) = oneshot::channel();
+      {
+         let mut pending = self.0.fit_test.lock();
+         if pending.as_ref().is_some_and(|tx| !tx.is_closed()) {
+            return Err(AirPodsError::PreconditionFailed("fit test already running"));
+         }
+         *pending = Some(tx);
+      }
+
+      {
+         let conn = self.0.conn.read().await;
+         let Some(conn) = conn.as_ref() else {
+            self.0.fit_test.lock().take();
+            return Err(AirPodsError::DeviceNotConnected);
+         };
+         if let Err(e) = conn.sender.send(PKT_START_FIT_TEST).await {
+            self.0.fit_test.lock().take();
+            return Err(e);
+         }
+      }
+      info!("{}: Ear-tip fit test started", self.address());
+
+      let result = time::timeout(FIT_TEST_TIMEOUT, rx).await;
+      self.0.fit_test.lock().take();
+      let result = result
+         .map_err(|_| AirPodsError::RequestTimeout)?
+         .map_err(|_| AirPodsError::ConnectionClosed)?;
+      info!("{}: Ear-tip fit test result: {result:?}", self.address());
+      Ok(result.to_vec())
+   }
+
    pub async fn passthrough(&self, packet: &[u8]) -> Result<()> {
       let conn = self.0.conn.read().await;
       if let Some(conn) = conn.as_ref() {
@@ -471,7 +1048,72 @@ impl AirPods {
       }
    }
 
+   /// Applies several settings in sequence, waiting for the device to confirm each one.
+   ///
+   /// If a setting is not confirmed, the ones applied before it are restored to
+   /// their previous values and the error is returned. The changes emit no event,
+   /// the caller notifies them once.
+   pub async fn apply_settings(&self, settings: &[Setting]) -> Result<()> {
+      let conn = self.0.conn.read().await;
+      let Some(conn) = conn.as_ref() else {
+         return Err(AirPodsError::DeviceNotConnected);
+      };
+      let _batch = SettingsBatch::start(&self.0);
+
+      let mut applied = Vec::with_capacity(settings.len());
+      for &setting in settings {
+         let previous = self.current_setting(setting);
+         if let Err(e) = self.send_confirmed(&conn.sender, setting).await {
+            warn!("{}: Failed to apply {setting:?}: {e}", self.address());
+            for previous in applied.into_iter().rev().flatten() {
+               if let Err(e) = self.send_confirmed(&conn.sender, previous).await {
+                  warn!("{}: Failed to restore {previous:?}: {e}", self.address());
+               }
+            }
+            return Err(e);
+         }
+         applied.push(previous);
+      }
+      Ok(())
+   }
+
+   /// Gets the current value of the setting changed by `setting`, if known.
+   fn current_setting(&self, setting: Setting) -> Option<Setting> {
+      match setting {
+         Setting::NoiseMode(_) => self.noise_mode().map(Setting::NoiseMode),
+         Setting::Feature(feature, _) => self
+            .0
+            .features_present
+            .get(feature)
+            .then(|| Setting::Feature(feature, self.feature_enabled(feature))),
+      }
+   }
+
+   /// Sends a setting and queries it back to confirm the device applied it.
+   async fn send_confirmed(&self, sender: &L2CapSender, setting: Setting) -> Result<()> {
+      let (feature, value) = setting.control();
+      sender.send(&setting.build()).await?;
+      match self.query_feature(sender, feature).await? {
+         Some(reported) if reported == value => {},
+         Some(_) => return Err(AirPodsError::SettingRejected(feature)),
+         None => return Err(AirPodsError::RequestTimeout),
+      }
+
+      match setting {
+         Setting::NoiseMode(mode) => self.0.noise_mode.store(Some(mode)),
+         Setting::Feature(feature, enabled) => {
+            self.set_feature_enabled(feature, enabled);
+         },
+      }
+      Ok(())
+   }
+
    fn process_packet(&self, address: Address, packet: Packet, event_tx: &EventSender) {
+      // Control packets answer feature queries, whichever handler picks them up below
+      if let Some((feature, value)) = parse_control_packet(&packet) {
+         self.record_feature_value(feature, value);
+      }
+
       // Battery status
       if packet.starts_with(HDR_BATTERY_STATE) {
          match parser::parse_battery_status(&packet) {
@@ -490,6 +1132,13 @@ impl AirPods {
                      .record_battery_drop(battery.left, battery.right);
                   event_tx.emit(self, AirPodsEvent::BatteryUpdated(battery));
                }
+
+               if let Some(primary) = battery.primary_pod
+                  && self.update_primary_bud(primary).is_updated()
+               {
+                  debug!("Primary bud for {address} is now {primary}");
+                  event_tx.emit(self, AirPodsEvent::PrimaryBudChanged(primary));
+               }
             },
             Err(e) => warn!("Failed to parse battery: {e}"),
          }
@@ -499,7 +1148,9 @@ impl AirPods {
          match parser::parse_noise_mode(&packet) {
             Ok(mode) => {
                debug!("Noise mode updated for {address}: {mode}");
-               if self.update_noise_mode(mode).is_updated() {
+               if self.update_noise_mode(mode).is_updated()
+                  && self.0.settings_batches.load(Ordering::Relaxed) == 0
+               {
                   event_tx.emit(self, AirPodsEvent::NoiseControlChanged(mode));
                }
             },
@@ -508,7 +1159,7 @@ impl AirPods {
       }
       // Ear detection
       else if packet.starts_with(HDR_EAR_DETECTION) {
-         match parser::parse_ear_detection(&packet) {
+         match parser::parse_ear_detection(&packet, self.primary_bud()) {
             Ok(status) => {
                debug!(
                   "Ear detection updated for {}: L:{} R:{}",
@@ -524,6 +1175,40 @@ impl AirPods {
             Err(e) => warn!("Failed to parse ear detection: {e}"),
          }
       }
+      // Transparency customization
+      else if packet.starts_with(HDR_TRANSPARENCY) {
+         match parser::parse_transparency_settings(&packet) {
+            Ok(settings) => {
+               debug!("Transparency settings updated for {address}: {settings:?}");
+               if self.update_transparency_settings(settings).is_updated() {
+                  event_tx.emit(self, AirPodsEvent::TransparencyChanged(settings));
+               }
+            },
+            Err(e) => warn!("Failed to parse transparency settings: {e}"),
+         }
+      }
+      // Hearing-aid profile
+      else if packet.starts_with(HDR_HEARING_AID) {
+         match parser::parse_hearing_aid_profile(&packet) {
+            Ok(profile) => {
+               debug!("Hearing aid profile updated for {address}: {profile:?}");
+               self.update_hearing_aid_profile(profile);
+            },
+            Err(e) => warn!("Failed to parse hearing aid profile: {e}"),
+         }
+      }
+      // Ear-tip fit test result
+      else if packet.starts_with(HDR_FIT_TEST_RESULT) {
+         match parser::parse_fit_test_result(&packet, self.primary_bud()) {
+            Ok(result) => {
+               debug!("Fit test result for {address}: {result:?}");
+               if let Some(tx) = self.0.fit_test.lock().take() {
+                  let _ = tx.send(result);
+               }
+            },
+            Err(e) => warn!("Failed to parse fit test result: {e}"),
+         }
+      }
       // Metadata packets
       else if packet.starts_with(HDR_METADATA) {
          if let Ok(metadata) = parser::parse_metadata(&packet) {
@@ -597,6 +1282,42 @@ impl AirPods {
          .save_to_study(self.address(), mode);
    }
 
+   /// Persists the last known state of the device, shown while it is away.
+   ///
+   /// Values not reported in this session keep their previously saved value.
+   pub fn save_state(&self) {
+      let Some(study) = &self.0.study else {
+         return;
+      };
+      self.0.state_saved.store(Some(Instant::now()));
+      let features = self.features();
+      let result = study.update_device_state(self.address(), |state| {
+         state.name = self.name();
+         state.adapter = self.adapter().clone();
+         if let Some(model) = self.model() {
+            state.product_id = Some(model.product_id);
+         }
+         if let Some(battery) = self.battery_info() {
+            state.battery = Some(battery);
+         }
+         if let Some(mode) = self.noise_mode() {
+            state.noise_mode = Some(mode);
+         }
+         if !features.is_empty() {
+            state.features = features
+               .into_iter()
+               .map(|(k, v)| (SmolStr::new_static(k.to_str()), v))
+               .collect();
+         }
+      });
+      if let Err(e) = result {
+         warn!(
+            "{}: Failed to persist the device state: {e}",
+            self.address()
+         );
+      }
+   }
+
    /// Checks if enough time has passed and samples collected to warrant a periodic save.
    /// Returns true if data should be saved.
    fn should_save_battery_study(&self, interval_minutes: u32) -> bool {
@@ -630,6 +1351,14 @@ impl AirPods {
          } else {
             debug!("Battery save check for {} returned false", self.address());
          }
+         if self
+            .0
+            .state_saved
+            .load()
+            .is_none_or(|t| t.elapsed() >= STATE_SAVE_INTERVAL)
+         {
+            self.save_state();
+         }
       } else {
          debug!("Device {} not connected, skipping tick", self.address());
       }
@@ -0,0 +1,343 @@
+//! Hearing-aid audiogram and hearing-assist configuration.
+//!
+//! This module contains the audiogram data model used by the hearing-aid
+//! mode of `AirPods` Pro 2, its import from CSV/JSON and its encoding into
+//! the AAP hearing-aid packet.
+
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use thiserror::Error;
+
+use crate::{
+   airpods::protocol::{HDR_HEARING_AID, RangeError},
+   bluetooth::l2cap::Packet,
+};
+
+/// Standard audiometric frequencies (Hz), in the order they are sent on the wire.
+pub const AUDIOGRAM_FREQUENCIES: [u16; 8] = [250, 500, 1000, 2000, 3000, 4000, 6000, 8000];
+
+/// Highest hearing threshold accepted by the firmware (dB HL).
+const MAX_THRESHOLD_DB: f32 = 120.0;
+
+/// Size of the hearing-aid payload: both audiograms followed by gain and tone.
+pub const HEARING_AID_PAYLOAD_LEN: usize = 4 * (2 * AUDIOGRAM_FREQUENCIES.len() + 2);
+
+/// Errors that can occur while importing an audiogram.
+#[derive(Error, Debug)]
+pub enum AudiogramError {
+   #[error("Invalid JSON audiogram: {0}")]
+   Json(#[from] serde_json::Error),
+
+   #[error("Invalid CSV audiogram at line {line}: {reason}")]
+   Csv { line: usize, reason: &'static str },
+
+   #[error("Unsupported audiogram format: {0}")]
+   UnknownFormat(String),
+
+   #[error("Unsupported frequency: {0} Hz")]
+   UnknownFrequency(u16),
+
+   #[error("No thresholds given for the {0} ear")]
+   MissingEar(&'static str),
+
+   #[error(transparent)]
+   OutOfRange(#[from] RangeError),
+}
+
+/// A single frequency/threshold measurement.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct AudiogramPoint {
+   pub frequency: u16,
+   pub db: f32,
+}
+
+/// Per-ear hearing thresholds (dB HL) at [`AUDIOGRAM_FREQUENCIES`].
+#[derive(Debug, Default, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct Audiogram {
+   pub left: [f32; AUDIOGRAM_FREQUENCIES.len()],
+   pub right: [f32; AUDIOGRAM_FREQUENCIES.len()],
+}
+
+#[derive(Deserialize)]
+struct JsonAudiogram {
+   #[serde(default)]
+   left: Vec<AudiogramPoint>,
+   #[serde(default)]
+   right: Vec<AudiogramPoint>,
+}
+
+impl Audiogram {
+   /// Parses an audiogram in the given format (`csv` or `json`).
+   pub fn import(format: &str, contents: &str) -> Result<Self, AudiogramError> {
+      match format.to_ascii_lowercase().as_str() {
+         "csv" => Self::from_csv(contents),
+         "json" => Self::from_json(contents),
+         _ => Err(AudiogramError::UnknownFormat(format.to_string())),
+      }
+   }
+
+   /// Parses an audiogram from JSON of the form
+   /// `{"left": [{"frequency": 250, "db": 20}, ...], "right": [...]}`.
+   pub fn from_json(contents: &str) -> Result<Self, AudiogramError> {
+      let parsed: JsonAudiogram = serde_json::from_str(contents)?;
+      Self::from_points(&parsed.left, &parsed.right)
+   }
+
+   /// Parses an audiogram from CSV rows of the form `ear,frequency,db`.
+   ///
+   /// Empty lines, `#` comments and a header row are ignored.
+   pub fn from_csv(contents: &str) -> Result<Self, AudiogramError> {
+      let mut left = Vec::new();
+      let mut right = Vec::new();
+
+      for (i, row) in contents.lines().enumerate() {
+         let line = i + 1;
+         let row = row.trim();
+         if row.is_empty() || row.starts_with('#') {
+            continue;
+         }
+
+         let mut cols = row.split(',').map(str::trim);
+         let (Some(ear), Some(frequency), Some(db), None) =
+            (cols.next(), cols.next(), cols.next(), cols.next())
+         else {
+            return Err(AudiogramError::Csv {
+               line,
+               reason: "expected 3 columns",
+            });
+         };
+
+         let ear = match ear.to_ascii_lowercase().as_str() {
+            "left" | "l" => &mut left,
+            "right" | "r" => &mut right,
+            "ear" if line == 1 => continue,
+            _ => {
+               return Err(AudiogramError::Csv {
+                  line,
+                  reason: "ear must be 'left' or 'right'",
+               });
+            },
+         };
+         let frequency = frequency.parse().map_err(|_| AudiogramError::Csv {
+            line,
+            reason: "invalid frequency",
+         })?;
+         let db = db.parse().map_err(|_| AudiogramError::Csv {
+            line,
+            reason: "invalid threshold",
+         })?;
+         ear.push(AudiogramPoint { frequency, db });
+      }
+
+      Self::from_points(&left, &right)
+   }
+
+   /// Builds an audiogram from measured points.
+   ///
+   /// Standard frequencies that were not measured are linearly interpolated
+   /// from their neighbours, or copied from the nearest measurement at the edges.
+   pub fn from_points(
+      left: &[AudiogramPoint],
+      right: &[AudiogramPoint],
+   ) -> Result<Self, AudiogramError> {
+      let audiogram = Self {
+         left: Self::resample(left, "left")?,
+         right: Self::resample(right, "right")?,
+      };
+      audiogram.validate()?;
+      Ok(audiogram)
+   }
+
+   fn resample(
+      points: &[AudiogramPoint],
+      ear: &'static str,
+   ) -> Result<[f32; AUDIOGRAM_FREQUENCIES.len()], AudiogramError> {
+      let mut measured: [Option<f32>; AUDIOGRAM_FREQUENCIES.len()] = Default::default();
+      for point in points {
+         let Some(i) = AUDIOGRAM_FREQUENCIES
+            .iter()
+            .position(|&f| f == point.frequency)
+         else {
+            return Err(AudiogramError::UnknownFrequency(point.frequency));
+         };
+         measured[i] = Some(point.db);
+      }
+
+      let known: Vec<(f32, f32)> = AUDIOGRAM_FREQUENCIES
+         .iter()
+         .zip(measured)
+         .filter_map(|(&f, db)| Some((f32::from(f), db?)))
+         .collect();
+      let (Some(&first), Some(&last)) = (known.first(), known.last()) else {
+         return Err(AudiogramError::MissingEar(ear));
+      };
+
+      Ok(AUDIOGRAM_FREQUENCIES.map(|f| {
+         let f = f32::from(f);
+         if f <= first.0 {
+            return first.1;
+         }
+         if f >= last.0 {
+            return last.1;
+         }
+         let upper = known
+            .iter()
+            .position(|&(kf, _)| kf >= f)
+            .unwrap_or(known.len() - 1);
+         let (f0, db0) = known[upper - 1];
+         let (f1, db1) = known[upper];
+         db0 + (db1 - db0) * (f - f0) / (f1 - f0)
+      }))
+   }
+
+   /// Checks that every threshold is within the range accepted by the firmware.
+   pub fn validate(&self) -> Result<(), RangeError> {
+      for (name, thresholds) in [
+         ("left threshold", &self.left),
+         ("right threshold", &self.right),
+      ] {
+         for &db in thresholds {
+            RangeError::check(name, db, 0.0, MAX_THRESHOLD_DB)?;
+         }
+      }
+      Ok(())
+   }
+
+   pub fn to_json(self) -> serde_json::Value {
+      let points = |thresholds: &[f32]| -> Vec<serde_json::Value> {
+         AUDIOGRAM_FREQUENCIES
+            .iter()
+            .zip(thresholds)
+            .map(|(f, db)| json!({ "frequency": f, "db": db }))
+            .collect()
+      };
+      json!({
+         "left": points(&self.left),
+         "right": points(&self.right),
+      })
+   }
+}
+
+/// Complete hearing-aid configuration: audiogram plus gain and tone adjustments.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct HearingAidProfile {
+   pub audiogram: Audiogram,
+   /// Overall amplification adjustment, from -1.0 to 1.0.
+   pub gain: f32,
+   /// Tone adjustment, from -1.0 (darker) to 1.0 (brighter).
+   pub tone: f32,
+}
+
+impl HearingAidProfile {
+   /// Checks that every value is within the range accepted by the firmware.
+   pub fn validate(&self) -> Result<(), RangeError> {
+      self.audiogram.validate()?;
+      RangeError::check("gain", self.gain, -1.0, 1.0)?;
+      RangeError::check("tone", self.tone, -1.0, 1.0)?;
+      Ok(())
+   }
+
+   /// Builds the packet applying this profile.
+   ///
+   /// Every value is a little-endian `f32`: left thresholds, right thresholds,
+   /// gain, then tone.
+   pub fn build(&self) -> Packet {
+      let mut packet = Packet::from_slice(HDR_HEARING_AID);
+      self
+         .audiogram
+         .left
+         .iter()
+         .chain(&self.audiogram.right)
+         .chain([&self.gain, &self.tone])
+         .for_each(|f| packet.extend_from_slice(&f.to_le_bytes()));
+      packet
+   }
+
+   /// Decodes a hearing-aid payload (without header).
+   pub fn decode(payload: &[u8]) -> Option<Self> {
+      if payload.len() < HEARING_AID_PAYLOAD_LEN {
+         return None;
+      }
+      let mut floats = payload
+         .chunks_exact(4)
+         .map(|c| f32::from_le_bytes([c[0], c[1], c[2], c[3]]));
+      let mut next = || floats.next().unwrap_or_default();
+
+      let mut profile = Self::default();
+      profile.audiogram.left.fill_with(&mut next);
+      profile.audiogram.right.fill_with(&mut next);
+      profile.gain = next();
+      profile.tone = next();
+      Some(profile)
+   }
+
+   pub fn to_json(self) -> serde_json::Value {
+      json!({
+         "audiogram": self.audiogram.to_json(),
+         "gain": self.gain,
+         "tone": self.tone,
+      })
+   }
+}
+
+#[cfg(test)]
+mod tests {
+   use super::*;
+
+   #[test]
+   fn csv_import_interpolates_missing_frequencies() {
+      let csv = "ear,frequency,db\n\
+                 left,250,10\nleft,500,20\nleft,1000,30\nleft,2000,40\nleft,4000,60\nleft,8000,70\n\
+                 # right ear only measured partially\n\
+                 right,1000,25\n";
+      let audiogram = Audiogram::from_csv(csv).unwrap();
+      assert_eq!(audiogram.left[..4], [10.0, 20.0, 30.0, 40.0]);
+      assert_eq!(audiogram.left[4], 50.0); // 3000 Hz between 2000 and 4000
+      assert_eq!(audiogram.left[6], 65.0); // 6000 Hz between 4000 and 8000
+      assert_eq!(audiogram.right, [25.0; 8]);
+   }
+
+   #[test]
+   fn json_import_rejects_bad_input() {
+      let json =
+         r#"{"left": [{"frequency": 1000, "db": 30}], "right": [{"frequency": 1234, "db": 30}]}"#;
+      assert!(matches!(
+         Audiogram::from_json(json),
+         Err(AudiogramError::UnknownFrequency(1234))
+      ));
+
+      let json = r#"{"left": [{"frequency": 1000, "db": 30}]}"#;
+      assert!(matches!(
+         Audiogram::from_json(json),
+         Err(AudiogramError::MissingEar("right"))
+      ));
+
+      let json =
+         r#"{"left": [{"frequency": 1000, "db": 130}], "right": [{"frequency": 1000, "db": 30}]}"#;
+      assert!(matches!(
+         Audiogram::from_json(json),
+         Err(AudiogramError::OutOfRange(_))
+      ));
+   }
+
+   #[test]
+   fn profile_roundtrip() {
+      let profile = HearingAidProfile {
+         audiogram: Audiogram {
+            left: [10.0, 15.0, 20.0, 25.0, 30.0, 35.0, 40.0, 45.0],
+            right: [5.0, 10.0, 15.0, 20.0, 25.0, 30.0, 35.0, 40.0],
+         },
+         gain: 0.25,
+         tone: -0.5,
+      };
+      profile.validate().unwrap();
+
+      let packet = profile.build();
+      assert_eq!(
+         packet.len(),
+         HDR_HEARING_AID.len() + HEARING_AID_PAYLOAD_LEN
+      );
+      let decoded = HearingAidProfile::decode(&packet[HDR_HEARING_AID.len()..]).unwrap();
+      assert_eq!(decoded, profile);
+   }
+}
@@ -4,6 +4,7 @@
 //! device management, protocol parsing, and packet handling.
 
 pub mod device;
+pub mod hearing;
 pub mod parser;
 pub mod protocol;
 pub mod recognition;
@@ -9,9 +9,14 @@ use log::{debug, warn};
 use smol_str::SmolStr;
 
 use crate::{
-   airpods::protocol::{
-      BatteryInfo, BatteryState, BatteryStatus, Component, EarDetectionStatus, HDR_BATTERY_STATE,
-      HDR_EAR_DETECTION, HDR_METADATA, NoiseControlMode,
+   airpods::{
+      hearing::{HEARING_AID_PAYLOAD_LEN, HearingAidProfile},
+      protocol::{
+         BatteryInfo, BatteryState, BatteryStatus, Component, EarDetectionStatus, EarTipFit,
+         FitTestResult, HDR_BATTERY_STATE, HDR_EAR_DETECTION, HDR_FIT_TEST_RESULT, HDR_HEARING_AID,
+         HDR_METADATA, HDR_TRANSPARENCY, NoiseControlMode, TRANSPARENCY_PAYLOAD_LEN,
+         TransparencySettings,
+      },
    },
    error::Result,
 };
@@ -38,6 +43,7 @@ pub enum ProtoError {
    PacketSizeMismatch { expected: usize, actual: usize },
 
    /// Unknown component type in battery status
+   #[allow(dead_code, reason = "not reported by the current parsers")]
    #[error("Unknown component type: 0x{component_type:02x}")]
    UnknownComponentType { component_type: u8 },
 
@@ -45,7 +51,12 @@ pub enum ProtoError {
    #[error("Unknown noise control mode: 0x{mode:02x}")]
    UnknownNoiseMode { mode: u32 },
 
+   /// Unknown ear-tip fit test result
+   #[error("Unknown fit test result: 0x{result:02x}")]
+   UnknownFitResult { result: u8 },
+
    /// Generic invalid packet format
+   #[allow(dead_code, reason = "not reported by the current parsers")]
    #[error("Invalid packet format: {reason}")]
    InvalidFormat { reason: &'static str },
 }
@@ -53,7 +64,8 @@ pub enum ProtoError {
 /// Parses a battery status packet from `AirPods`.
 ///
 /// The packet format contains battery information for up to 3 components
-/// (left, right, case).
+/// (left, right, case). Buds are listed primary first, which is used to
+/// populate [`BatteryInfo::primary_pod`] and [`BatteryInfo::secondary_pod`].
 pub fn parse_battery_status(data: &[u8]) -> Result<BatteryInfo> {
    if !data.starts_with(HDR_BATTERY_STATE) {
       return Err(
@@ -151,13 +163,14 @@ pub fn parse_battery_status(data: &[u8]) -> Result<BatteryInfo> {
             Component::Headphone => battery_info.headphone = battery_state,
          }
 
-         /*if matches!(component, Component::Left | Component::Right) {
+         // The primary bud is always reported first, the secondary second.
+         if matches!(component, Component::Left | Component::Right) {
             if battery_info.primary_pod.is_none() {
                battery_info.primary_pod = Some(component);
             } else {
                battery_info.secondary_pod = Some(component);
             }
-         }*/
+         }
       }
    }
    debug!("Battery parsed - {battery_info}");
@@ -182,7 +195,11 @@ pub fn parse_noise_mode(data: &[u8]) -> Result<NoiseControlMode> {
    Ok(mode)
 }
 
-pub fn parse_ear_detection(data: &[u8]) -> Result<EarDetectionStatus> {
+/// Parses an ear detection packet from `AirPods`.
+///
+/// The two status bytes are ordered primary bud first. If the primary bud is
+/// not known yet, the left bud is assumed to be primary.
+pub fn parse_ear_detection(data: &[u8], primary: Option<Component>) -> Result<EarDetectionStatus> {
    if !data.starts_with(HDR_EAR_DETECTION) {
       return Err(
          ProtoError::WrongPacketType {
@@ -200,9 +217,81 @@ pub fn parse_ear_detection(data: &[u8]) -> Result<EarDetectionStatus> {
          .into(),
       );
    }
-   let left_out = data[6] == 0x01;
-   let right_out = data[7] == 0x01;
-   Ok(EarDetectionStatus::new(!left_out, !right_out))
+   let primary_out = data[6] == 0x01;
+   let secondary_out = data[7] == 0x01;
+   if primary == Some(Component::Right) {
+      Ok(EarDetectionStatus::new(!secondary_out, !primary_out))
+   } else {
+      Ok(EarDetectionStatus::new(!primary_out, !secondary_out))
+   }
+}
+
+/// Parses a transparency customization packet from `AirPods`.
+pub fn parse_transparency_settings(data: &[u8]) -> Result<TransparencySettings> {
+   let Some(payload) = data.strip_prefix(HDR_TRANSPARENCY) else {
+      return Err(
+         ProtoError::WrongPacketType {
+            expected: "transparency settings",
+         }
+         .into(),
+      );
+   };
+   TransparencySettings::decode(payload).ok_or_else(|| {
+      ProtoError::PacketTooShort {
+         expected: HDR_TRANSPARENCY.len() + TRANSPARENCY_PAYLOAD_LEN,
+         actual: data.len(),
+      }
+      .into()
+   })
+}
+
+/// Parses a hearing-aid profile packet from `AirPods`.
+pub fn parse_hearing_aid_profile(data: &[u8]) -> Result<HearingAidProfile> {
+   let Some(payload) = data.strip_prefix(HDR_HEARING_AID) else {
+      return Err(
+         ProtoError::WrongPacketType {
+            expected: "hearing aid profile",
+         }
+         .into(),
+      );
+   };
+   HearingAidProfile::decode(payload).ok_or_else(|| {
+      ProtoError::PacketTooShort {
+         expected: HDR_HEARING_AID.len() + HEARING_AID_PAYLOAD_LEN,
+         actual: data.len(),
+      }
+      .into()
+   })
+}
+
+/// Parses an ear-tip fit test result packet from `AirPods`.
+///
+/// Like ear detection, the packet reports the primary bud first, so `primary`
+/// tells which side each result belongs to; it defaults to the left bud.
+/// Returns the results for the primary and secondary bud, in that order.
+pub fn parse_fit_test_result(data: &[u8], primary: Option<Component>) -> Result<FitTestResult> {
+   if !data.starts_with(HDR_FIT_TEST_RESULT) {
+      return Err(
+         ProtoError::WrongPacketType {
+            expected: "fit test result",
+         }
+         .into(),
+      );
+   }
+   if data.len() < 8 {
+      return Err(
+         ProtoError::PacketTooShort {
+            expected: 8,
+            actual: data.len(),
+         }
+         .into(),
+      );
+   }
+   let fit =
+      |result: u8| EarTipFit::from_repr(result).ok_or(ProtoError::UnknownFitResult { result });
+   let primary = primary.unwrap_or(Component::Left);
+   let secondary = primary.other_bud().unwrap_or(Component::Right);
+   Ok([(primary, fit(data[6])?), (secondary, fit(data[7])?)])
 }
 
 #[derive(Debug, Default)]
@@ -247,3 +336,143 @@ pub fn parse_metadata(data: &[u8]) -> Result<Metadata> {
 
    Ok(Metadata { name_candidate })
 }
+
+#[cfg(test)]
+mod tests {
+   use super::*;
+
+   #[test]
+   fn battery_status_tracks_primary_pod() {
+      let packet = hex::decode("04000400040003020164020104015502010801500101").unwrap();
+      let battery = parse_battery_status(&packet).unwrap();
+      assert_eq!(battery.right.level, 100);
+      assert_eq!(battery.left.level, 85);
+      assert_eq!(battery.case.level, 80);
+      assert_eq!(battery.primary_pod, Some(Component::Right));
+      assert_eq!(battery.secondary_pod, Some(Component::Left));
+   }
+
+   #[test]
+   fn battery_status_single_bud_has_no_secondary() {
+      let packet = hex::decode("0400040004000204013202010201000401").unwrap();
+      let battery = parse_battery_status(&packet).unwrap();
+      assert_eq!(battery.left.level, 50);
+      assert!(!battery.right.is_available());
+      assert_eq!(battery.primary_pod, Some(Component::Left));
+      assert_eq!(battery.secondary_pod, None);
+   }
+
+   #[test]
+   fn transparency_settings_roundtrip() {
+      let settings = TransparencySettings {
+         enabled: true,
+         amplification: 0.5,
+         balance: -0.25,
+         tone: 0.1,
+         ambient_noise_reduction: 0.75,
+         conversation_boost: true,
+         eq: [
+            [0.5, -0.25, 0.0, 1.0, 0.125, -1.0, 0.75, 0.0],
+            [0.0, 0.25, -0.5, 0.0, 0.375, 1.0, -0.75, 0.5],
+         ],
+      };
+      settings.validate().unwrap();
+
+      let packet = settings.build();
+      assert_eq!(
+         packet.len(),
+         HDR_TRANSPARENCY.len() + TRANSPARENCY_PAYLOAD_LEN
+      );
+      assert_eq!(parse_transparency_settings(&packet).unwrap(), settings);
+      assert!(parse_transparency_settings(&packet[..40]).is_err());
+   }
+
+   #[test]
+   fn transparency_settings_keep_eq() {
+      // As reported by the device: EQ set by the phone, then amplification, tone,
+      // conversation boost and ambient noise reduction for each bud
+      let floats: [f32; 25] = [
+         1.0, // enabled
+         0.1, 0.2, 0.3, 0.4, 0.5, 0.6, 0.7, 0.8, 0.25, 0.0, 0.0, 0.5, //
+         0.8, 0.7, 0.6, 0.5, 0.4, 0.3, 0.2, 0.1, 0.75, 0.0, 0.0, 0.5,
+      ];
+      let mut packet = HDR_TRANSPARENCY.to_vec();
+      for f in floats {
+         packet.extend_from_slice(&f.to_le_bytes());
+      }
+      let mut settings = parse_transparency_settings(&packet).unwrap();
+      assert_eq!(settings.eq[0][0], 0.1);
+      assert_eq!(settings.eq[1][7], 0.1);
+      assert_eq!(settings.build().as_slice(), packet.as_slice());
+
+      // Changing a level leaves the EQ untouched
+      settings.tone = 0.25;
+      let rebuilt = parse_transparency_settings(&settings.build()).unwrap();
+      assert_eq!(rebuilt.eq, settings.eq);
+      assert_eq!(rebuilt.tone, 0.25);
+   }
+
+   #[test]
+   fn transparency_settings_reject_out_of_range() {
+      let settings = TransparencySettings {
+         ambient_noise_reduction: 1.5,
+         ..Default::default()
+      };
+      assert!(settings.validate().is_err());
+
+      // Both within range, but the right bud would get 1.5
+      let settings = TransparencySettings {
+         amplification: 1.0,
+         balance: 0.5,
+         ..Default::default()
+      };
+      let err = settings.validate().unwrap_err();
+      assert_eq!(err.name, "right amplification");
+      assert_eq!(err.value, 1.5);
+
+      let settings = TransparencySettings {
+         amplification: 0.5,
+         balance: 0.5,
+         ..Default::default()
+      };
+      assert!(settings.validate().is_ok());
+   }
+
+   #[test]
+   fn ear_detection_follows_primary_pod() {
+      let packet = [0x04, 0x00, 0x04, 0x00, 0x06, 0x00, 0x00, 0x01];
+      let status = parse_ear_detection(&packet, None).unwrap();
+      assert!(status.is_left_in_ear());
+      assert!(!status.is_right_in_ear());
+
+      let status = parse_ear_detection(&packet, Some(Component::Right)).unwrap();
+      assert!(!status.is_left_in_ear());
+      assert!(status.is_right_in_ear());
+   }
+
+   #[test]
+   fn fit_test_result_primary_first() {
+      let packet = [0x04, 0x00, 0x04, 0x00, 0x0c, 0x00, 0x00, 0x01];
+      let result = parse_fit_test_result(&packet, None).unwrap();
+      assert_eq!(
+         result,
+         [
+            (Component::Left, EarTipFit::GoodSeal),
+            (Component::Right, EarTipFit::Adjust),
+         ]
+      );
+
+      let result = parse_fit_test_result(&packet, Some(Component::Right)).unwrap();
+      assert_eq!(
+         result,
+         [
+            (Component::Right, EarTipFit::GoodSeal),
+            (Component::Left, EarTipFit::Adjust),
+         ]
+      );
+
+      let packet = [0x04, 0x00, 0x04, 0x00, 0x0c, 0x00, 0x01, 0x07];
+      assert!(parse_fit_test_result(&packet, None).is_err());
+      assert!(parse_fit_test_result(&packet[..7], None).is_err());
+   }
+}
@@ -6,6 +6,7 @@
 use std::{
    fmt,
    num::NonZeroU8,
+   ops::RangeInclusive,
    str::FromStr,
    sync::{
       LazyLock,
@@ -15,6 +16,7 @@ use std::{
 
 use serde::{Deserialize, Serialize};
 use serde_json::json;
+use thiserror::Error;
 
 use crate::bluetooth::l2cap::Packet;
 
@@ -27,6 +29,7 @@ pub const PKT_SET_FEATURES: &[u8] = &[
 pub const PKT_REQUEST_NOTIFY: &[u8] = &[
    0x04, 0x00, 0x04, 0x00, 0x0f, 0x00, 0xff, 0xff, 0xff, 0xff, 0xff,
 ];
+pub const PKT_START_FIT_TEST: &[u8] = &[0x04, 0x00, 0x04, 0x00, 0x0b, 0x00, 0x01];
 
 // Parsing headers
 pub const HDR_BATTERY_STATE: &[u8] = b"\x04\x00\x04\x00\x04\x00";
@@ -38,6 +41,9 @@ pub const HDR_ACK_HANDSHAKE: &[u8] = b"\x01\x00\x04\x00";
 pub const HDR_ACK_FEATURES: &[u8] = b"\x04\x00\x04\x00\x2b";
 pub const HDR_METADATA: &[u8] = b"\x04\x00\x04\x00\x1d";
 pub const HDR_EAR_DETECTION: &[u8] = b"\x04\x00\x04\x00\x06\x00";
+pub const HDR_TRANSPARENCY: &[u8] = b"\x04\x00\x04\x00\x18\x00";
+pub const HDR_HEARING_AID: &[u8] = b"\x04\x00\x04\x00\x53\x00";
+pub const HDR_FIT_TEST_RESULT: &[u8] = b"\x04\x00\x04\x00\x0c\x00";
 
 /// Represents different components of `AirPods`.
 #[repr(u8)]
@@ -52,7 +58,10 @@ pub const HDR_EAR_DETECTION: &[u8] = b"\x04\x00\x04\x00\x06\x00";
    strum::FromRepr,
    strum::Display,
    strum::EnumString,
+   strum::IntoStaticStr,
+   Hash,
 )]
+#[strum(serialize_all = "lowercase")]
 pub enum Component {
    Headphone = 0x01,
    Right = 0x02,
@@ -60,6 +69,21 @@ pub enum Component {
    Case = 0x08,
 }
 
+impl Component {
+   pub fn to_str(self) -> &'static str {
+      self.into()
+   }
+
+   /// Returns the opposite bud, if this component is one of the buds.
+   pub const fn other_bud(self) -> Option<Self> {
+      match self {
+         Self::Left => Some(Self::Right),
+         Self::Right => Some(Self::Left),
+         Self::Headphone | Self::Case => None,
+      }
+   }
+}
+
 /// Battery status for `AirPods` components.
 #[derive(
    Default,
@@ -83,6 +107,36 @@ pub enum BatteryStatus {
    Disconnected = 0x04,
 }
 
+/// Ear-tip fit test result for a single bud.
+#[derive(
+   Debug,
+   Clone,
+   Copy,
+   PartialEq,
+   Eq,
+   Serialize,
+   Deserialize,
+   strum::FromRepr,
+   strum::Display,
+   strum::IntoStaticStr,
+)]
+#[repr(u8)]
+pub enum EarTipFit {
+   #[strum(serialize = "good_seal")]
+   GoodSeal = 0x00,
+   #[strum(serialize = "adjust_or_try_different_tip")]
+   Adjust = 0x01,
+}
+
+/// Fit test results for the primary and secondary bud, in that order.
+pub type FitTestResult = [(Component, EarTipFit); 2];
+
+impl EarTipFit {
+   pub fn to_str(self) -> &'static str {
+      self.into()
+   }
+}
+
 /// Noise control modes supported by `AirPods`.
 #[derive(
    Default,
@@ -229,7 +283,7 @@ pub const KNOWN_FEATURES: &[(u8, &str)] = &[
 ];
 
 /// Represents a feature command that can be sent to `AirPods`.
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
 #[repr(transparent)]
 pub struct FeatureId(u8);
 
@@ -373,6 +427,45 @@ impl fmt::Display for FeatureId {
    }
 }
 
+/// Identifies a model and firmware revision, as reported by the device modalias.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct DeviceModel {
+   pub product_id: u32,
+   pub version: u32,
+}
+
+/// Product IDs of the models with an ear-tip fit test (`AirPods` Pro).
+const FIT_TEST_PIDS: &[u32] = &[0x2014, 0x2024];
+
+impl DeviceModel {
+   pub fn supports_fit_test(self) -> bool {
+      FIT_TEST_PIDS.contains(&self.product_id)
+   }
+}
+
+impl fmt::Display for DeviceModel {
+   fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+      write!(f, "{:04x}/{:04x}", self.product_id, self.version)
+   }
+}
+
+/// Feature IDs queried by the discovery sweep.
+///
+/// Covers the known features and a few IDs past the highest one, where newer
+/// firmware adds its features. The rest of the ID space is never written to, as
+/// the effect of a query on an undocumented control ID is unknown.
+pub const FEATURE_SWEEP: RangeInclusive<u8> = 0x01..=FeatureId::ALLOW_OFF.id() + 8;
+
+/// Result of a feature discovery sweep.
+///
+/// Lists every feature ID the firmware answered a query for, along with the
+/// raw value it reported.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct FeatureProbe {
+   pub answered: Vec<(u8, u32)>,
+   pub last_updated: u64, // Unix timestamp
+}
+
 /// Battery state for a single `AirPods` component.
 #[derive(Default, Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
 pub struct BatteryState {
@@ -421,6 +514,9 @@ pub struct BatteryInfo {
    pub right: BatteryState,
    pub case: BatteryState,
    pub headphone: BatteryState,
+   /// Bud currently holding the host link and the microphone.
+   pub primary_pod: Option<Component>,
+   pub secondary_pod: Option<Component>,
 }
 
 impl fmt::Display for BatteryInfo {
@@ -440,6 +536,8 @@ impl BatteryInfo {
          right: BatteryState::new(),
          case: BatteryState::new(),
          headphone: BatteryState::new(),
+         primary_pod: None,
+         secondary_pod: None,
       }
    }
 
@@ -507,6 +605,14 @@ pub fn build_control_packet(cmd: u8, data: [u8; 4]) -> Packet {
       .collect()
 }
 
+/// Parses a control packet into its feature and raw value.
+pub fn parse_control_packet(data: &[u8]) -> Option<(FeatureId, u32)> {
+   let rest = data.strip_prefix(HDR_CMD_CTL)?;
+   let (feature, rest) = rest.split_first()?;
+   let value = u32::from_le_bytes(rest.try_into().ok()?);
+   Some((FeatureId::from_id(*feature), value))
+}
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 #[repr(u32)]
 pub enum FeatureCmd {
@@ -521,14 +627,170 @@ impl FeatureCmd {
       build_control_packet(feature, data.to_le_bytes())
    }
    pub fn parse(data: &[u8]) -> Option<(FeatureId, Self)> {
-      let rest = data.strip_prefix(HDR_CMD_CTL)?;
-      let (feature, rest) = rest.split_first()?;
-      let u: u32 = u32::from_le_bytes(rest.try_into().ok()?);
-      match u {
-         0 => Some((FeatureId::from_id(*feature), Self::Query)),
-         1 => Some((FeatureId::from_id(*feature), Self::Enable)),
-         2 => Some((FeatureId::from_id(*feature), Self::Disable)),
+      let (feature, value) = parse_control_packet(data)?;
+      match value {
+         0 => Some((feature, Self::Query)),
+         1 => Some((feature, Self::Enable)),
+         2 => Some((feature, Self::Disable)),
          _ => None,
       }
    }
 }
+
+/// A setting applied with a control packet.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Setting {
+   NoiseMode(NoiseControlMode),
+   Feature(FeatureId, bool),
+}
+
+impl Setting {
+   /// Feature and raw value of the control packet applying this setting.
+   pub const fn control(self) -> (FeatureId, u32) {
+      match self {
+         Self::NoiseMode(mode) => (FeatureId::NOISE_CONTROL, mode as u32),
+         Self::Feature(feature, true) => (feature, FeatureCmd::Enable as u32),
+         Self::Feature(feature, false) => (feature, FeatureCmd::Disable as u32),
+      }
+   }
+
+   pub fn build(self) -> Packet {
+      let (feature, value) = self.control();
+      build_control_packet(feature.id(), value.to_le_bytes())
+   }
+}
+
+/// A setting value outside of its accepted range.
+#[derive(Error, Debug)]
+#[error("{name} out of range: {value} (must be within {min}..={max})")]
+pub struct RangeError {
+   pub name: &'static str,
+   pub value: f32,
+   pub min: f32,
+   pub max: f32,
+}
+
+impl RangeError {
+   /// Checks that `value` lies within `min..=max`.
+   pub fn check(name: &'static str, value: f32, min: f32, max: f32) -> Result<(), Self> {
+      if (min..=max).contains(&value) {
+         Ok(())
+      } else {
+         Err(Self {
+            name,
+            value,
+            min,
+            max,
+         })
+      }
+   }
+}
+
+/// Number of EQ bands in each bud's transparency block.
+pub const TRANSPARENCY_EQ_BANDS: usize = 8;
+/// Number of floats in each bud's transparency block (EQ, amplification, tone,
+/// conversation boost, ambient noise reduction).
+const TRANSPARENCY_BUD_FLOATS: usize = TRANSPARENCY_EQ_BANDS + 4;
+/// Size of the transparency payload: enabled flag followed by the left and right blocks.
+pub const TRANSPARENCY_PAYLOAD_LEN: usize = 4 * (1 + 2 * TRANSPARENCY_BUD_FLOATS);
+
+/// Transparency mode customization (`AirPods` Pro).
+///
+/// On the wire every value is a little-endian `f32`, booleans included. Each bud
+/// carries its own amplification; `amplification` and `balance` are the mean
+/// and half-difference of the two, so `balance` is negative towards the left.
+/// The per-band EQ is set by the phone; it is not exposed, only sent back as
+/// reported so that changing the other values keeps it.
+#[derive(Default, Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct TransparencySettings {
+   pub enabled: bool,
+   pub amplification: f32,
+   pub balance: f32,
+   pub tone: f32,
+   pub ambient_noise_reduction: f32,
+   pub conversation_boost: bool,
+   /// EQ bands of the left and right bud.
+   pub eq: [[f32; TRANSPARENCY_EQ_BANDS]; 2],
+}
+
+impl TransparencySettings {
+   /// Checks that every value is within the range accepted by the firmware.
+   pub fn validate(&self) -> Result<(), RangeError> {
+      RangeError::check("amplification", self.amplification, -1.0, 1.0)?;
+      RangeError::check("balance", self.balance, -1.0, 1.0)?;
+      // Each bud gets the mean shifted by the balance, which must stay in range too
+      let left = self.amplification - self.balance;
+      RangeError::check("left amplification", left, -1.0, 1.0)?;
+      let right = self.amplification + self.balance;
+      RangeError::check("right amplification", right, -1.0, 1.0)?;
+      RangeError::check("tone", self.tone, -1.0, 1.0)?;
+      RangeError::check(
+         "ambient_noise_reduction",
+         self.ambient_noise_reduction,
+         0.0,
+         1.0,
+      )?;
+      Ok(())
+   }
+
+   /// Builds the packet applying these settings.
+   pub fn build(&self) -> Packet {
+      let flag = |b: bool| if b { 1.0 } else { 0.0 };
+      let bud = |eq: [f32; TRANSPARENCY_EQ_BANDS], amplification: f32| {
+         eq.into_iter().chain([
+            amplification,
+            self.tone,
+            flag(self.conversation_boost),
+            self.ambient_noise_reduction,
+         ])
+      };
+
+      let mut packet = Packet::from_slice(HDR_TRANSPARENCY);
+      [flag(self.enabled)]
+         .into_iter()
+         .chain(bud(self.eq[0], self.amplification - self.balance))
+         .chain(bud(self.eq[1], self.amplification + self.balance))
+         .for_each(|f: f32| packet.extend_from_slice(&f.to_le_bytes()));
+      packet
+   }
+
+   /// Decodes a transparency payload (without header).
+   pub fn decode(payload: &[u8]) -> Option<Self> {
+      if payload.len() < TRANSPARENCY_PAYLOAD_LEN {
+         return None;
+      }
+      let mut floats = payload
+         .chunks_exact(4)
+         .map(|c| f32::from_le_bytes([c[0], c[1], c[2], c[3]]));
+      let mut next = || floats.next().unwrap_or_default();
+
+      let enabled = next() != 0.0;
+      let mut bud = || {
+         let eq: [f32; TRANSPARENCY_EQ_BANDS] = std::array::from_fn(|_| next());
+         (eq, [next(), next(), next(), next()])
+      };
+      let (left_eq, left) = bud();
+      let (right_eq, right) = bud();
+
+      Some(Self {
+         enabled,
+         amplification: f32::midpoint(left[0], right[0]),
+         balance: (right[0] - left[0]) / 2.0,
+         tone: f32::midpoint(left[1], right[1]),
+         conversation_boost: left[2] != 0.0 || right[2] != 0.0,
+         ambient_noise_reduction: f32::midpoint(left[3], right[3]),
+         eq: [left_eq, right_eq],
+      })
+   }
+
+   pub fn to_json(self) -> serde_json::Value {
+      json!({
+         "enabled": self.enabled,
+         "amplification": self.amplification,
+         "balance": self.balance,
+         "tone": self.tone,
+         "ambient_noise_reduction": self.ambient_noise_reduction,
+         "conversation_boost": self.conversation_boost,
+      })
+   }
+}
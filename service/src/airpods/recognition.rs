@@ -4,8 +4,12 @@
 //! based on various criteria such as modalias, manufacturer data,
 //! services, and name/alias patterns.
 
+use std::collections::HashMap;
+
 use uuid::Uuid;
 
+use crate::airpods::protocol::{BatteryInfo, BatteryState, BatteryStatus};
+
 /// Patterns to match `AirPods` devices (case-insensitive)
 const AIRPOD_PATTERNS: &[&str] = &["airpods", "beats", "powerbeats"];
 // Note: "earpods" are wired earphones, not Bluetooth AirPods
@@ -52,6 +56,77 @@ fn check_manufacturer_data(data: &[u8]) -> bool {
    false
 }
 
+/// Contents of a proximity pairing advertisement.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ProximityPairing {
+   pub product_id: u32,
+   pub battery: BatteryInfo,
+}
+
+/// Parses a proximity pairing advertisement.
+///
+/// Layout after the type and length: `[2]` prefix, `[3..5]` model (LE),
+/// `[5]` status, `[6]` bud levels, `[7]` charging flags and case level.
+/// Levels are in steps of 10%, 15 meaning unknown.
+pub fn parse_proximity_pairing(mfg_data: &HashMap<u16, Vec<u8>>) -> Option<ProximityPairing> {
+   let data = mfg_data.get(&APPLE_CID)?;
+   if data.len() < 8 || data[0] != PP_TYPE {
+      return None;
+   }
+
+   let level = |nibble: u8, charging: bool| match nibble {
+      0..=10 => BatteryState {
+         level: nibble * 10,
+         status: if charging {
+            BatteryStatus::Charging
+         } else {
+            BatteryStatus::Discharging
+         },
+      },
+      _ => BatteryState::new(),
+   };
+   // The bud levels are swapped depending on which bud is broadcasting
+   let flipped = data[5] & 0x20 == 0;
+   let (left, right) = if flipped {
+      (data[6] >> 4, data[6] & 0x0F)
+   } else {
+      (data[6] & 0x0F, data[6] >> 4)
+   };
+   let charging = data[7] >> 4;
+   let (left_charging, right_charging) = if flipped {
+      (charging & 0x02 != 0, charging & 0x01 != 0)
+   } else {
+      (charging & 0x01 != 0, charging & 0x02 != 0)
+   };
+
+   let mut battery = BatteryInfo::new();
+   battery.left = level(left, left_charging);
+   battery.right = level(right, right_charging);
+   battery.case = level(data[7] & 0x0F, charging & 0x04 != 0);
+   Some(ProximityPairing {
+      product_id: u16::from_le_bytes([data[3], data[4]]).into(),
+      battery,
+   })
+}
+
+/// Checks for a proximity pairing advertisement, broadcast by the buds while
+/// the case lid is open
+pub fn is_proximity_pairing(mfg_data: &HashMap<u16, Vec<u8>>) -> bool {
+   mfg_data
+      .get(&APPLE_CID)
+      .is_some_and(|data| check_manufacturer_data(data))
+}
+
+/// Advertisement monitor pattern matching proximity pairing advertisements.
+pub fn proximity_pairing_pattern() -> bluer::monitor::Pattern {
+   let [lo, hi] = APPLE_CID.to_le_bytes();
+   bluer::monitor::Pattern::new(
+      bluer::monitor::data_type::MANUFACTURER_SPECIFIC_DATA,
+      0,
+      &[lo, hi, PP_TYPE],
+   )
+}
+
 pub async fn is_device_airpods(dev: &bluer::Device) -> bool {
    // 1. Check modalias (most reliable for connected devices)
    if let Ok(Some(modalias)) = dev.modalias().await
@@ -104,3 +179,27 @@ pub async fn is_device_airpods(dev: &bluer::Device) -> bool {
    }
    false
 }
+
+#[cfg(test)]
+mod tests {
+   use super::*;
+
+   #[test]
+   fn test_parse_proximity_pairing() {
+      let data = vec![0x07, 0x19, 0x01, 0x14, 0x20, 0x00, 0x89, 0x4F, 0x00];
+      let pp = parse_proximity_pairing(&HashMap::from([(APPLE_CID, data)])).unwrap();
+      assert_eq!(pp.product_id, 0x2014);
+      assert_eq!(pp.battery.left.level, 80);
+      assert_eq!(pp.battery.right.level, 90);
+      assert!(!pp.battery.left.is_charging());
+      assert!(!pp.battery.case.is_available());
+
+      let data = vec![0x07, 0x19, 0x01, 0x14, 0x20, 0x20, 0x89, 0x15, 0x00];
+      let pp = parse_proximity_pairing(&HashMap::from([(APPLE_CID, data)])).unwrap();
+      assert_eq!(pp.battery.left.level, 90);
+      assert!(pp.battery.left.is_charging());
+      assert_eq!(pp.battery.case.level, 50);
+
+      assert!(parse_proximity_pairing(&HashMap::from([(APPLE_CID, vec![0x10, 0x05])])).is_none());
+   }
+}
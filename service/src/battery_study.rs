@@ -2,10 +2,14 @@
 //!
 //! This module provides storage and analysis of battery drain patterns
 //! per `AirPods` device for immediate battery estimates upon connection
-//! and continuous accuracy improvement.
+//! and continuous accuracy improvement. The same environment also caches
+//! feature discovery results per model and firmware, keeps the last
+//! applied transparency settings per device, and the last known state of
+//! every device so it can be shown while the device is away.
 
 use std::{
    borrow::{Borrow, Cow},
+   collections::BTreeMap,
    path::PathBuf,
    sync::{Arc, LazyLock},
    time::{Duration, Instant, SystemTime},
@@ -16,12 +20,16 @@ use heed::{Database, Env, EnvOpenOptions, types::SerdeBincode};
 use log::{debug, info};
 use parking_lot::Mutex;
 use serde::{Deserialize, Serialize};
+use serde_json::json;
 use smol_str::SmolStr;
 use strum::IntoEnumIterator;
 use thiserror::Error;
 
 use crate::{
-   airpods::protocol::{BatteryInfo, BatteryState, NoiseControlMap, NoiseControlMode},
+   airpods::protocol::{
+      BatteryInfo, BatteryState, DeviceModel, FeatureProbe, NoiseControlMap, NoiseControlMode,
+      TransparencySettings,
+   },
    error::Result,
    ringbuf::Ring,
 };
@@ -198,6 +206,48 @@ struct Db {
    env: Env,
    /// MAC address -> `DeviceStudy`
    devices: Database<KeyCodec, SerdeBincode<DeviceStudy>>,
+   /// Model and firmware -> `FeatureProbe`
+   feature_probes: Database<SerdeBincode<DeviceModel>, SerdeBincode<FeatureProbe>>,
+   /// MAC address -> last applied `TransparencySettings`
+   transparency: Database<KeyCodec, SerdeBincode<TransparencySettings>>,
+   /// MAC address -> last known `DeviceState`
+   device_states: Database<KeyCodec, SerdeBincode<DeviceState>>,
+}
+
+/// Last known state of a device.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct DeviceState {
+   pub name: SmolStr,
+   pub adapter: SmolStr,
+   pub product_id: Option<u32>,
+   pub battery: Option<BatteryInfo>,
+   pub noise_mode: Option<NoiseControlMode>,
+   pub features: BTreeMap<SmolStr, bool>,
+   pub last_seen: u64, // Unix timestamp
+}
+
+impl DeviceState {
+   /// Converts the state to the JSON representation of an absent device.
+   pub fn to_json(&self, address: Address) -> serde_json::Value {
+      let mut info = json!({
+         "address": address.to_string(),
+         "name": self.name.as_str(),
+         "connected": false,
+         "last_seen": self.last_seen,
+         "adapter": self.adapter.as_str(),
+         "features": self.features,
+      });
+      if let Some(battery) = self.battery {
+         info["battery"] = battery.to_json();
+      }
+      if let Some(mode) = self.noise_mode {
+         info["noise_mode"] = json!(mode.to_str());
+      }
+      if let Some(product_id) = self.product_id {
+         info["model"] = json!({ "product_id": product_id });
+      }
+      info
+   }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -233,7 +283,7 @@ impl BatteryStudy {
       let env = unsafe {
          EnvOpenOptions::new()
             .map_size(10 * 1024 * 1024) // 10MB should be plenty
-            .max_dbs(1)
+            .max_dbs(4)
             .open(&path)
             .map_err(Error::OpenEnvironment)?
       };
@@ -244,10 +294,28 @@ impl BatteryStudy {
          .create_database(&mut wtxn, Some("devices"))
          .map_err(Error::DatabaseOperation)?;
 
+      let feature_probes = env
+         .create_database(&mut wtxn, Some("feature_probes"))
+         .map_err(Error::DatabaseOperation)?;
+
+      let transparency = env
+         .create_database(&mut wtxn, Some("transparency"))
+         .map_err(Error::DatabaseOperation)?;
+
+      let device_states = env
+         .create_database(&mut wtxn, Some("device_states"))
+         .map_err(Error::DatabaseOperation)?;
+
       wtxn.commit().map_err(Error::Transaction)?;
 
       Ok(Self {
-         db: Arc::new(Db { env, devices }),
+         db: Arc::new(Db {
+            env,
+            devices,
+            feature_probes,
+            transparency,
+            device_states,
+         }),
       })
    }
 
@@ -411,6 +479,116 @@ impl BatteryStudy {
 
       Ok(())
    }
+
+   /// Get the cached feature discovery result for a model and firmware
+   pub fn get_feature_probe(&self, model: DeviceModel) -> Result<Option<FeatureProbe>> {
+      let rtxn = self.db.env.read_txn().map_err(Error::Transaction)?;
+      Ok(self
+         .db
+         .feature_probes
+         .get(&rtxn, &model)
+         .map_err(Error::DatabaseOperation)?)
+   }
+
+   /// Store a feature discovery result for a model and firmware
+   pub fn put_feature_probe(&self, model: DeviceModel, mut probe: FeatureProbe) -> Result<()> {
+      probe.last_updated = unix_now();
+
+      let mut wtxn = self.db.env.write_txn().map_err(Error::Transaction)?;
+      self
+         .db
+         .feature_probes
+         .put(&mut wtxn, &model, &probe)
+         .map_err(Error::DatabaseOperation)?;
+      wtxn.commit().map_err(Error::Transaction)?;
+
+      Ok(())
+   }
+
+   /// Get the last applied transparency settings for a device
+   pub fn get_transparency(&self, address: Address) -> Result<Option<TransparencySettings>> {
+      let rtxn = self.db.env.read_txn().map_err(Error::Transaction)?;
+      Ok(self
+         .db
+         .transparency
+         .get(&rtxn, &address)
+         .map_err(Error::DatabaseOperation)?)
+   }
+
+   /// Store the last applied transparency settings for a device
+   pub fn put_transparency(&self, address: Address, settings: &TransparencySettings) -> Result<()> {
+      let mut wtxn = self.db.env.write_txn().map_err(Error::Transaction)?;
+      self
+         .db
+         .transparency
+         .put(&mut wtxn, &address, settings)
+         .map_err(Error::DatabaseOperation)?;
+      wtxn.commit().map_err(Error::Transaction)?;
+
+      Ok(())
+   }
+
+   /// Updates the last known state of a device, marking it as seen now
+   pub fn update_device_state(
+      &self,
+      address: Address,
+      f: impl FnOnce(&mut DeviceState),
+   ) -> Result<()> {
+      let mut wtxn = self.db.env.write_txn().map_err(Error::Transaction)?;
+      let mut state = self
+         .db
+         .device_states
+         .get(&wtxn, &address)
+         .map_err(Error::DatabaseOperation)?
+         .unwrap_or_default();
+      f(&mut state);
+      state.last_seen = unix_now();
+      self
+         .db
+         .device_states
+         .put(&mut wtxn, &address, &state)
+         .map_err(Error::DatabaseOperation)?;
+      wtxn.commit().map_err(Error::Transaction)?;
+
+      Ok(())
+   }
+
+   /// Get the last known state of a device
+   pub fn get_device_state(&self, address: Address) -> Result<Option<DeviceState>> {
+      let rtxn = self.db.env.read_txn().map_err(Error::Transaction)?;
+      Ok(self
+         .db
+         .device_states
+         .get(&rtxn, &address)
+         .map_err(Error::DatabaseOperation)?)
+   }
+
+   /// Get the last known state of every device, most recently seen first
+   pub fn device_states(&self) -> Result<Vec<(Address, DeviceState)>> {
+      let rtxn = self.db.env.read_txn().map_err(Error::Transaction)?;
+      let mut states = self
+         .db
+         .device_states
+         .iter(&rtxn)
+         .map_err(Error::DatabaseOperation)?
+         .collect::<heed::Result<Vec<_>>>()
+         .map_err(Error::DatabaseOperation)?;
+      states.sort_by_key(|(_, state)| std::cmp::Reverse(state.last_seen));
+      Ok(states)
+   }
+
+   /// Forget the last known state of a device, returning whether there was one
+   pub fn remove_device_state(&self, address: Address) -> Result<bool> {
+      let mut wtxn = self.db.env.write_txn().map_err(Error::Transaction)?;
+      let removed = self
+         .db
+         .device_states
+         .delete(&mut wtxn, &address)
+         .map_err(Error::DatabaseOperation)?;
+      wtxn.commit().map_err(Error::Transaction)?;
+
+      Ok(removed)
+   }
 }
 
 /// Battery tracker that manages real-time battery monitoring and integrates with long-term study.
@@ -936,6 +1114,75 @@ mod tests {
       Ok(())
    }
 
+   #[test]
+   fn test_feature_probe_roundtrip() -> Result<()> {
+      let (manager, _dir) = create_test_db()?;
+      let model = DeviceModel {
+         product_id: 0x2014,
+         version: 0x0a01,
+      };
+
+      assert!(manager.get_feature_probe(model)?.is_none());
+
+      let probe = FeatureProbe {
+         answered: vec![(0x0D, 2), (0x28, 1)],
+         ..Default::default()
+      };
+      manager.put_feature_probe(model, probe)?;
+
+      let cached = manager.get_feature_probe(model)?.unwrap();
+      assert_eq!(cached.answered, vec![(0x0D, 2), (0x28, 1)]);
+      assert!(cached.last_updated > 0);
+
+      let other = DeviceModel {
+         version: 0x0b01,
+         ..model
+      };
+      assert!(manager.get_feature_probe(other)?.is_none());
+
+      Ok(())
+   }
+
+   #[test]
+   fn test_device_state_roundtrip() -> Result<()> {
+      let (manager, _dir) = create_test_db()?;
+      assert!(manager.device_states()?.is_empty());
+
+      let mut battery = BatteryInfo::new();
+      battery.left = mock_state(40, false);
+      manager.update_device_state(TEST_ADDRESS, |state| {
+         state.name = SmolStr::new_static("Test AirPods");
+         state.battery = Some(battery);
+      })?;
+      // Fields left alone keep their value
+      manager.update_device_state(TEST_ADDRESS, |state| {
+         state.noise_mode = Some(NoiseControlMode::Active);
+      })?;
+
+      let states = manager.device_states()?;
+      assert_eq!(states.len(), 1);
+      let (address, state) = &states[0];
+      assert_eq!(*address, TEST_ADDRESS);
+      assert_eq!(state.name, "Test AirPods");
+      assert_eq!(state.battery.unwrap().left.level, 40);
+      assert_eq!(state.noise_mode, Some(NoiseControlMode::Active));
+      assert!(state.last_seen > 0);
+
+      let json = state.to_json(*address);
+      assert_eq!(json["connected"], false);
+      assert_eq!(json["battery"]["left"]["level"], 40);
+
+      let saved = manager
+         .get_device_state(TEST_ADDRESS)?
+         .expect("state saved");
+      assert_eq!(saved.last_seen, state.last_seen);
+      assert!(manager.remove_device_state(TEST_ADDRESS)?);
+      assert!(manager.get_device_state(TEST_ADDRESS)?.is_none());
+      assert!(manager.device_states()?.is_empty());
+
+      Ok(())
+   }
+
    #[test]
    fn test_battery_history_ring_buffer() {
       let mut history = BatteryHistory::default();
@@ -999,6 +1246,7 @@ mod tests {
             status: BatteryStatus::Normal,
          },
          headphone: BatteryState::new(),
+         ..BatteryInfo::new()
       };
 
       // Should return None when charging
@@ -1054,6 +1302,7 @@ mod tests {
             status: BatteryStatus::Normal,
          },
          headphone: BatteryState::new(),
+         ..BatteryInfo::new()
       };
 
       // Should return None with insufficient data
@@ -1100,6 +1349,7 @@ mod tests {
             status: BatteryStatus::Normal,
          },
          headphone: BatteryState::new(),
+         ..BatteryInfo::new()
       };
 
       // Should be false with no samples
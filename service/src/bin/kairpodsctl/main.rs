@@ -0,0 +1,672 @@
+//! Command line client for the kAirPods D-Bus service.
+//!
+//! Talks to `org.kairpods` on the session bus and provides the common
+//! operations without hand-written `busctl` calls.
+
+use std::{
+   collections::HashMap,
+   process::ExitCode,
+   time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use futures::StreamExt;
+use serde_json::{Value, json};
+use zbus::{Connection, MatchRule, MessageStream, fdo, message, proxy, zvariant};
+
+use statusbar::BarOptions;
+
+mod statusbar;
+
+const USAGE: &str = "\
+Usage: kairpodsctl [OPTIONS] <COMMAND>
+
+Commands:
+  list                               List managed devices
+  status <DEVICE>                    Show the state of a device
+  noise <DEVICE> <MODE>              Set noise control (off, anc, transparency, adaptive)
+  feature <DEVICE> <FEATURE> on|off  Toggle a feature, e.g. ear_detection
+  connect <DEVICE>                   Connect to a device
+  disconnect <DEVICE>                Disconnect from a device
+  pair [ADDRESS]                     Look for AirPods to pair, or pair the given ones
+  watch                              Stream events until interrupted
+  commands                           List the actions of SendCommand and their parameters
+  bar [DEVICE]                       Print a status line on every change
+  cycle-noise [DEVICE]               Switch to the next noise control mode
+
+DEVICE is a Bluetooth address or a device name. Without one, `bar` and
+`cycle-noise` use the first connected device.
+
+Exit status: 0 on success, 1 on failure, 2 on invalid usage, 3 if kairpodsd
+is not running, 4 if the device is unknown, 5 if it is not connected and 6
+if it did not answer in time.
+
+Options:
+  --json                   Print machine-readable JSON
+  --style STYLE            Status line style: waybar (default), polybar or i3blocks
+  --format FMT             Status line text, default \"{name} {battery}\"
+  --tooltip-format FMT     waybar tooltip text
+  --modes LIST             Modes cycled by cycle-noise, default \"anc,transparency,off\"
+  -v, --version            Print version information and exit
+  -h, --help               Print this help message and exit
+
+Placeholders: {name} {address} {battery} {left} {right} {case} {headphone}
+              {noise} {ttl} {status}";
+
+#[proxy(
+   interface = "org.kairpods.manager",
+   default_service = "org.kairpods",
+   default_path = "/org/kairpods/manager"
+)]
+trait Manager {
+   fn get_devices(&self) -> fdo::Result<String>;
+
+   fn get_device(&self, address: &str) -> fdo::Result<String>;
+
+   fn list_commands(&self) -> fdo::Result<String>;
+
+   fn send_command(
+      &self,
+      address: &str,
+      action: &str,
+      params: HashMap<&str, zvariant::Value<'_>>,
+   ) -> fdo::Result<bool>;
+
+   fn connect_device(&self, address: &str) -> fdo::Result<bool>;
+
+   fn disconnect_device(&self, address: &str) -> fdo::Result<bool>;
+
+   fn start_pairing(&self) -> fdo::Result<bool>;
+
+   fn get_pairing_candidates(&self) -> fdo::Result<String>;
+
+   fn pair_device(&self, address: &str) -> fdo::Result<bool>;
+}
+
+/// How long `pair` waits for AirPods to show up.
+const PAIRING_SCAN_TIME: Duration = Duration::from_secs(10);
+
+/// Well-known name of kairpodsd.
+const SERVICE_NAME: &str = "org.kairpods";
+
+/// Prefix of the errors specific to the service.
+const SERVICE_ERROR_PREFIX: &str = "org.kairpods.Error.";
+
+/// Failure of a command, mapped to an exit code.
+enum CliError {
+   Usage(String),
+   Bus(zbus::Error),
+   Service(fdo::Error),
+}
+
+impl From<fdo::Error> for CliError {
+   fn from(e: fdo::Error) -> Self {
+      Self::Service(e)
+   }
+}
+
+impl From<zbus::Error> for CliError {
+   fn from(e: zbus::Error) -> Self {
+      match e {
+         zbus::Error::FDO(e) => Self::Service(*e),
+         e => Self::Bus(e),
+      }
+   }
+}
+
+impl CliError {
+   fn report(&self) -> ExitCode {
+      match self {
+         Self::Usage(msg) => {
+            eprintln!("kairpodsctl: {msg}");
+            eprintln!("Try 'kairpodsctl --help' for more information.");
+            ExitCode::from(2)
+         },
+         Self::Bus(e) => {
+            eprintln!("kairpodsctl: D-Bus error: {e}");
+            ExitCode::FAILURE
+         },
+         Self::Service(fdo::Error::ServiceUnknown(_) | fdo::Error::NameHasNoOwner(_)) => {
+            eprintln!("kairpodsctl: kairpodsd is not running");
+            ExitCode::from(3)
+         },
+         Self::Service(fdo::Error::InvalidArgs(msg)) => {
+            eprintln!("kairpodsctl: invalid argument: {msg}");
+            ExitCode::from(2)
+         },
+         Self::Service(fdo::Error::Failed(msg)) => {
+            eprintln!("kairpodsctl: {msg}");
+            ExitCode::FAILURE
+         },
+         Self::Service(fdo::Error::ZBus(zbus::Error::MethodError(name, msg, _)))
+            if name.starts_with(SERVICE_ERROR_PREFIX) =>
+         {
+            let msg = msg.as_deref().unwrap_or(name.as_str());
+            eprintln!("kairpodsctl: {msg}");
+            match &name[SERVICE_ERROR_PREFIX.len()..] {
+               "NotFound" => {
+                  eprintln!("Run 'kairpodsctl list' to see the managed devices.");
+                  ExitCode::from(4)
+               },
+               "NotConnected" => {
+                  eprintln!("Run 'kairpodsctl connect <DEVICE>' to connect it.");
+                  ExitCode::from(5)
+               },
+               "Timeout" => ExitCode::from(6),
+               _ => ExitCode::FAILURE,
+            }
+         },
+         Self::Service(e) => {
+            eprintln!("kairpodsctl: {e}");
+            ExitCode::FAILURE
+         },
+      }
+   }
+}
+
+type Result<T, E = CliError> = std::result::Result<T, E>;
+
+#[tokio::main(flavor = "current_thread")]
+async fn main() -> ExitCode {
+   let mut json = false;
+   let mut bar = BarOptions::default();
+   let mut args = Vec::new();
+   let mut argv = std::env::args().skip(1);
+   while let Some(arg) = argv.next() {
+      match arg.as_str() {
+         "--json" => json = true,
+         flag if BarOptions::accepts(flag) => {
+            let Some(value) = argv.next() else {
+               return CliError::Usage(format!("{flag} needs a value")).report();
+            };
+            if let Err(e) = bar.set(flag, value) {
+               return e.report();
+            }
+         },
+         "--version" | "-v" => {
+            println!("kairpodsctl {}", env!("CARGO_PKG_VERSION"));
+            return ExitCode::SUCCESS;
+         },
+         "--help" | "-h" => {
+            println!("{USAGE}");
+            return ExitCode::SUCCESS;
+         },
+         _ => args.push(arg),
+      }
+   }
+
+   match run(&args, json, &bar).await {
+      Ok(()) => ExitCode::SUCCESS,
+      Err(e) => e.report(),
+   }
+}
+
+async fn run(args: &[String], json: bool, bar: &BarOptions) -> Result<()> {
+   let args: Vec<&str> = args.iter().map(String::as_str).collect();
+   let Some((&command, args)) = args.split_first() else {
+      return Err(CliError::Usage("missing command".into()));
+   };
+   let (min_args, max_args) = match command {
+      "list" | "watch" | "commands" => (0, 0),
+      "bar" | "cycle-noise" | "pair" => (0, 1),
+      "status" | "connect" | "disconnect" => (1, 1),
+      "noise" => (2, 2),
+      "feature" => (3, 3),
+      _ => return Err(CliError::Usage(format!("unknown command '{command}'"))),
+   };
+   if !(min_args..=max_args).contains(&args.len()) {
+      return Err(CliError::Usage(format!(
+         "wrong number of arguments for '{command}'"
+      )));
+   }
+
+   let connection = connect().await?;
+   let manager = ManagerProxy::new(&connection).await?;
+
+   match (command, args) {
+      ("list", []) => {
+         let devices = devices(&manager).await?;
+         if json {
+            println!("{}", Value::Array(devices));
+         } else if devices.is_empty() {
+            println!("No devices");
+         } else {
+            for device in &devices {
+               print_summary(device);
+            }
+         }
+      },
+      ("status", [device]) => {
+         let address = resolve(&manager, device).await?;
+         let state = parse_json(&manager.get_device(&address).await?)?;
+         if json {
+            println!("{state}");
+         } else {
+            print_status(&state);
+         }
+      },
+      ("noise", [device, mode]) => {
+         let address = resolve(&manager, device).await?;
+         let params = HashMap::from([("value", zvariant::Value::from(*mode))]);
+         manager
+            .send_command(&address, "set_noise_mode", params)
+            .await?;
+         report(json, &address, json!({ "noise_mode": mode }));
+      },
+      ("feature", [device, feature, state]) => {
+         let enabled = match *state {
+            "on" | "true" | "1" => true,
+            "off" | "false" | "0" => false,
+            _ => {
+               return Err(CliError::Usage(format!(
+                  "expected on or off, got {state:?}"
+               )));
+            },
+         };
+         let address = resolve(&manager, device).await?;
+         let params = HashMap::from([
+            ("feature", zvariant::Value::from(*feature)),
+            ("enabled", zvariant::Value::from(enabled)),
+         ]);
+         manager
+            .send_command(&address, "set_feature", params)
+            .await?;
+         report(
+            json,
+            &address,
+            json!({ "feature": feature, "enabled": enabled }),
+         );
+      },
+      ("connect", [device]) => {
+         let address = resolve(&manager, device).await?;
+         manager.connect_device(&address).await?;
+         report(json, &address, json!({ "connected": true }));
+      },
+      ("disconnect", [device]) => {
+         let address = resolve(&manager, device).await?;
+         manager.disconnect_device(&address).await?;
+         report(json, &address, json!({ "connected": false }));
+      },
+      ("pair", []) => {
+         manager.start_pairing().await?;
+         if !json {
+            println!("Open the case lid near this computer, looking for AirPods...");
+         }
+         tokio::time::sleep(PAIRING_SCAN_TIME).await;
+         let found = parse_json(&manager.get_pairing_candidates().await?)?;
+         if json {
+            println!("{found}");
+         } else {
+            print_candidates(&found["candidates"]);
+         }
+      },
+      ("pair", [address]) => {
+         manager.pair_device(address).await?;
+         report(json, address, json!({ "paired": true }));
+      },
+      ("watch", []) => watch(&connection, json).await?,
+      ("commands", []) => {
+         let commands = parse_json(&manager.list_commands().await?)?;
+         if json {
+            println!("{commands}");
+         } else {
+            print_commands(&commands);
+         }
+      },
+      ("bar", device) => {
+         statusbar::run_bar(&connection, &manager, device.first().copied(), bar).await?;
+      },
+      ("cycle-noise", device) => {
+         statusbar::cycle_noise(&manager, device.first().copied(), bar).await?;
+      },
+      _ => unreachable!("arity checked above"),
+   }
+   Ok(())
+}
+
+/// Connects to the bus kairpodsd runs on: the session bus, or the system bus
+/// if it only runs there (`kairpodsd --system`).
+async fn connect() -> Result<Connection> {
+   let session = Connection::session().await;
+   if let Ok(connection) = &session
+      && has_service(connection).await
+   {
+      return session.map_err(CliError::from);
+   }
+   if let Ok(connection) = Connection::system().await
+      && has_service(&connection).await
+   {
+      return Ok(connection);
+   }
+   // Report the service as not running on the session bus
+   Ok(session?)
+}
+
+async fn has_service(connection: &Connection) -> bool {
+   let Ok(dbus) = fdo::DBusProxy::new(connection).await else {
+      return false;
+   };
+   dbus
+      .name_has_owner(SERVICE_NAME.try_into().expect("valid bus name"))
+      .await
+      .unwrap_or(false)
+}
+
+fn parse_json(s: &str) -> Result<Value> {
+   serde_json::from_str(s)
+      .map_err(|e| CliError::Service(fdo::Error::Failed(format!("invalid reply: {e}"))))
+}
+
+async fn devices(manager: &ManagerProxy<'_>) -> Result<Vec<Value>> {
+   match parse_json(&manager.get_devices().await?)? {
+      Value::Array(devices) => Ok(devices),
+      _ => Ok(Vec::new()),
+   }
+}
+
+/// Resolves a device name to its address; anything else is passed through as is.
+async fn resolve(manager: &ManagerProxy<'_>, device: &str) -> Result<String> {
+   let found = devices(manager).await?.into_iter().find_map(|d| {
+      let address = d["address"].as_str()?;
+      let name = d["name"].as_str().unwrap_or_default();
+      (address.eq_ignore_ascii_case(device) || name.eq_ignore_ascii_case(device))
+         .then(|| address.to_string())
+   });
+   Ok(found.unwrap_or_else(|| device.to_string()))
+}
+
+fn report(json: bool, address: &str, mut result: Value) {
+   if json {
+      result["address"] = json!(address);
+      println!("{result}");
+   } else {
+      println!("OK");
+   }
+}
+
+fn battery_str(battery: &Value) -> String {
+   match battery["level"].as_u64() {
+      Some(level) if battery["charging"].as_bool() == Some(true) => format!("{level}%+"),
+      Some(level) => format!("{level}%"),
+      None => "-".into(),
+   }
+}
+
+fn print_summary(device: &Value) {
+   let battery = &device["battery"];
+   let levels = if battery["headphone"].is_null() {
+      format!(
+         "L {} R {} C {}",
+         battery_str(&battery["left"]),
+         battery_str(&battery["right"]),
+         battery_str(&battery["case"])
+      )
+   } else {
+      battery_str(&battery["headphone"])
+   };
+   println!(
+      "{}  {:<24}  {:<12}  {:<20}  {}",
+      device["address"].as_str().unwrap_or("?"),
+      device["name"].as_str().unwrap_or("?"),
+      if device["connected"].as_bool() == Some(true) {
+         "connected".to_string()
+      } else if let Some(last_seen) = device["last_seen"].as_u64() {
+         format!("seen {} ago", ago(last_seen))
+      } else {
+         "disconnected".to_string()
+      },
+      levels,
+      device["noise_mode"].as_str().unwrap_or("-"),
+   );
+}
+
+/// Formats the time since a Unix timestamp, e.g. `2h`.
+fn ago(timestamp: u64) -> String {
+   let now = SystemTime::now()
+      .duration_since(UNIX_EPOCH)
+      .map_or(0, |d| d.as_secs());
+   match now.saturating_sub(timestamp) {
+      secs @ ..60 => format!("{secs}s"),
+      secs @ ..3600 => format!("{}m", secs / 60),
+      secs @ ..86400 => format!("{}h", secs / 3600),
+      secs => format!("{}d", secs / 86400),
+   }
+}
+
+fn print_status(device: &Value) {
+   let field = |key: &str| device[key].as_str().unwrap_or("-").to_string();
+   println!("Name:        {}", field("name"));
+   println!("Address:     {}", field("address"));
+   println!(
+      "Connected:   {}",
+      if device["connected"].as_bool() == Some(true) {
+         "yes"
+      } else {
+         "no"
+      }
+   );
+   if let Some(last_seen) = device["last_seen"].as_u64() {
+      println!("Last seen:   {} ago", ago(last_seen));
+   }
+
+   let battery = &device["battery"];
+   if battery["headphone"].is_null() {
+      println!(
+         "Battery:     left {}, right {}, case {}",
+         battery_str(&battery["left"]),
+         battery_str(&battery["right"]),
+         battery_str(&battery["case"])
+      );
+   } else {
+      println!("Battery:     {}", battery_str(&battery["headphone"]));
+   }
+   if let Some(minutes) = device["battery_ttl_estimate"].as_u64() {
+      println!("Remaining:   {}h {:02}m", minutes / 60, minutes % 60);
+   }
+   println!("Noise mode:  {}", field("noise_mode"));
+
+   let ear = &device["ear_detection"];
+   if !ear.is_null() {
+      let in_ear = |key: &str| {
+         if ear[key].as_bool() == Some(true) {
+            "in ear"
+         } else {
+            "out"
+         }
+      };
+      println!(
+         "Ears:        left {}, right {}",
+         in_ear("left_in_ear"),
+         in_ear("right_in_ear")
+      );
+   }
+   if let Some(primary) = device["primary_bud"].as_str() {
+      println!("Primary bud: {primary}");
+   }
+
+   let connection = &device["connection"];
+   if let Some(state) = connection["state"].as_str() {
+      let mut line = state.replace('_', " ");
+      if let Some(adapter) = connection["adapter"].as_str()
+         && !adapter.is_empty()
+      {
+         line += &format!(" via {adapter}");
+      }
+      if let Some(retries) = connection["retry_count"].as_u64()
+         && retries > 0
+      {
+         line += &format!(", {retries} failed attempts");
+      }
+      if let Some(next) = connection["next_retry"].as_u64() {
+         let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_secs());
+         line += &format!(", next in {}s", next.saturating_sub(now));
+      }
+      println!("Link:        {line}");
+      if let Some(error) = connection["last_error"].as_str() {
+         println!("Last error:  {error}");
+      }
+   }
+
+   if let Some(features) = device["features"].as_object()
+      && !features.is_empty()
+   {
+      let mut features: Vec<_> = features.iter().collect();
+      features.sort_by_key(|(name, _)| *name);
+      println!("Features:");
+      for (name, enabled) in features {
+         let state = if enabled.as_bool() == Some(true) {
+            "on"
+         } else {
+            "off"
+         };
+         println!("  {name:<28} {state}");
+      }
+   }
+}
+
+fn print_candidates(candidates: &Value) {
+   let candidates = candidates.as_array().map(Vec::as_slice).unwrap_or_default();
+   if candidates.is_empty() {
+      println!("No AirPods found");
+      return;
+   }
+   for candidate in candidates {
+      let battery = &candidate["battery"];
+      println!(
+         "{}  {:<24}  {:<20}  {}",
+         candidate["address"].as_str().unwrap_or("?"),
+         candidate["name"].as_str().unwrap_or("?"),
+         format!(
+            "L {} R {} C {}",
+            battery_str(&battery["left"]),
+            battery_str(&battery["right"]),
+            battery_str(&battery["case"])
+         ),
+         if candidate["paired"].as_bool() == Some(true) {
+            "paired"
+         } else {
+            ""
+         },
+      );
+   }
+   println!("Run 'kairpodsctl pair <ADDRESS>' to pair one of them.");
+}
+
+fn print_commands(commands: &Value) {
+   for command in commands.as_array().into_iter().flatten() {
+      println!(
+         "{}  {}",
+         command["name"].as_str().unwrap_or("?"),
+         command["description"].as_str().unwrap_or_default()
+      );
+      for param in command["params"].as_array().into_iter().flatten() {
+         let domain = if let Some(values) = param["values"].as_array() {
+            let values: Vec<&str> = values.iter().filter_map(Value::as_str).collect();
+            values.join("|")
+         } else if param["type"] == "d" {
+            format!("{}..{}", param["min"], param["max"])
+         } else {
+            "true|false".into()
+         };
+         let optional = if param["required"].as_bool() == Some(true) {
+            ""
+         } else {
+            " (optional)"
+         };
+         println!(
+            "  {:<26} {}{optional}",
+            param["name"].as_str().unwrap_or("?"),
+            domain
+         );
+      }
+   }
+}
+
+/// Prints every signal of the service until the stream ends.
+async fn watch(connection: &Connection, json: bool) -> Result<()> {
+   let rule = MatchRule::builder()
+      .msg_type(message::Type::Signal)
+      .sender("org.kairpods")?
+      .interface("org.kairpods.manager")?
+      .build();
+   let mut stream = MessageStream::for_match_rule(rule, connection, None).await?;
+
+   while let Some(msg) = stream.next().await {
+      let msg = msg?;
+      let header = msg.header();
+      let Some(member) = header.member() else {
+         continue;
+      };
+
+      let (address, payload) = signal_fields(&msg.body())?;
+
+      if json {
+         // Most payloads are JSON themselves, keep them structured
+         let payload = payload.map(|p| serde_json::from_str(&p).unwrap_or(Value::String(p)));
+         println!(
+            "{}",
+            json!({ "event": member.as_str(), "address": address, "data": payload })
+         );
+      } else {
+         let line: Vec<&str> = [
+            Some(member.as_str()),
+            address.as_deref(),
+            payload.as_deref(),
+         ]
+         .into_iter()
+         .flatten()
+         .collect();
+         println!("{}", line.join(" "));
+      }
+   }
+   Ok(())
+}
+
+/// Splits the body of a signal into the device address and the payload.
+///
+/// Signals of another shape than the usual address and JSON payload are printed as
+/// D-Bus values, so that none goes missing.
+fn signal_fields(body: &message::Body) -> zbus::Result<(Option<String>, Option<String>)> {
+   Ok(match body.signature().to_string_no_parens().as_str() {
+      "" => (None, None),
+      "s" => (Some(body.deserialize::<String>()?), None),
+      "ss" => {
+         let (address, payload): (String, String) = body.deserialize()?;
+         (Some(address), Some(payload))
+      },
+      _ => (
+         None,
+         Some(body.deserialize::<zvariant::Structure<'_>>()?.to_string()),
+      ),
+   })
+}
+
+#[cfg(test)]
+mod tests {
+   use super::*;
+
+   fn fields<B>(body: &B) -> (Option<String>, Option<String>)
+   where
+      B: serde::Serialize + zvariant::DynamicType,
+   {
+      let msg = zbus::Message::signal("/org/kairpods/manager", "org.kairpods.manager", "Test")
+         .unwrap()
+         .build(body)
+         .unwrap();
+      signal_fields(&msg.body()).unwrap()
+   }
+
+   #[test]
+   fn test_signal_fields() {
+      assert_eq!(fields(&()), (None, None));
+      assert_eq!(
+         fields(&("AA:BB:CC:DD:EE:FF", "{}")),
+         (Some("AA:BB:CC:DD:EE:FF".into()), Some("{}".into()))
+      );
+      assert_eq!(
+         fields(&(7u32, true)),
+         (None, Some("(uint32 7, true)".into()))
+      );
+   }
+}
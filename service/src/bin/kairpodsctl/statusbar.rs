@@ -0,0 +1,331 @@
+//! Status-bar output for waybar, polybar and i3blocks.
+//!
+//! `bar` prints a status line for the selected device whenever the service
+//! signals a change, `cycle-noise` is meant to be bound to a click on it.
+
+use std::{collections::HashMap, time::Duration};
+
+use futures::StreamExt;
+use serde_json::{Value, json};
+use tokio::{select, time};
+use zbus::{Connection, MatchRule, MessageStream, message, zvariant};
+
+use crate::{CliError, ManagerProxy, Result, battery_str, devices, parse_json};
+
+/// Interval at which the status is refreshed even without signals, to keep
+/// the remaining time current and to notice a restarted service.
+const REFRESH_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Battery level at or below which the `low` class is used.
+const LOW_BATTERY: u64 = 20;
+
+const DEFAULT_FORMAT: &str = "{name} {battery}";
+const DEFAULT_TOOLTIP_FORMAT: &str =
+   "{name}\nLeft {left} · Right {right} · Case {case}\nNoise control: {noise}\nRemaining: {ttl}";
+const DEFAULT_MODES: [&str; 3] = ["anc", "transparency", "off"];
+
+/// Output format of the status line.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum BarStyle {
+   /// waybar custom module JSON (`return-type: json`).
+   #[default]
+   Waybar,
+   /// Plain text line, for polybar `tail = true` and i3blocks `interval=persist`.
+   Plain,
+}
+
+/// Options of the status-bar commands.
+pub struct BarOptions {
+   pub style: BarStyle,
+   pub format: String,
+   pub tooltip_format: String,
+   pub modes: Vec<String>,
+}
+
+impl Default for BarOptions {
+   fn default() -> Self {
+      Self {
+         style: BarStyle::default(),
+         format: DEFAULT_FORMAT.to_string(),
+         tooltip_format: DEFAULT_TOOLTIP_FORMAT.to_string(),
+         modes: DEFAULT_MODES.map(String::from).to_vec(),
+      }
+   }
+}
+
+impl BarOptions {
+   /// Returns whether `flag` is a status-bar option taking a value.
+   pub fn accepts(flag: &str) -> bool {
+      matches!(
+         flag,
+         "--style" | "--format" | "--tooltip-format" | "--modes"
+      )
+   }
+
+   /// Applies a status-bar option.
+   pub fn set(&mut self, flag: &str, value: String) -> Result<()> {
+      match flag {
+         "--style" => {
+            self.style = match value.as_str() {
+               "waybar" => BarStyle::Waybar,
+               "polybar" | "i3blocks" | "plain" => BarStyle::Plain,
+               _ => return Err(CliError::Usage(format!("unknown style {value:?}"))),
+            };
+         },
+         "--format" => self.format = value,
+         "--tooltip-format" => self.tooltip_format = value,
+         "--modes" => {
+            self.modes = value
+               .split(',')
+               .map(str::trim)
+               .filter(|m| !m.is_empty())
+               .map(String::from)
+               .collect();
+            if self.modes.is_empty() {
+               return Err(CliError::Usage("--modes needs at least one mode".into()));
+            }
+         },
+         _ => unreachable!("checked by accepts()"),
+      }
+      Ok(())
+   }
+}
+
+/// Picks the device matching `selector` (address or name), or the first
+/// connected one if no selector is given.
+async fn select_device(
+   manager: &ManagerProxy<'_>,
+   selector: Option<&str>,
+) -> Result<Option<Value>> {
+   let devices = devices(manager).await?;
+   Ok(match selector {
+      Some(selector) => devices.into_iter().find(|d| {
+         [&d["address"], &d["name"]]
+            .iter()
+            .any(|v| v.as_str().is_some_and(|s| s.eq_ignore_ascii_case(selector)))
+      }),
+      None => devices
+         .into_iter()
+         .find(|d| d["connected"].as_bool() == Some(true)),
+   })
+}
+
+/// Lowest available level, in percent: the headphone level, or the lower bud.
+fn percentage(device: &Value) -> Option<u64> {
+   let battery = &device["battery"];
+   if let Some(level) = battery["headphone"]["level"].as_u64() {
+      return Some(level);
+   }
+   [&battery["left"], &battery["right"]]
+      .iter()
+      .filter_map(|b| b["level"].as_u64())
+      .min()
+}
+
+fn is_charging(device: &Value) -> bool {
+   ["left", "right", "headphone"]
+      .iter()
+      .any(|c| device["battery"][c]["charging"].as_bool() == Some(true))
+}
+
+/// Expands `{placeholder}`s in `template` with the state of `device`.
+///
+/// Unknown placeholders are kept verbatim.
+fn render(template: &str, device: &Value) -> String {
+   let battery = &device["battery"];
+   let field = |key: &str| -> Option<String> {
+      Some(match key {
+         "name" | "address" => device[key].as_str().unwrap_or_default().to_string(),
+         "left" | "right" | "case" | "headphone" => battery_str(&battery[key]),
+         "battery" => match percentage(device) {
+            Some(level) if is_charging(device) => format!("{level}%+"),
+            Some(level) => format!("{level}%"),
+            None => "-".into(),
+         },
+         "noise" => device["noise_mode"].as_str().unwrap_or("-").to_string(),
+         "ttl" => match device["battery_ttl_estimate"].as_u64() {
+            Some(minutes) => format!("{}h {:02}m", minutes / 60, minutes % 60),
+            None => "-".into(),
+         },
+         "status" => {
+            if device["connected"].as_bool() == Some(true) {
+               "connected".into()
+            } else {
+               "disconnected".into()
+            }
+         },
+         _ => return None,
+      })
+   };
+
+   let mut out = String::with_capacity(template.len());
+   let mut rest = template;
+   while let Some(start) = rest.find('{') {
+      out.push_str(&rest[..start]);
+      let tail = &rest[start..];
+      match tail
+         .find('}')
+         .and_then(|end| Some((field(&tail[1..end])?, end)))
+      {
+         Some((value, end)) => {
+            out.push_str(&value);
+            rest = &tail[end + 1..];
+         },
+         None => {
+            out.push('{');
+            rest = &tail[1..];
+         },
+      }
+   }
+   out.push_str(rest);
+   out
+}
+
+/// Builds the status line for `device`, or the empty line if there is none.
+fn status_line(options: &BarOptions, device: Option<&Value>) -> String {
+   let Some(device) = device else {
+      return match options.style {
+         BarStyle::Waybar => json!({ "text": "", "class": "disconnected" }).to_string(),
+         BarStyle::Plain => String::new(),
+      };
+   };
+
+   let text = render(&options.format, device);
+   match options.style {
+      BarStyle::Plain => text,
+      BarStyle::Waybar => {
+         let percentage = percentage(device);
+         let class = if device["connected"].as_bool() != Some(true) {
+            "disconnected"
+         } else if is_charging(device) {
+            "charging"
+         } else if percentage.is_some_and(|p| p <= LOW_BATTERY) {
+            "low"
+         } else {
+            "connected"
+         };
+         json!({
+            "text": text,
+            "tooltip": render(&options.tooltip_format, device),
+            "class": class,
+            "percentage": percentage,
+         })
+         .to_string()
+      },
+   }
+}
+
+/// Prints the status line of a device every time it changes.
+pub async fn run_bar(
+   connection: &Connection,
+   manager: &ManagerProxy<'_>,
+   selector: Option<&str>,
+   options: &BarOptions,
+) -> Result<()> {
+   let rule = MatchRule::builder()
+      .msg_type(message::Type::Signal)
+      .sender("org.kairpods")?
+      .path("/org/kairpods/manager")?
+      .build();
+   let mut stream = MessageStream::for_match_rule(rule, connection, None).await?;
+   let mut refresh = time::interval(REFRESH_INTERVAL);
+
+   let mut last = None;
+   loop {
+      // Keep showing the empty status while the service is not running
+      let device = match select_device(manager, selector).await {
+         Ok(device) => device,
+         Err(CliError::Service(_)) => None,
+         Err(e) => return Err(e),
+      };
+      let line = status_line(options, device.as_ref());
+      if last.as_ref() != Some(&line) {
+         println!("{line}");
+         last = Some(line);
+      }
+
+      select! {
+         msg = stream.next() => {
+            if msg.is_none() {
+               return Ok(());
+            }
+         }
+         _ = refresh.tick() => {}
+      }
+   }
+}
+
+/// Switches the device to the mode following its current one in `options.modes`.
+pub async fn cycle_noise(
+   manager: &ManagerProxy<'_>,
+   selector: Option<&str>,
+   options: &BarOptions,
+) -> Result<()> {
+   let Some(device) = select_device(manager, selector).await? else {
+      return Err(CliError::Service(zbus::fdo::Error::Failed(
+         "no connected device".into(),
+      )));
+   };
+   let address = device["address"].as_str().unwrap_or_default();
+   let state = parse_json(&manager.get_device(address).await?)?;
+
+   let current = state["noise_mode"].as_str();
+   let next = options
+      .modes
+      .iter()
+      .position(|m| Some(m.as_str()) == current)
+      .map_or(0, |i| (i + 1) % options.modes.len());
+   let mode = options.modes[next].as_str();
+
+   let params = HashMap::from([("value", zvariant::Value::from(mode))]);
+   manager
+      .send_command(address, "set_noise_mode", params)
+      .await?;
+   Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+   use super::*;
+
+   fn device() -> Value {
+      json!({
+         "address": "AA:BB:CC:DD:EE:FF",
+         "name": "AirPods Pro",
+         "connected": true,
+         "battery": {
+            "left": { "level": 80, "charging": false },
+            "right": { "level": 15, "charging": false },
+            "case": null,
+            "headphone": null,
+         },
+         "battery_ttl_estimate": 95,
+         "noise_mode": "anc",
+      })
+   }
+
+   #[test]
+   fn test_render() {
+      let device = device();
+      assert_eq!(
+         render(
+            "{name}: {left} {right} {case} [{noise}] {ttl} {bogus",
+            &device
+         ),
+         "AirPods Pro: 80% 15% - [anc] 1h 35m {bogus"
+      );
+      assert_eq!(render("{battery} {unknown}", &device), "15% {unknown}");
+   }
+
+   #[test]
+   fn test_waybar_line() {
+      let options = BarOptions::default();
+      let line: Value = serde_json::from_str(&status_line(&options, Some(&device()))).unwrap();
+      assert_eq!(line["text"], "AirPods Pro 15%");
+      assert_eq!(line["class"], "low");
+      assert_eq!(line["percentage"], 15);
+
+      let line: Value = serde_json::from_str(&status_line(&options, None)).unwrap();
+      assert_eq!(line["class"], "disconnected");
+   }
+}
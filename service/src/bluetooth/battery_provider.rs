@@ -0,0 +1,277 @@
+//! `BlueZ` battery provider for `AirPods`.
+//!
+//! `BlueZ` only knows the battery level reported over HFP, which `AirPods`
+//! do not provide. This module registers the service as an
+//! `org.bluez.BatteryProvider1` on every adapter a device is connected through,
+//! so that `org.bluez.Battery1` (and with it UPower and the desktop battery
+//! indicators) reflects the levels read over AAP. The provider is registered on
+//! every adapter the manager picks up, and again for a device whose adapter
+//! dropped the registration.
+//!
+//! `BlueZ` lives on the system bus, hence the provider uses its own connection.
+
+use std::collections::HashSet;
+
+use bluer::Address;
+use log::{debug, info};
+use smol_str::SmolStr;
+use tokio::sync::Mutex;
+use zbus::{
+   Connection,
+   fdo::ObjectManager,
+   interface, proxy,
+   zvariant::{ObjectPath, OwnedObjectPath},
+};
+
+use crate::{
+   airpods::{
+      device::AirPods,
+      protocol::{BatteryInfo, Component},
+   },
+   config::BatteryPolicy,
+   event::AirPodsEvent,
+};
+
+/// Root of the provider objects, registered with `BatteryProviderManager1`.
+const PROVIDER_ROOT: &str = "/org/kairpods/battery";
+
+/// Value of the `Source` property of the provided batteries.
+const PROVIDER_SOURCE: &str = "kairpodsd";
+
+#[proxy(
+   interface = "org.bluez.BatteryProviderManager1",
+   default_service = "org.bluez"
+)]
+trait BatteryProviderManager {
+   fn register_battery_provider(&self, provider: &ObjectPath<'_>) -> zbus::Result<()>;
+}
+
+/// A single battery exported to `BlueZ`.
+struct ProvidedBattery {
+   device: OwnedObjectPath,
+   percentage: u8,
+}
+
+#[interface(name = "org.bluez.BatteryProvider1")]
+impl ProvidedBattery {
+   #[zbus(property)]
+   fn device(&self) -> OwnedObjectPath {
+      self.device.clone()
+   }
+
+   #[zbus(property)]
+   fn percentage(&self) -> u8 {
+      self.percentage
+   }
+
+   #[zbus(property)]
+   fn source(&self) -> &str {
+      PROVIDER_SOURCE
+   }
+}
+
+/// Publishes `AirPods` battery levels to `BlueZ`.
+pub struct BatteryProvider {
+   connection: Connection,
+   policy: BatteryPolicy,
+   registered: Mutex<HashSet<SmolStr>>,
+}
+
+impl BatteryProvider {
+   /// Connects to the system bus and serves the provider root.
+   pub async fn new(policy: BatteryPolicy) -> zbus::Result<Self> {
+      let connection = zbus::connection::Builder::system()?
+         .serve_at(PROVIDER_ROOT, ObjectManager)?
+         .build()
+         .await?;
+      Ok(Self {
+         connection,
+         policy,
+         registered: Mutex::new(HashSet::new()),
+      })
+   }
+
+   /// Updates the provided battery an event refers to.
+   pub async fn dispatch(&self, device: &AirPods, event: &AirPodsEvent) -> zbus::Result<()> {
+      match event {
+         AirPodsEvent::DeviceAdded => self.register(device.adapter()).await,
+         AirPodsEvent::DeviceConnected => match device.battery_info() {
+            Some(battery) => self.update(device, &battery).await,
+            None => Ok(()),
+         },
+         AirPodsEvent::BatteryUpdated(battery) => self.update(device, battery).await,
+         AirPodsEvent::DeviceDisconnected | AirPodsEvent::DeviceRemoved => {
+            self.remove(device).await
+         },
+         AirPodsEvent::DeviceError => {
+            // BlueZ drops the registration along with a lost adapter
+            self.registered.lock().await.remove(device.adapter());
+            Ok(())
+         },
+         _ => Ok(()),
+      }
+   }
+
+   /// Registers the provider with an adapter the manager picked up.
+   ///
+   /// An adapter coming back has lost the previous registration along with its objects.
+   pub async fn add_adapter(&self, adapter: &SmolStr) -> zbus::Result<()> {
+      self.registered.lock().await.remove(adapter);
+      self.register(adapter).await
+   }
+
+   /// Registers the provider with an adapter, once per adapter.
+   async fn register(&self, adapter: &SmolStr) -> zbus::Result<()> {
+      let mut registered = self.registered.lock().await;
+      if registered.contains(adapter) {
+         return Ok(());
+      }
+
+      let manager = BatteryProviderManagerProxy::builder(&self.connection)
+         .path(format!("/org/bluez/{adapter}"))?
+         .build()
+         .await?;
+      match manager
+         .register_battery_provider(&ObjectPath::from_static_str_unchecked(PROVIDER_ROOT))
+         .await
+      {
+         Ok(()) => info!("Registered battery provider on {adapter}"),
+         Err(zbus::Error::MethodError(name, _, _)) if name == "org.bluez.Error.AlreadyExists" => {},
+         Err(e) => return Err(e),
+      }
+      registered.insert(adapter.clone());
+      Ok(())
+   }
+
+   async fn update(&self, device: &AirPods, battery: &BatteryInfo) -> zbus::Result<()> {
+      let Some(percentage) = percentage(self.policy, battery) else {
+         return self.remove(device).await;
+      };
+
+      let server = self.connection.object_server();
+      let path = provider_path(device.address());
+      if let Ok(iface) = server.interface::<_, ProvidedBattery>(&path).await {
+         let mut provided = iface.get_mut().await;
+         if provided.percentage != percentage {
+            provided.percentage = percentage;
+            provided.percentage_changed(iface.signal_emitter()).await?;
+         }
+         return Ok(());
+      }
+
+      self.register(device.adapter()).await?;
+      debug!("Providing battery for {}: {percentage}%", device.address());
+      server
+         .at(
+            &path,
+            ProvidedBattery {
+               device: bluez_device_path(device.adapter(), device.address()),
+               percentage,
+            },
+         )
+         .await?;
+      Ok(())
+   }
+
+   async fn remove(&self, device: &AirPods) -> zbus::Result<()> {
+      let path = provider_path(device.address());
+      match self
+         .connection
+         .object_server()
+         .remove::<ProvidedBattery, _>(&path)
+         .await
+      {
+         Ok(_) | Err(zbus::Error::InterfaceNotFound) => Ok(()),
+         Err(e) => Err(e),
+      }
+   }
+}
+
+/// Combines the bud levels into a single percentage according to `policy`.
+///
+/// Returns `None` if no bud (or headphone) level is available.
+pub fn percentage(policy: BatteryPolicy, battery: &BatteryInfo) -> Option<u8> {
+   if battery.headphone.is_available() {
+      return Some(battery.headphone.level);
+   }
+
+   let buds = [battery.left, battery.right];
+   let levels = buds.iter().filter(|b| b.is_available()).map(|b| b.level);
+   match policy {
+      BatteryPolicy::Min => levels.min(),
+      BatteryPolicy::Max => levels.max(),
+      BatteryPolicy::Average => {
+         let (sum, count) = levels.fold((0u32, 0u32), |(s, c), l| (s + u32::from(l), c + 1));
+         (count > 0).then(|| (sum / count) as u8)
+      },
+      BatteryPolicy::Primary => {
+         let primary = battery.primary_pod.map(|c| match c {
+            Component::Left => battery.left,
+            Component::Right => battery.right,
+            _ => battery.headphone,
+         });
+         match primary {
+            Some(state) if state.is_available() => Some(state.level),
+            _ => levels.min(),
+         }
+      },
+   }
+}
+
+fn provider_path(address: Address) -> OwnedObjectPath {
+   object_path(format!(
+      "{PROVIDER_ROOT}/dev_{}",
+      address.to_string().replace(':', "_")
+   ))
+}
+
+fn bluez_device_path(adapter: &str, address: Address) -> OwnedObjectPath {
+   object_path(format!(
+      "/org/bluez/{adapter}/dev_{}",
+      address.to_string().replace(':', "_")
+   ))
+}
+
+fn object_path(path: String) -> OwnedObjectPath {
+   ObjectPath::try_from(path)
+      .expect("address is a valid path element")
+      .into()
+}
+
+#[cfg(test)]
+mod tests {
+   use super::*;
+   use crate::airpods::protocol::{BatteryState, BatteryStatus};
+
+   fn battery(left: Option<u8>, right: Option<u8>) -> BatteryInfo {
+      let state = |level: Option<u8>| match level {
+         Some(level) => BatteryState {
+            level,
+            status: BatteryStatus::Discharging,
+         },
+         None => BatteryState::new(),
+      };
+      BatteryInfo {
+         left: state(left),
+         right: state(right),
+         primary_pod: Some(Component::Right),
+         ..BatteryInfo::new()
+      }
+   }
+
+   #[test]
+   fn test_percentage_policies() {
+      let both = battery(Some(40), Some(81));
+      assert_eq!(percentage(BatteryPolicy::Min, &both), Some(40));
+      assert_eq!(percentage(BatteryPolicy::Max, &both), Some(81));
+      assert_eq!(percentage(BatteryPolicy::Average, &both), Some(60));
+      assert_eq!(percentage(BatteryPolicy::Primary, &both), Some(81));
+
+      // Primary bud in the case falls back to the remaining bud
+      let left_only = battery(Some(40), None);
+      assert_eq!(percentage(BatteryPolicy::Primary, &left_only), Some(40));
+      assert_eq!(percentage(BatteryPolicy::Average, &left_only), Some(40));
+
+      assert_eq!(percentage(BatteryPolicy::Min, &battery(None, None)), None);
+   }
+}
@@ -5,10 +5,14 @@
 
 use std::{
    collections::{HashMap, HashSet},
-   time::Duration,
+   sync::Arc,
+   time::{Duration, SystemTime, UNIX_EPOCH},
 };
 
-use bluer::{Adapter, AdapterEvent, Address, Session};
+use bluer::{
+   Adapter, AdapterEvent, Address, DeviceEvent, DeviceProperty, Session,
+   monitor::{Monitor, MonitorEvent},
+};
 use futures::stream::StreamExt;
 use log::{debug, error, info, warn};
 use smol_str::SmolStr;
@@ -20,16 +24,25 @@ use tokio::{
 };
 
 use crate::{
-   airpods::{self, device::AirPods},
-   battery_study::BatteryStudy,
-   config::Config,
+   airpods::{
+      self,
+      device::{AAPState, AirPods, ConnectionInfo},
+      protocol::DeviceModel,
+   },
+   battery_study::{BatteryStudy, DeviceState},
+   bluetooth::{
+      battery_provider::BatteryProvider,
+      pairing::{self, Candidate, PairingSession},
+   },
+   config::{Config, ConnectTrigger, DeviceProfile, KnownDevice},
    error::{AirPodsError, Result},
    event::{AirPodsEvent, EventSender},
 };
 use rand::Rng;
 
-/// Interval to poll for new devices and check connection health
-const HEALTH_CHECK_INTERVAL: Duration = Duration::from_secs(5);
+/// Interval to poll for new devices and check connection health, as a safety net
+/// for missed property change events
+const HEALTH_CHECK_INTERVAL: Duration = Duration::from_secs(60);
 /// Interval to check for new adapters
 const ADAPTER_CHECK_INTERVAL: Duration = Duration::from_secs(10);
 /// Delay before retrying adapter operations after failure
@@ -38,6 +51,8 @@ const ADAPTER_RECOVERY_DELAY: Duration = Duration::from_secs(5);
 const AAP_CONNECTION_TIMEOUT: Duration = Duration::from_secs(30);
 /// Maximum AAP connection retry delay
 const MAX_AAP_RETRY_DELAY: Duration = Duration::from_secs(120);
+/// Maximum delay between Bluetooth connection attempts
+const MAX_CONNECT_RETRY_DELAY: Duration = Duration::from_secs(300);
 /// Device tick interval
 const DEVICE_TICK_INTERVAL: Duration = Duration::from_secs(10);
 /// Channel buffer size
@@ -56,25 +71,45 @@ struct AdapterInfo {
    adapter: Adapter,
    state: AdapterState,
    monitor_handle: Option<JoinHandle<()>>,
+   advertisements: AdvertisementWatch,
    retry_count: u32,
    name: SmolStr,
 }
 
+/// Passive scan for the advertisements of buds whose case lid opened.
+#[derive(Debug, Default)]
+enum AdvertisementWatch {
+   #[default]
+   Off,
+   On(JoinHandle<()>),
+   /// `BlueZ` has no advertisement monitor for this adapter.
+   Unavailable,
+}
+
+impl AdvertisementWatch {
+   fn stop(&mut self) {
+      if let Self::On(handle) = self {
+         handle.abort();
+         *self = Self::Off;
+      }
+   }
+}
+
 // === Device Management ===
 
-#[derive(Debug, Copy, Clone, PartialEq)]
-enum BluetoothState {
-   Connected,
-   Disconnected,
+/// Property change subscription of a candidate device.
+struct DeviceWatch {
+   adapter_name: SmolStr,
+   handle: JoinHandle<()>,
+   /// The device was found not to be `AirPods`; it is only watched for pairing,
+   /// after which it is recognized again, and not looked at otherwise.
+   rejected: bool,
 }
 
 #[derive(Debug, Copy, Clone, PartialEq)]
-enum AAPState {
-   Disconnected,
-   Connecting,
+enum BluetoothState {
    Connected,
-   Failed(&'static str),
-   WaitingToReconnect,
+   Disconnected,
 }
 
 struct ManagedDevice {
@@ -84,9 +119,30 @@ struct ManagedDevice {
    adapter_name: SmolStr,
    aap_retry_count: u32,
    last_aap_error: Option<String>,
+   next_aap_retry: Option<SystemTime>,
    aap_handle: Option<JoinHandle<()>>,
 }
 
+impl ManagedDevice {
+   /// Publishes the connection state to the device if it changed.
+   fn publish_state(&self, event_tx: &EventSender) {
+      let info = ConnectionInfo {
+         state: self.aap_state,
+         bluetooth_connected: self.bluetooth_state == BluetoothState::Connected,
+         adapter: self.adapter_name.clone(),
+         retry_count: self.aap_retry_count,
+         last_error: self.last_aap_error.clone(),
+         next_retry: self
+            .next_aap_retry
+            .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+            .map(|d| d.as_secs()),
+      };
+      if self.device.update_connection_info(info.clone()) {
+         event_tx.emit(&self.device, AirPodsEvent::ConnectionStateChanged(info));
+      }
+   }
+}
+
 // === Commands ===
 
 #[derive(Debug)]
@@ -98,18 +154,33 @@ enum ManagerCommand {
 
    // Device events
    DeviceDiscovered(Address, SmolStr), // address, adapter_name
+   DevicePaired(Address, SmolStr),     // address, adapter_name
    BluetoothConnected(Address),
    BluetoothDisconnected(Address),
    AAPConnected(Address),
-   AAPDisconnected(Address, bool), // address, is_error
+   AAPDisconnected(Address, Option<String>), // address, error
    DeviceLost(Address),
+   Advertising(Address),
+   BluetoothConnectFinished(Address, Option<String>), // address, error
+   PairingFinished(Address, Result<String>, oneshot::Sender<Result<()>>), // address, name
 
    // User commands
+   Connect(Address, oneshot::Sender<Result<()>>),
    EstablishAAP(Address, Option<oneshot::Sender<Result<()>>>),
    DisconnectAAP(Address, Option<oneshot::Sender<Result<()>>>),
    GetDeviceState(Address, oneshot::Sender<Option<AirPods>>),
    GetAllDeviceStates(oneshot::Sender<Vec<AirPods>>),
    CountDevices(oneshot::Sender<u32>),
+   StartPairing(oneshot::Sender<Result<()>>),
+   StopPairing(oneshot::Sender<bool>),
+   PairingCandidates(oneshot::Sender<Option<(bool, Vec<Candidate>)>>),
+   Pair(Address, oneshot::Sender<Result<()>>),
+   KnownDevices(oneshot::Sender<Vec<KnownDevice>>),
+   DeviceStates(oneshot::Sender<Vec<(Address, DeviceState)>>),
+   AddKnownDevice(KnownDevice, oneshot::Sender<Result<()>>),
+   RenameKnownDevice(Address, String, oneshot::Sender<Result<()>>),
+   RemoveKnownDevice(Address, bool, oneshot::Sender<Result<()>>), // address, unpair
+   ReloadConfig(Box<Config>, oneshot::Sender<bool>),
 }
 
 // === Main Manager ===
@@ -118,6 +189,7 @@ enum ManagerCommand {
 ///
 /// This type provides a high-level interface for managing `AirPods` devices
 /// across all available Bluetooth adapters.
+#[derive(Clone)]
 pub struct BluetoothManager {
    inbox: mpsc::Sender<ManagerCommand>,
 }
@@ -127,21 +199,30 @@ impl BluetoothManager {
       event_tx: EventSender,
       config: Config,
       battery_study: Option<BatteryStudy>,
+      battery_provider: Option<Arc<BatteryProvider>>,
    ) -> Result<Self> {
       let (command_tx, command_rx) = mpsc::channel(CHANNEL_BUFFER_SIZE);
       tokio::spawn(
-         ManagerActor::new(config, event_tx, command_rx, battery_study)
-            .await
-            .run(),
+         ManagerActor::new(
+            config,
+            event_tx,
+            command_rx,
+            battery_study,
+            battery_provider,
+         )
+         .await
+         .run(),
       );
       Ok(Self { inbox: command_tx })
    }
 
-   pub async fn establish_aap(&self, address: Address) -> Result<()> {
+   /// Connects to a device, initiating the Bluetooth connection if its
+   /// configuration allows it.
+   pub async fn connect(&self, address: Address) -> Result<()> {
       let (tx, rx) = oneshot::channel();
       self
          .inbox
-         .send(ManagerCommand::EstablishAAP(address, Some(tx)))
+         .send(ManagerCommand::Connect(address, tx))
          .await
          .map_err(|_| AirPodsError::ManagerShutdown)?;
       rx.await.map_err(|_| AirPodsError::ManagerShutdown)?
@@ -157,6 +238,126 @@ impl BluetoothManager {
       rx.await.map_err(|_| AirPodsError::ManagerShutdown)?
    }
 
+   /// Starts discovering `AirPods` to pair.
+   pub async fn start_pairing(&self) -> Result<()> {
+      let (tx, rx) = oneshot::channel();
+      self
+         .inbox
+         .send(ManagerCommand::StartPairing(tx))
+         .await
+         .map_err(|_| AirPodsError::ManagerShutdown)?;
+      rx.await.map_err(|_| AirPodsError::ManagerShutdown)?
+   }
+
+   /// Stops the pairing discovery, returning whether one was running.
+   pub async fn stop_pairing(&self) -> bool {
+      let (tx, rx) = oneshot::channel();
+      if self
+         .inbox
+         .send(ManagerCommand::StopPairing(tx))
+         .await
+         .is_err()
+      {
+         return false;
+      }
+      rx.await.unwrap_or_default()
+   }
+
+   /// Lists the devices found by the pairing discovery, along with whether it
+   /// is still running, or `None` if it was not started.
+   pub async fn pairing_candidates(&self) -> Option<(bool, Vec<Candidate>)> {
+      let (tx, rx) = oneshot::channel();
+      self
+         .inbox
+         .send(ManagerCommand::PairingCandidates(tx))
+         .await
+         .ok()?;
+      rx.await.ok().flatten()
+   }
+
+   /// Pairs a device found by the pairing discovery and adds it to the known devices.
+   pub async fn pair(&self, address: Address) -> Result<()> {
+      let (tx, rx) = oneshot::channel();
+      self
+         .inbox
+         .send(ManagerCommand::Pair(address, tx))
+         .await
+         .map_err(|_| AirPodsError::ManagerShutdown)?;
+      rx.await.map_err(|_| AirPodsError::ManagerShutdown)?
+   }
+
+   /// Replaces the configuration, returning whether it differs from the current one.
+   pub async fn reload_config(&self, config: Config) -> Result<bool> {
+      let (tx, rx) = oneshot::channel();
+      self
+         .inbox
+         .send(ManagerCommand::ReloadConfig(Box::new(config), tx))
+         .await
+         .map_err(|_| AirPodsError::ManagerShutdown)?;
+      rx.await.map_err(|_| AirPodsError::ManagerShutdown)
+   }
+
+   /// Lists the devices of the configuration.
+   pub async fn known_devices(&self) -> Vec<KnownDevice> {
+      let (tx, rx) = oneshot::channel();
+      if self
+         .inbox
+         .send(ManagerCommand::KnownDevices(tx))
+         .await
+         .is_err()
+      {
+         return Vec::new();
+      }
+      rx.await.unwrap_or_default()
+   }
+
+   /// Lists the last known state of every device seen so far.
+   pub async fn device_states(&self) -> Vec<(Address, DeviceState)> {
+      let (tx, rx) = oneshot::channel();
+      if self
+         .inbox
+         .send(ManagerCommand::DeviceStates(tx))
+         .await
+         .is_err()
+      {
+         return Vec::new();
+      }
+      rx.await.unwrap_or_default()
+   }
+
+   /// Adds a device to the configuration.
+   pub async fn add_known_device(&self, device: KnownDevice) -> Result<()> {
+      let (tx, rx) = oneshot::channel();
+      self
+         .inbox
+         .send(ManagerCommand::AddKnownDevice(device, tx))
+         .await
+         .map_err(|_| AirPodsError::ManagerShutdown)?;
+      rx.await.map_err(|_| AirPodsError::ManagerShutdown)?
+   }
+
+   /// Renames a device of the configuration.
+   pub async fn rename_known_device(&self, address: Address, name: String) -> Result<()> {
+      let (tx, rx) = oneshot::channel();
+      self
+         .inbox
+         .send(ManagerCommand::RenameKnownDevice(address, name, tx))
+         .await
+         .map_err(|_| AirPodsError::ManagerShutdown)?;
+      rx.await.map_err(|_| AirPodsError::ManagerShutdown)?
+   }
+
+   /// Removes a device from the configuration, and from `BlueZ` if `unpair` is set.
+   pub async fn remove_known_device(&self, address: Address, unpair: bool) -> Result<()> {
+      let (tx, rx) = oneshot::channel();
+      self
+         .inbox
+         .send(ManagerCommand::RemoveKnownDevice(address, unpair, tx))
+         .await
+         .map_err(|_| AirPodsError::ManagerShutdown)?;
+      rx.await.map_err(|_| AirPodsError::ManagerShutdown)?
+   }
+
    pub async fn get_device(&self, address: Address) -> Result<AirPods> {
       let (tx, rx) = oneshot::channel();
       self
@@ -208,11 +409,16 @@ struct ManagerActor {
    loopback_tx: mpsc::Sender<ManagerCommand>,
    session: Session,
    battery_study: Option<BatteryStudy>,
+   battery_provider: Option<Arc<BatteryProvider>>,
 
    // State
    adapters: HashMap<SmolStr, AdapterInfo>,
    devices: HashMap<Address, ManagedDevice>,
+   watches: HashMap<Address, DeviceWatch>,
    aap_connecting: HashSet<Address>, // Prevent duplicate AAP connections
+   bluetooth_connecting: HashSet<Address>, // Prevent duplicate Bluetooth connections
+   pairing: Option<PairingSession>,
+   pairing_with: Option<Address>,
 }
 
 impl ManagerActor {
@@ -221,6 +427,7 @@ impl ManagerActor {
       event_tx: EventSender,
       command_rx: mpsc::Receiver<ManagerCommand>,
       battery_study: Option<BatteryStudy>,
+      battery_provider: Option<Arc<BatteryProvider>>,
    ) -> Self {
       let session = Session::new()
          .await
@@ -235,9 +442,14 @@ impl ManagerActor {
          loopback_tx,
          session,
          battery_study,
+         battery_provider,
          adapters: HashMap::new(),
          devices: HashMap::new(),
+         watches: HashMap::new(),
          aap_connecting: HashSet::new(),
+         bluetooth_connecting: HashSet::new(),
+         pairing: None,
+         pairing_with: None,
       }
    }
 
@@ -246,6 +458,7 @@ impl ManagerActor {
 
       // Initialize adapters
       self.initialize_adapters().await;
+      self.connect_on_startup().await;
 
       // Start periodic checks
       let mut health_check_interval = time::interval(HEALTH_CHECK_INTERVAL);
@@ -288,6 +501,7 @@ impl ManagerActor {
                  }
              }
          }
+         self.update_advertisement_watches().await;
       }
 
       // Cleanup
@@ -348,14 +562,25 @@ impl ManagerActor {
                      name.clone(),
                      adapter.clone(),
                   )),
+                  advertisements: AdvertisementWatch::Off,
                   adapter,
                   retry_count: 0,
                   name: name.clone(),
                },
             );
 
-            // Check for already connected devices
-            self.check_connected_devices(&name).await;
+            // Publish the battery levels of the devices on it to BlueZ
+            if let Some(provider) = self.battery_provider.clone() {
+               let name = name.clone();
+               tokio::spawn(async move {
+                  if let Err(e) = provider.add_adapter(&name).await {
+                     warn!("Failed to register the battery provider on {name}: {e}");
+                  }
+               });
+            }
+
+            // Check for already known devices
+            self.check_known_devices(&name).await;
          },
          Err(e) => {
             warn!("Failed to initialize adapter {name}: {e}");
@@ -394,8 +619,7 @@ impl ManagerActor {
                   debug!("Device removed on {name}: {addr}");
                   let _ = loopback.send(ManagerCommand::DeviceLost(addr)).await;
                },
-               // Note: bluer doesn't provide DeviceConnected/Disconnected events
-               // We'll detect connection changes through periodic scanning
+               // Connection changes are watched per device, see `watch_device`
                _ => {},
             }
          }
@@ -407,7 +631,78 @@ impl ManagerActor {
       })
    }
 
-   async fn check_connected_devices(&self, adapter_name: &SmolStr) {
+   /// Watches for proximity pairing advertisements while a known device that
+   /// connects on `lid_open` is disconnected, so opening the case is noticed
+   /// without another client scanning.
+   ///
+   /// The advertisement monitor is passive and does not disturb connections.
+   async fn update_advertisement_watches(&mut self) {
+      let wanted = self.config.known_devices.iter().any(|known| {
+         known.auto_connect.contains(&ConnectTrigger::LidOpen)
+            && known
+               .address
+               .parse()
+               .is_ok_and(|addr| !self.is_bluetooth_connected(addr))
+      });
+      for info in self.adapters.values_mut() {
+         match (
+            &info.advertisements,
+            wanted && info.state == AdapterState::Active,
+         ) {
+            (AdvertisementWatch::Off, true) => {
+               info.advertisements =
+                  match Self::watch_advertisements(self.loopback_tx.clone(), &info.adapter).await {
+                     Ok(handle) => {
+                        debug!("Watching advertisements on {}", info.name);
+                        AdvertisementWatch::On(handle)
+                     },
+                     Err(e) => {
+                        warn!(
+                           "Cannot watch advertisements on {}, lid_open only works while \
+                            another client scans: {e}",
+                           info.name
+                        );
+                        AdvertisementWatch::Unavailable
+                     },
+                  };
+            },
+            (AdvertisementWatch::On(_), false) => {
+               debug!("Stopped watching advertisements on {}", info.name);
+               info.advertisements.stop();
+            },
+            _ => {},
+         }
+      }
+   }
+
+   async fn watch_advertisements(
+      loopback: mpsc::Sender<ManagerCommand>,
+      adapter: &Adapter,
+   ) -> bluer::Result<JoinHandle<()>> {
+      let manager = adapter.monitor().await?;
+      let mut monitor = manager
+         .register(Monitor {
+            patterns: Some(vec![airpods::recognition::proximity_pairing_pattern()]),
+            ..Default::default()
+         })
+         .await?;
+      Ok(tokio::spawn(async move {
+         // Unregistered along with the manager once the task is aborted
+         let _manager = manager;
+         while let Some(event) = monitor.next().await {
+            if let MonitorEvent::DeviceFound(id) = event
+               && loopback
+                  .send(ManagerCommand::Advertising(id.device))
+                  .await
+                  .is_err()
+            {
+               break;
+            }
+         }
+      }))
+   }
+
+   async fn check_known_devices(&self, adapter_name: &SmolStr) {
       let Some(adapter_info) = self.adapters.get(adapter_name) else {
          return;
       };
@@ -416,13 +711,11 @@ impl ManagerActor {
          return;
       };
 
+      // Devices already present don't show up as added in the adapter events,
+      // discover them to start watching their connection state. Devices already
+      // rejected are watched too, so they are skipped here.
       for addr in addresses {
-         if let Ok(device) = adapter_info.adapter.device(addr)
-            && device.is_connected().await == Ok(true)
-            && self.is_airpods_device(&device).await
-            && !self.devices.contains_key(&addr)
-         {
-            // Found a connected AirPods device without AAP connection
+         if !self.watches.contains_key(&addr) {
             let _ = self
                .loopback_tx
                .send(ManagerCommand::DeviceDiscovered(addr, adapter_name.clone()))
@@ -431,6 +724,324 @@ impl ManagerActor {
       }
    }
 
+   /// Subscribes to the property changes of a candidate device, so
+   /// connections are picked up as soon as bluetoothd reports them.
+   ///
+   /// A `rejected` device is only watched for being paired.
+   fn watch_device(&mut self, device: bluer::Device, adapter_name: &SmolStr, rejected: bool) {
+      let addr = device.address();
+      if self.watches.contains_key(&addr) {
+         return;
+      }
+
+      let loopback = self.loopback_tx.clone();
+      let name = adapter_name.clone();
+      let handle = tokio::spawn(async move {
+         let mut events = match device.events().await {
+            Ok(events) => events,
+            Err(e) => {
+               warn!("Failed to watch {addr}, relying on polling: {e}");
+               return;
+            },
+         };
+
+         while let Some(DeviceEvent::PropertyChanged(property)) = events.next().await {
+            let cmd = match property {
+               // Recognition can depend on the pairing, look at the device again
+               DeviceProperty::Paired(true) => ManagerCommand::DevicePaired(addr, name.clone()),
+               _ if rejected => continue,
+               DeviceProperty::Connected(true) => ManagerCommand::BluetoothConnected(addr),
+               DeviceProperty::Connected(false) => ManagerCommand::BluetoothDisconnected(addr),
+               DeviceProperty::ManufacturerData(data)
+                  if airpods::recognition::is_proximity_pairing(&data) =>
+               {
+                  ManagerCommand::Advertising(addr)
+               },
+               _ => continue,
+            };
+            debug!("Device {addr} changed: {cmd:?}");
+            if loopback.send(cmd).await.is_err() {
+               break;
+            }
+         }
+      });
+      self.watches.insert(
+         addr,
+         DeviceWatch {
+            adapter_name: adapter_name.clone(),
+            handle,
+            rejected,
+         },
+      );
+   }
+
+   async fn start_pairing(&mut self) -> Result<()> {
+      // Drop the previous session first, an adapter only runs one discovery per client
+      self.pairing = None;
+      let adapter = self
+         .adapters
+         .values()
+         .filter(|info| info.state == AdapterState::Active)
+         .min_by_key(|info| &info.name)
+         .ok_or(AirPodsError::AdapterNotAvailable)?
+         .adapter
+         .clone();
+      self.pairing = Some(PairingSession::start(adapter).await?);
+      Ok(())
+   }
+
+   fn pair(&mut self, addr: Address, reply: oneshot::Sender<Result<()>>) {
+      if self.pairing_with.is_some() {
+         let _ = reply.send(Err(AirPodsError::PreconditionFailed(
+            "Already pairing a device",
+         )));
+         return;
+      }
+      let Some(session) = self.pairing.as_ref().filter(|s| s.contains(addr)) else {
+         let _ = reply.send(Err(AirPodsError::DeviceNotFound(addr)));
+         return;
+      };
+      let device = match session.adapter().device(addr) {
+         Ok(device) => device,
+         Err(e) => {
+            let _ = reply.send(Err(e.into()));
+            return;
+         },
+      };
+      self.pairing_with = Some(addr);
+      // Inquiry interferes with paging, stop the discovery before pairing and connecting
+      self.pairing = None;
+
+      let session = self.session.clone();
+      let loopback = self.loopback_tx.clone();
+      tokio::spawn(async move {
+         let result = pairing::pair(&session, &device).await;
+         let _ = loopback
+            .send(ManagerCommand::PairingFinished(addr, result, reply))
+            .await;
+      });
+   }
+
+   /// Changes the known devices and saves them to the configuration file,
+   /// leaving both untouched if either fails.
+   fn update_known_devices(
+      &mut self,
+      f: impl FnOnce(&mut Vec<KnownDevice>) -> Result<()>,
+   ) -> Result<()> {
+      let mut known = self.config.known_devices.clone();
+      f(&mut known)?;
+      if let Err(e) = self.config.save_known_devices(&known) {
+         warn!("Failed to save the known devices: {e}");
+         return Err(e);
+      }
+      self.config.known_devices = known;
+      for (addr, device) in &self.devices {
+         device
+            .device
+            .set_profile(self.config.profile(&addr.to_string()));
+      }
+      self.recheck_rejected_known_devices();
+      Ok(())
+   }
+
+   fn reload_config(&mut self, config: Config) -> bool {
+      if config == self.config {
+         return false;
+      }
+      self.config = config;
+      for (addr, device) in &self.devices {
+         device
+            .device
+            .set_profile(self.config.profile(&addr.to_string()));
+      }
+      self.recheck_rejected_known_devices();
+      true
+   }
+
+   fn add_known_device(&mut self, device: KnownDevice) -> Result<()> {
+      if self.config.is_known_device(&device.address).is_some() {
+         return Err(AirPodsError::PreconditionFailed("Device already known"));
+      }
+      info!("Adding known device {} ({})", device.name, device.address);
+      self.update_known_devices(|known| {
+         known.push(device);
+         Ok(())
+      })
+   }
+
+   async fn remove_known_device(&mut self, addr: Address, unpair: bool) -> Result<()> {
+      let address = addr.to_string();
+      let is_known = self.config.is_known_device(&address).is_some();
+      let bluez_device = if unpair {
+         self.find_device(addr).await
+      } else {
+         None
+      };
+      let had_state = match &self.battery_study {
+         Some(study) => study.get_device_state(addr)?.is_some(),
+         None => false,
+      };
+      if !is_known && !had_state && bluez_device.is_none() {
+         return Err(AirPodsError::DeviceNotFound(addr));
+      }
+
+      if let Some((adapter_name, _)) = bluez_device
+         && let Some(info) = self.adapters.get(&adapter_name)
+      {
+         info!("Unpairing {addr} from {adapter_name}");
+         info.adapter.remove_device(addr).await?;
+      }
+      if is_known {
+         info!("Removing known device {addr}");
+         self.update_known_devices(|known| {
+            known.retain(|d| !d.address.eq_ignore_ascii_case(&address));
+            Ok(())
+         })?;
+      }
+      // Forget the saved state last, so a failed unpair or save leaves the device listed
+      if had_state && let Some(study) = &self.battery_study {
+         study.remove_device_state(addr)?;
+      }
+      Ok(())
+   }
+
+   /// Connects to the known devices configured to be connected at startup.
+   async fn connect_on_startup(&mut self) {
+      let addresses: Vec<Address> = self
+         .config
+         .known_devices
+         .iter()
+         .filter(|d| d.auto_connect.contains(&ConnectTrigger::Startup))
+         .filter_map(|d| {
+            d.address
+               .parse()
+               .inspect_err(|e| warn!("Invalid known device address {:?}: {e}", d.address))
+               .ok()
+         })
+         .collect();
+      for addr in addresses {
+         self.connect_bluetooth(addr, None).await;
+      }
+   }
+
+   fn is_bluetooth_connected(&self, addr: Address) -> bool {
+      self
+         .devices
+         .get(&addr)
+         .is_some_and(|d| d.bluetooth_state == BluetoothState::Connected)
+   }
+
+   /// Finds the adapter a device is known to, preferring the one it was last seen on.
+   async fn find_device(&self, addr: Address) -> Option<(SmolStr, bluer::Device)> {
+      let seen_on = self
+         .devices
+         .get(&addr)
+         .map(|d| &d.adapter_name)
+         .or_else(|| self.watches.get(&addr).map(|w| &w.adapter_name));
+      if let Some(name) = seen_on
+         && let Some(info) = self.adapters.get(name)
+         && info.state == AdapterState::Active
+         && let Ok(device) = info.adapter.device(addr)
+      {
+         return Some((name.clone(), device));
+      }
+
+      for (name, info) in &self.adapters {
+         if info.state == AdapterState::Active
+            && let Ok(addresses) = info.adapter.device_addresses().await
+            && addresses.contains(&addr)
+            && let Ok(device) = info.adapter.device(addr)
+         {
+            return Some((name.clone(), device));
+         }
+      }
+      None
+   }
+
+   /// Initiates the Bluetooth connection to a device, retrying with backoff.
+   ///
+   /// `reply` receives the outcome of the first attempt, later attempts continue
+   /// in the background.
+   async fn connect_bluetooth(
+      &mut self,
+      addr: Address,
+      reply: Option<oneshot::Sender<Result<()>>>,
+   ) {
+      if !self.bluetooth_connecting.insert(addr) {
+         if let Some(reply) = reply {
+            let _ = reply.send(Err(AirPodsError::AlreadyConnecting));
+         }
+         return;
+      }
+      let Some((adapter_name, device)) = self.find_device(addr).await else {
+         warn!("Cannot connect to {addr}: not known to any adapter");
+         self.bluetooth_connecting.remove(&addr);
+         if let Some(reply) = reply {
+            let _ = reply.send(Err(AirPodsError::DeviceNotFound(addr)));
+         }
+         return;
+      };
+      info!("Connecting to {addr} on {adapter_name}");
+
+      let retries = self.config.connection_retry_count;
+      let base_delay = Duration::from_secs(self.config.reconnect_delay_sec);
+      let loopback = self.loopback_tx.clone();
+      tokio::spawn(async move {
+         let mut reply = reply;
+         let mut attempt = 0;
+         let error = loop {
+            let err = match device.connect().await {
+               Ok(()) => break None,
+               Err(e) => e,
+            };
+            if let Some(reply) = reply.take() {
+               let _ = reply.send(Err(err.clone().into()));
+            }
+            attempt += 1;
+            if attempt > retries {
+               break Some(err.to_string());
+            }
+            let delay = calc_connect_delay(base_delay, attempt);
+            info!("Connecting to {addr} failed: {err}, retrying in {delay:?}");
+            time::sleep(delay).await;
+         };
+         if let Some(reply) = reply.take() {
+            let _ = reply.send(Ok(()));
+         }
+         let _ = loopback
+            .send(ManagerCommand::BluetoothConnectFinished(addr, error))
+            .await;
+      });
+   }
+
+   fn unwatch_device(&mut self, addr: Address) {
+      if let Some(watch) = self.watches.remove(&addr) {
+         watch.handle.abort();
+      }
+   }
+
+   fn is_rejected(&self, addr: Address) -> bool {
+      self.watches.get(&addr).is_some_and(|watch| watch.rejected)
+   }
+
+   /// Looks again at the rejected devices that are now known devices.
+   fn recheck_rejected_known_devices(&mut self) {
+      let known: Vec<(Address, SmolStr)> = self
+         .watches
+         .iter()
+         .filter(|(addr, watch)| {
+            watch.rejected && self.config.is_known_device(&addr.to_string()).is_some()
+         })
+         .map(|(addr, watch)| (*addr, watch.adapter_name.clone()))
+         .collect();
+      for (addr, adapter_name) in known {
+         self.unwatch_device(addr);
+         let _ = self
+            .loopback_tx
+            .try_send(ManagerCommand::DeviceDiscovered(addr, adapter_name));
+      }
+   }
+
    async fn is_airpods_device(&self, device: &bluer::Device) -> bool {
       // Check known addresses
       let addr = device.address();
@@ -454,6 +1065,12 @@ impl ManagerActor {
          ManagerCommand::DeviceDiscovered(addr, adapter_name) => {
             self.handle_device_discovered(addr, adapter_name).await;
          },
+         ManagerCommand::DevicePaired(addr, adapter_name) => {
+            if self.is_rejected(addr) {
+               self.unwatch_device(addr);
+            }
+            self.handle_device_discovered(addr, adapter_name).await;
+         },
          ManagerCommand::BluetoothConnected(addr) => {
             self.handle_bluetooth_connected(addr).await;
          },
@@ -463,12 +1080,45 @@ impl ManagerActor {
          ManagerCommand::AAPConnected(addr) => {
             self.handle_aap_connected(addr);
          },
-         ManagerCommand::AAPDisconnected(addr, is_error) => {
-            self.handle_aap_disconnected(addr, is_error);
+         ManagerCommand::AAPDisconnected(addr, error) => {
+            self.handle_aap_disconnected(addr, error);
          },
          ManagerCommand::DeviceLost(addr) => {
             self.handle_device_lost(addr);
          },
+         ManagerCommand::Advertising(addr) => {
+            if self
+               .config
+               .auto_connects(&addr.to_string(), ConnectTrigger::LidOpen)
+               && !self.is_bluetooth_connected(addr)
+               && !self.bluetooth_connecting.contains(&addr)
+            {
+               info!("{addr} is advertising, connecting");
+               self.connect_bluetooth(addr, None).await;
+            }
+         },
+         ManagerCommand::BluetoothConnectFinished(addr, error) => {
+            self.bluetooth_connecting.remove(&addr);
+            match error {
+               // The device watch may have reported the connection already
+               None if !self.is_bluetooth_connected(addr) => {
+                  self.handle_bluetooth_connected(addr).await;
+               },
+               None => {},
+               Some(e) => warn!("Giving up connecting to {addr}: {e}"),
+            }
+         },
+         ManagerCommand::Connect(addr, reply) => {
+            if !self.is_bluetooth_connected(addr)
+               && self
+                  .config
+                  .auto_connects(&addr.to_string(), ConnectTrigger::Request)
+            {
+               self.connect_bluetooth(addr, Some(reply)).await;
+            } else {
+               let _ = reply.send(self.establish_aap_connection(addr).await);
+            }
+         },
          ManagerCommand::EstablishAAP(addr, reply) => {
             let result = self.establish_aap_connection(addr).await;
             if let Some(reply) = reply {
@@ -493,6 +1143,70 @@ impl ManagerActor {
             let count = self.devices.len() as u32;
             let _ = reply.send(count);
          },
+         ManagerCommand::StartPairing(reply) => {
+            let _ = reply.send(self.start_pairing().await);
+         },
+         ManagerCommand::StopPairing(reply) => {
+            let _ = reply.send(self.pairing.take().is_some());
+         },
+         ManagerCommand::PairingCandidates(reply) => {
+            let candidates = match &self.pairing {
+               Some(session) => Some((session.is_discovering(), session.candidates().await)),
+               None => None,
+            };
+            let _ = reply.send(candidates);
+         },
+         ManagerCommand::Pair(addr, reply) => {
+            self.pair(addr, reply);
+         },
+         ManagerCommand::PairingFinished(addr, result, reply) => {
+            self.pairing_with = None;
+            let result = result.and_then(|name| {
+               if self.config.is_known_device(&addr.to_string()).is_some() {
+                  return Ok(());
+               }
+               self.add_known_device(KnownDevice {
+                  address: addr.to_string(),
+                  name,
+                  auto_connect: Vec::new(),
+                  profile: DeviceProfile::default(),
+               })
+            });
+            let _ = reply.send(result);
+         },
+         ManagerCommand::ReloadConfig(config, reply) => {
+            let _ = reply.send(self.reload_config(*config));
+         },
+         ManagerCommand::KnownDevices(reply) => {
+            let _ = reply.send(self.config.known_devices.clone());
+         },
+         ManagerCommand::DeviceStates(reply) => {
+            let states = match &self.battery_study {
+               Some(study) => study.device_states().unwrap_or_else(|e| {
+                  warn!("Failed to read the device states: {e}");
+                  Vec::new()
+               }),
+               None => Vec::new(),
+            };
+            let _ = reply.send(states);
+         },
+         ManagerCommand::AddKnownDevice(device, reply) => {
+            let _ = reply.send(self.add_known_device(device));
+         },
+         ManagerCommand::RenameKnownDevice(addr, name, reply) => {
+            let result = self.update_known_devices(|known| {
+               let device = known
+                  .iter_mut()
+                  .find(|d| d.address.eq_ignore_ascii_case(&addr.to_string()))
+                  .ok_or(AirPodsError::DeviceNotFound(addr))?;
+               device.name = name;
+               Ok(())
+            });
+            let _ = reply.send(result);
+         },
+         ManagerCommand::RemoveKnownDevice(addr, unpair, reply) => {
+            let _ = reply.send(self.remove_known_device(addr, unpair).await);
+         },
       }
       true
    }
@@ -514,8 +1228,8 @@ impl ManagerActor {
             ));
          }
 
-         // Re-check connected devices and trigger reconnects
-         self.check_connected_devices(&name).await;
+         // Re-check known devices and trigger reconnects
+         self.check_known_devices(&name).await;
 
          // Try to reconnect failed AAP connections on this adapter
          let devices_to_reconnect: Vec<Address> = self
@@ -548,15 +1262,28 @@ impl ManagerActor {
          if let Some(handle) = info.monitor_handle.take() {
             handle.abort();
          }
+         info.advertisements.stop();
+
+         // Watches of devices on this adapter end with it
+         self.watches.retain(|_, watch| {
+            let keep = watch.adapter_name != name;
+            if !keep {
+               watch.handle.abort();
+            }
+            keep
+         });
 
          // Mark all AAP connections on this adapter as failed
          for device in self.devices.values_mut() {
             if device.adapter_name == name {
                device.aap_state = AAPState::Failed("Adapter lost");
+               device.last_aap_error = Some("Adapter lost".to_string());
+               device.next_aap_retry = None;
                // Abort AAP handle if it exists
                if let Some(handle) = device.aap_handle.take() {
                   handle.abort();
                }
+               device.publish_state(&self.event_tx);
                self
                   .event_tx
                   .emit(&device.device, AirPodsEvent::DeviceError);
@@ -600,12 +1327,6 @@ impl ManagerActor {
    }
 
    async fn handle_device_discovered(&mut self, addr: Address, adapter_name: SmolStr) {
-      // Check if we already know about this device
-      if self.devices.contains_key(&addr) {
-         return;
-      }
-
-      // Verify it's an AirPods device
       let Some(adapter_info) = self.adapters.get(&adapter_name) else {
          return;
       };
@@ -614,9 +1335,23 @@ impl ManagerActor {
          return;
       };
 
+      // Check if we already know about this device, its watch may have ended
+      // with a lost adapter
+      if self.devices.contains_key(&addr) {
+         self.watch_device(device, &adapter_name, false);
+         return;
+      }
+      if self.is_rejected(addr) {
+         return;
+      }
+
+      // Verify it's an AirPods device
       if !self.is_airpods_device(&device).await {
+         debug!("{addr} is not AirPods, ignoring it until it pairs");
+         self.watch_device(device, &adapter_name, true);
          return;
       }
+      self.watch_device(device.clone(), &adapter_name, false);
 
       // Only proceed if already connected by bluetoothd
       if !device.is_connected().await.unwrap_or(false) {
@@ -633,7 +1368,16 @@ impl ManagerActor {
       info!("Found connected AirPods: {name} ({addr})");
 
       // Create managed device
-      let airpods = AirPods::new(addr, name, self.battery_study.clone());
+      let airpods = AirPods::new(addr, name, adapter_name.clone(), self.battery_study.clone());
+      airpods.set_profile(self.config.profile(&addr.to_string()));
+      if let Ok(Some(modalias)) = device.modalias().await {
+         airpods.set_model(DeviceModel {
+            product_id: modalias.product,
+            version: modalias.device,
+         });
+      }
+      airpods.save_state();
+      self.event_tx.emit(&airpods, AirPodsEvent::DeviceAdded);
       let managed = ManagedDevice {
          device: airpods,
          bluetooth_state: BluetoothState::Connected,
@@ -641,8 +1385,10 @@ impl ManagerActor {
          adapter_name,
          aap_retry_count: 0,
          last_aap_error: None,
+         next_aap_retry: None,
          aap_handle: None,
       };
+      managed.publish_state(&self.event_tx);
 
       self.devices.insert(addr, managed);
 
@@ -654,6 +1400,7 @@ impl ManagerActor {
       // Check if this is an AirPods device
       let is_airpods = if let Some(device) = self.devices.get_mut(&addr) {
          device.bluetooth_state = BluetoothState::Connected;
+         device.publish_state(&self.event_tx);
          true
       } else {
          // Check if this is a newly connected AirPods
@@ -681,12 +1428,15 @@ impl ManagerActor {
    fn handle_bluetooth_disconnected(&mut self, addr: Address) {
       if let Some(device) = self.devices.get_mut(&addr) {
          device.bluetooth_state = BluetoothState::Disconnected;
+         device.device.save_state();
 
          // Clean up AAP connection
          if let Some(handle) = device.aap_handle.take() {
             handle.abort();
          }
          device.aap_state = AAPState::Disconnected;
+         device.next_aap_retry = None;
+         device.publish_state(&self.event_tx);
 
          self
             .event_tx
@@ -701,6 +1451,8 @@ impl ManagerActor {
          device.aap_state = AAPState::Connected;
          device.aap_retry_count = 0;
          device.last_aap_error = None;
+         device.next_aap_retry = None;
+         device.publish_state(&self.event_tx);
 
          self
             .event_tx
@@ -710,8 +1462,12 @@ impl ManagerActor {
       self.aap_connecting.remove(&addr);
    }
 
-   fn handle_aap_disconnected(&mut self, addr: Address, is_error: bool) {
+   fn handle_aap_disconnected(&mut self, addr: Address, error: Option<String>) {
       if let Some(device) = self.devices.get_mut(&addr) {
+         let is_error = error.is_some();
+         if let Some(error) = error {
+            device.last_aap_error = Some(error);
+         }
          if is_error && device.bluetooth_state == BluetoothState::Connected {
             // Only retry AAP if Bluetooth is still connected
             device.aap_state = AAPState::WaitingToReconnect;
@@ -720,6 +1476,7 @@ impl ManagerActor {
             // Schedule AAP reconnection with backoff
             let loopback = self.loopback_tx.clone();
             let delay = calc_retry_delay(device.aap_retry_count);
+            device.next_aap_retry = Some(SystemTime::now() + delay);
             info!("AAP connection to {addr} failed, retrying in {delay:?}");
 
             tokio::spawn(async move {
@@ -731,17 +1488,24 @@ impl ManagerActor {
          } else {
             device.aap_state = AAPState::Disconnected;
             device.aap_retry_count = 0;
+            device.next_aap_retry = None;
          }
+         device.publish_state(&self.event_tx);
       }
 
       self.aap_connecting.remove(&addr);
    }
 
    fn handle_device_lost(&mut self, addr: Address) {
+      self.unwatch_device(addr);
       if let Some(device) = self.devices.remove(&addr) {
+         device.device.save_state();
          self
             .event_tx
             .emit(&device.device, AirPodsEvent::DeviceDisconnected);
+         self
+            .event_tx
+            .emit(&device.device, AirPodsEvent::DeviceRemoved);
       }
       self.aap_connecting.remove(&addr);
    }
@@ -756,6 +1520,9 @@ impl ManagerActor {
          .devices
          .get_mut(&addr)
          .ok_or(AirPodsError::DeviceNotFound(addr))?;
+      if device.aap_state == AAPState::Connected {
+         return Ok(());
+      }
 
       // Check adapter is available
       let adapter_info = self
@@ -815,7 +1582,10 @@ impl ManagerActor {
             },
          };
          if let Err(e) = loopback
-            .send(ManagerCommand::AAPDisconnected(addr, err.is_some()))
+            .send(ManagerCommand::AAPDisconnected(
+               addr,
+               err.map(|e| e.to_string()),
+            ))
             .await
          {
             warn!("Channel overflow sending AAP disconnected: {e}");
@@ -828,6 +1598,8 @@ impl ManagerActor {
       // Mark as connecting only after spawn succeeds
       self.aap_connecting.insert(addr);
       device.aap_state = AAPState::Connecting;
+      device.next_aap_retry = None;
+      device.publish_state(&self.event_tx);
 
       Ok(())
    }
@@ -844,6 +1616,8 @@ impl ManagerActor {
       }
 
       device.aap_state = AAPState::Disconnected;
+      device.next_aap_retry = None;
+      device.publish_state(&self.event_tx);
       device.device.disconnect().await;
 
       self.aap_connecting.remove(&addr);
@@ -867,6 +1641,10 @@ impl ManagerActor {
          }
       }
 
+      for (_, watch) in self.watches.drain() {
+         watch.handle.abort();
+      }
+
       // Abort AAP handles and disconnect all devices
       for device in self.devices.values_mut() {
          if let Some(handle) = device.aap_handle.take() {
@@ -907,7 +1685,8 @@ impl ManagerActor {
          // Check all connected devices
          if let Ok(addresses) = adapter_info.adapter.device_addresses().await {
             for addr in addresses {
-               if let Ok(device) = adapter_info.adapter.device(addr)
+               if !self.is_rejected(addr)
+                  && let Ok(device) = adapter_info.adapter.device(addr)
                   && device.is_connected().await.unwrap_or(false)
                   && self.is_airpods_device(&device).await
                   && !self.has_aap_connection(addr)
@@ -966,6 +1745,10 @@ impl ManagerActor {
    }
 }
 
+fn calc_connect_delay(base_delay: Duration, attempt: u32) -> Duration {
+   (base_delay * (1 << attempt.saturating_sub(1).min(4))).min(MAX_CONNECT_RETRY_DELAY)
+}
+
 fn calc_retry_delay(retry_count: u32) -> Duration {
    let base_delay = Duration::from_secs(2);
    let exponential = base_delay * (1 << retry_count.min(4));
@@ -3,5 +3,7 @@
 //! This module provides Bluetooth connectivity including L2CAP socket
 //! management and device discovery/connection handling.
 
+pub mod battery_provider;
 pub mod l2cap;
 pub mod manager;
+pub mod pairing;
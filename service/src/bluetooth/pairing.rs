@@ -0,0 +1,194 @@
+//! Discovery and pairing of new `AirPods`.
+//!
+//! A pairing session scans for nearby devices recognized as `AirPods` for a
+//! limited time. Pairing one of them registers an agent that confirms the
+//! request of that device only, then trusts and connects it, so the buds can
+//! be set up without the desktop Bluetooth settings.
+
+use std::{collections::BTreeSet, sync::Arc, time::Duration};
+
+use bluer::{
+   Adapter, AdapterEvent, Address, DiscoveryFilter, Session,
+   agent::{Agent, ReqError, ReqResult},
+};
+use futures::StreamExt;
+use log::{debug, info};
+use serde_json::{Value, json};
+use tokio::{task::JoinHandle, time};
+
+use crate::{
+   airpods::{protocol::BatteryInfo, recognition},
+   error::Result,
+};
+
+/// How long a pairing session scans before stopping on its own
+const DISCOVERY_TIMEOUT: Duration = Duration::from_secs(120);
+
+/// A device found by a pairing session.
+#[derive(Debug, Clone)]
+pub struct Candidate {
+   pub address: Address,
+   pub name: String,
+   pub paired: bool,
+   pub rssi: Option<i16>,
+   pub product_id: Option<u32>,
+   /// Levels from the latest advertisement.
+   pub battery: Option<BatteryInfo>,
+}
+
+impl Candidate {
+   async fn read(device: &bluer::Device) -> Self {
+      let address = device.address();
+      let name = match device.name().await {
+         Ok(Some(name)) => name,
+         _ => device.alias().await.unwrap_or_else(|_| address.to_string()),
+      };
+      let advertisement = device
+         .manufacturer_data()
+         .await
+         .ok()
+         .flatten()
+         .and_then(|data| recognition::parse_proximity_pairing(&data));
+      let product_id = match device.modalias().await {
+         Ok(Some(modalias)) => Some(modalias.product),
+         _ => advertisement.map(|pp| pp.product_id),
+      };
+      Self {
+         address,
+         name,
+         paired: device.is_paired().await.unwrap_or(false),
+         rssi: device.rssi().await.ok().flatten(),
+         product_id,
+         battery: advertisement.map(|pp| pp.battery),
+      }
+   }
+
+   pub fn to_json(&self) -> Value {
+      json!({
+         "address": self.address.to_string(),
+         "name": self.name,
+         "paired": self.paired,
+         "rssi": self.rssi,
+         "product_id": self.product_id,
+         "battery": self.battery.map(BatteryInfo::to_json),
+      })
+   }
+}
+
+/// A running discovery for `AirPods` to pair.
+///
+/// Discovery stops when the session is dropped or after `DISCOVERY_TIMEOUT`;
+/// the devices found so far stay available.
+pub struct PairingSession {
+   adapter: Adapter,
+   candidates: Arc<parking_lot::Mutex<BTreeSet<Address>>>,
+   handle: JoinHandle<()>,
+}
+
+impl PairingSession {
+   pub async fn start(adapter: Adapter) -> Result<Self> {
+      adapter
+         .set_discovery_filter(DiscoveryFilter {
+            // Keep receiving advertisements for the battery levels
+            duplicate_data: true,
+            ..Default::default()
+         })
+         .await?;
+      let events = adapter.discover_devices().await?;
+      info!("Discovering AirPods to pair on {}", adapter.name());
+
+      let candidates = Arc::<parking_lot::Mutex<BTreeSet<Address>>>::default();
+      let handle = tokio::spawn({
+         let adapter = adapter.clone();
+         let candidates = candidates.clone();
+         async move {
+            let scan = async {
+               let mut events = std::pin::pin!(events);
+               while let Some(event) = events.next().await {
+                  if let AdapterEvent::DeviceAdded(addr) = event
+                     && !candidates.lock().contains(&addr)
+                     && let Ok(device) = adapter.device(addr)
+                     && recognition::is_device_airpods(&device).await
+                  {
+                     debug!("Found AirPods to pair: {addr}");
+                     candidates.lock().insert(addr);
+                  }
+               }
+            };
+            if time::timeout(DISCOVERY_TIMEOUT, scan).await.is_err() {
+               info!("Pairing discovery timed out");
+            }
+         }
+      });
+      Ok(Self {
+         adapter,
+         candidates,
+         handle,
+      })
+   }
+
+   pub fn is_discovering(&self) -> bool {
+      !self.handle.is_finished()
+   }
+
+   pub fn contains(&self, address: Address) -> bool {
+      self.candidates.lock().contains(&address)
+   }
+
+   pub const fn adapter(&self) -> &Adapter {
+      &self.adapter
+   }
+
+   /// Reads the current state of the devices found so far.
+   pub async fn candidates(&self) -> Vec<Candidate> {
+      let addresses: Vec<Address> = self.candidates.lock().iter().copied().collect();
+      let mut candidates = Vec::with_capacity(addresses.len());
+      for addr in addresses {
+         if let Ok(device) = self.adapter.device(addr) {
+            candidates.push(Candidate::read(&device).await);
+         }
+      }
+      candidates
+   }
+}
+
+impl Drop for PairingSession {
+   fn drop(&mut self) {
+      self.handle.abort();
+   }
+}
+
+/// Pairs, trusts and connects a device, returning its name.
+pub async fn pair(session: &Session, device: &bluer::Device) -> Result<String> {
+   let addr = device.address();
+   let agent = Agent {
+      request_confirmation: Some(Box::new(move |req| Box::pin(confirm(req.device, addr)))),
+      request_authorization: Some(Box::new(move |req| Box::pin(confirm(req.device, addr)))),
+      ..Default::default()
+   };
+   // Unregistered when dropped
+   let _agent = session.register_agent(agent).await?;
+
+   if !device.is_paired().await? {
+      info!("Pairing with {addr}");
+      device.pair().await?;
+   }
+   device.set_trusted(true).await?;
+   device.connect().await?;
+
+   let name = match device.name().await? {
+      Some(name) => name,
+      None => device.alias().await?,
+   };
+   info!("Paired with {name} ({addr})");
+   Ok(name)
+}
+
+/// Confirms the pairing request of the device being paired, and no other.
+async fn confirm(requester: Address, target: Address) -> ReqResult<()> {
+   if requester == target {
+      Ok(())
+   } else {
+      Err(ReqError::Rejected)
+   }
+}
@@ -3,15 +3,29 @@
 //! This module handles loading and saving configuration from disk,
 //! including known devices and connection parameters.
 
-use std::{env, fs, path::PathBuf};
+use std::{
+   collections::BTreeMap,
+   env, fmt,
+   fs::{self, File},
+   io::Write,
+   path::{Path, PathBuf},
+   str::FromStr,
+};
 
 use serde::{Deserialize, Serialize};
 use smol_str::SmolStr;
+use toml_edit::{DocumentMut, Item, Table};
 
-use crate::error::{AirPodsError, Result};
+use crate::{
+   airpods::protocol::{
+      FeatureId, KNOWN_FEATURES, NoiseControlMode, Setting, TransparencySettings,
+   },
+   error::{AirPodsError, Result},
+   event::EventKind,
+};
 
 /// Main configuration structure for the service.
-#[derive(Serialize, Deserialize, Clone)]
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
 pub struct Config {
    #[serde(default)]
    pub known_devices: Vec<KnownDevice>,
@@ -30,13 +44,414 @@ pub struct Config {
 
    #[serde(default)]
    pub log_filter: Option<SmolStr>,
+
+   #[serde(default)]
+   pub battery_provider: BatteryProviderConfig,
+
+   /// Shows a `StatusNotifierItem` tray icon, for desktops without the Plasma widget.
+   #[serde(default)]
+   pub tray_icon: bool,
+
+   #[serde(default)]
+   pub passthrough: PassthroughConfig,
+
+   #[serde(default)]
+   pub rules: RulesConfig,
+
+   #[serde(default)]
+   pub hooks: HooksConfig,
+}
+
+/// Settings for publishing battery levels to `BlueZ`.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct BatteryProviderConfig {
+   #[serde(default = "default_true")]
+   pub enabled: bool,
+
+   #[serde(default)]
+   pub policy: BatteryPolicy,
+}
+
+/// How the bud levels are combined into the single percentage `BlueZ` exposes.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum BatteryPolicy {
+   /// Lowest level of the available buds.
+   #[default]
+   Min,
+   /// Highest level of the available buds.
+   Max,
+   /// Average level of the available buds.
+   Average,
+   /// Level of the primary bud, falling back to the lowest one.
+   Primary,
+}
+
+/// Access to the raw `Passthrough` D-Bus method.
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+pub struct PassthroughConfig {
+   #[serde(default)]
+   pub enabled: bool,
+
+   /// Hex prefixes a packet must start with; any packet is allowed if empty.
+   #[serde(default)]
+   pub allowed_prefixes: Vec<String>,
 }
 
 /// Represents a known `AirPods` device.
-#[derive(Serialize, Deserialize, Clone)]
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
 pub struct KnownDevice {
    pub address: String,
    pub name: String,
+
+   /// When kairpodsd connects the device itself instead of waiting for bluetoothd.
+   #[serde(default, skip_serializing_if = "Vec::is_empty")]
+   pub auto_connect: Vec<ConnectTrigger>,
+
+   /// Settings applied every time the device connects.
+   #[serde(default, skip_serializing_if = "DeviceProfile::is_empty")]
+   pub profile: DeviceProfile,
+}
+
+/// Settings a known device is brought to once connected.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct DeviceProfile {
+   #[serde(
+      default,
+      with = "noise_mode_name::option",
+      skip_serializing_if = "Option::is_none"
+   )]
+   pub noise_mode: Option<NoiseControlMode>,
+
+   /// Feature toggles, by `KNOWN_FEATURES` name.
+   #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+   pub features: BTreeMap<SmolStr, bool>,
+
+   /// Transparency levels; the ones left out keep their current value.
+   #[serde(default, skip_serializing_if = "TransparencyProfile::is_empty")]
+   pub transparency: TransparencyProfile,
+
+   /// Skips the settings the device already reports.
+   #[serde(default = "default_true", skip_serializing_if = "is_true")]
+   pub only_if_different: bool,
+
+   /// Applies the profile only on the first connection after the service starts.
+   #[serde(default, skip_serializing_if = "is_false")]
+   pub once_per_session: bool,
+}
+
+/// Numeric transparency settings of a [`DeviceProfile`].
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq)]
+pub struct TransparencyProfile {
+   pub amplification: Option<f32>,
+   pub balance: Option<f32>,
+   pub tone: Option<f32>,
+   pub ambient_noise_reduction: Option<f32>,
+}
+
+impl TransparencyProfile {
+   /// Checks if no transparency level is set.
+   pub fn is_empty(&self) -> bool {
+      *self == Self::default()
+   }
+}
+
+impl Default for DeviceProfile {
+   fn default() -> Self {
+      Self {
+         noise_mode: None,
+         features: BTreeMap::new(),
+         transparency: TransparencyProfile::default(),
+         only_if_different: true,
+         once_per_session: false,
+      }
+   }
+}
+
+impl DeviceProfile {
+   /// Checks if the profile changes no setting.
+   pub fn is_empty(&self) -> bool {
+      self.noise_mode.is_none() && self.features.is_empty() && self.transparency.is_empty()
+   }
+
+   /// Gets the noise mode and feature settings, in the order `ApplySettings` uses.
+   ///
+   /// Feature names are checked by `Config::validate`, any other is ignored.
+   pub fn settings(&self) -> Vec<Setting> {
+      let mut features: Vec<(FeatureId, bool)> = self
+         .features
+         .iter()
+         .filter_map(|(name, &enabled)| Some((name.parse::<FeatureId>().ok()?, enabled)))
+         .collect();
+      features.sort_by_key(|&(feature, _)| {
+         KNOWN_FEATURES
+            .iter()
+            .position(|&(id, _)| id == feature.id())
+      });
+      self
+         .noise_mode
+         .map(Setting::NoiseMode)
+         .into_iter()
+         .chain(
+            features
+               .into_iter()
+               .map(|(f, enabled)| Setting::Feature(f, enabled)),
+         )
+         .collect()
+   }
+
+   /// Applies the transparency levels of the profile on top of `current`.
+   pub fn transparency(&self, current: TransparencySettings) -> TransparencySettings {
+      let levels = self.transparency;
+      TransparencySettings {
+         amplification: levels.amplification.unwrap_or(current.amplification),
+         balance: levels.balance.unwrap_or(current.balance),
+         tone: levels.tone.unwrap_or(current.tone),
+         ambient_noise_reduction: levels
+            .ambient_noise_reduction
+            .unwrap_or(current.ambient_noise_reduction),
+         ..current
+      }
+   }
+}
+
+/// (De)serializes a noise mode by the name used on D-Bus, like `"anc"`.
+mod noise_mode_name {
+   use serde::{Deserialize, Deserializer, Serializer, de::Error};
+
+   use crate::airpods::protocol::NoiseControlMode;
+
+   pub fn serialize<S: Serializer>(
+      mode: &NoiseControlMode,
+      serializer: S,
+   ) -> Result<S::Ok, S::Error> {
+      serializer.serialize_str(mode.to_str())
+   }
+
+   pub fn deserialize<'de, D: Deserializer<'de>>(
+      deserializer: D,
+   ) -> Result<NoiseControlMode, D::Error> {
+      String::deserialize(deserializer)?
+         .parse()
+         .map_err(D::Error::custom)
+   }
+
+   pub mod option {
+      use serde::{Deserialize, Deserializer, Serializer, de::Error};
+
+      use crate::airpods::protocol::NoiseControlMode;
+
+      pub fn serialize<S: Serializer>(
+         mode: &Option<NoiseControlMode>,
+         serializer: S,
+      ) -> Result<S::Ok, S::Error> {
+         match mode {
+            Some(mode) => serializer.serialize_some(mode.to_str()),
+            None => serializer.serialize_none(),
+         }
+      }
+
+      pub fn deserialize<'de, D: Deserializer<'de>>(
+         deserializer: D,
+      ) -> Result<Option<NoiseControlMode>, D::Error> {
+         Option::<String>::deserialize(deserializer)?
+            .map(|name| name.parse().map_err(D::Error::custom))
+            .transpose()
+      }
+   }
+}
+
+/// Occasion for the service to initiate the Bluetooth connection to a known device.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ConnectTrigger {
+   /// The buds advertise, which they do once the case lid is opened.
+   LidOpen,
+   /// A client calls `ConnectDevice`.
+   Request,
+   /// The service starts.
+   Startup,
+}
+
+/// Commands run on device events.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct HooksConfig {
+   /// Seconds after which a hook is killed.
+   #[serde(default = "default_hook_timeout")]
+   pub timeout_sec: u64,
+
+   /// Hooks running at once; the ones fired beyond it are skipped.
+   #[serde(default = "default_hook_concurrency")]
+   pub max_concurrent: usize,
+
+   /// Shell commands run for each kind of event.
+   #[serde(flatten)]
+   pub commands: BTreeMap<EventKind, Vec<String>>,
+}
+
+impl Default for HooksConfig {
+   fn default() -> Self {
+      Self {
+         timeout_sec: default_hook_timeout(),
+         max_concurrent: default_hook_concurrency(),
+         commands: BTreeMap::new(),
+      }
+   }
+}
+
+/// Automation rules run by the service on device events.
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+pub struct RulesConfig {
+   /// Logs the actions of matching rules instead of running them.
+   #[serde(default)]
+   pub dry_run: bool,
+
+   #[serde(default, rename = "rule")]
+   pub rules: Vec<Rule>,
+}
+
+/// Runs actions when one of the triggers fires and every condition holds.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct Rule {
+   pub name: SmolStr,
+   pub on: Vec<Trigger>,
+   #[serde(default, rename = "if")]
+   pub conditions: Conditions,
+   #[serde(rename = "do")]
+   pub actions: Vec<Action>,
+}
+
+/// What makes a rule evaluate its conditions.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Trigger {
+   /// A device event, like `device_connected` or `ear_detection_changed`.
+   Event(EventKind),
+   /// The battery level drops below the given percentage.
+   BatteryBelow(u8),
+   /// The battery level rises above the given percentage.
+   BatteryAbove(u8),
+   /// The local time reaches the given time of day, for every connected device.
+   Time(TimeOfDay),
+}
+
+/// State a device must be in for a rule to run, every field left out holds.
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct Conditions {
+   /// Address or name of the device.
+   pub device: Option<SmolStr>,
+   pub connected: Option<bool>,
+   pub in_ear: Option<InEar>,
+   pub battery_above: Option<u8>,
+   pub battery_below: Option<u8>,
+   #[serde(
+      default,
+      with = "noise_mode_name::option",
+      skip_serializing_if = "Option::is_none"
+   )]
+   pub noise_mode: Option<NoiseControlMode>,
+   /// Start of the time window, in local time.
+   pub after: Option<TimeOfDay>,
+   /// End of the time window, which may wrap around midnight.
+   pub before: Option<TimeOfDay>,
+}
+
+/// Buds in ear, as reported by ear detection.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum InEar {
+   #[serde(rename = "none")]
+   Neither,
+   Any,
+   Both,
+}
+
+/// Something a rule does.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Action {
+   NoiseMode(#[serde(with = "noise_mode_name")] NoiseControlMode),
+   /// Toggles a feature by `KNOWN_FEATURES` name.
+   Feature {
+      name: SmolStr,
+      enabled: bool,
+   },
+   /// Shows a desktop notification.
+   Notify(String),
+   /// Runs a shell command.
+   Run(String),
+}
+
+/// Local time of day, written as `"HH:MM"`.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(try_from = "String", into = "String")]
+pub struct TimeOfDay {
+   pub hour: u8,
+   pub minute: u8,
+}
+
+impl fmt::Display for TimeOfDay {
+   fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+      write!(f, "{:02}:{:02}", self.hour, self.minute)
+   }
+}
+
+impl FromStr for TimeOfDay {
+   type Err = String;
+
+   fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+      let invalid = || format!("invalid time of day {s:?}, expected HH:MM");
+      let (hour, minute) = s.split_once(':').ok_or_else(invalid)?;
+      let hour: u8 = hour.parse().map_err(|_| invalid())?;
+      let minute: u8 = minute.parse().map_err(|_| invalid())?;
+      if hour > 23 || minute > 59 {
+         return Err(invalid());
+      }
+      Ok(Self { hour, minute })
+   }
+}
+
+impl TryFrom<String> for TimeOfDay {
+   type Error = String;
+
+   fn try_from(s: String) -> std::result::Result<Self, Self::Error> {
+      s.parse()
+   }
+}
+
+impl From<TimeOfDay> for String {
+   fn from(time: TimeOfDay) -> Self {
+      time.to_string()
+   }
+}
+
+const fn default_true() -> bool {
+   true
+}
+
+#[allow(
+   clippy::trivially_copy_pass_by_ref,
+   reason = "serde passes fields by reference"
+)]
+const fn is_true(value: &bool) -> bool {
+   *value
+}
+
+#[allow(
+   clippy::trivially_copy_pass_by_ref,
+   reason = "serde passes fields by reference"
+)]
+const fn is_false(value: &bool) -> bool {
+   !*value
+}
+
+const fn default_hook_timeout() -> u64 {
+   30
+}
+
+const fn default_hook_concurrency() -> usize {
+   4
 }
 
 const fn default_poll_interval() -> u64 {
@@ -64,18 +479,31 @@ impl Default for Config {
          reconnect_delay_sec: default_reconnect_delay(),
          notification_retries: default_notification_retries(),
          log_filter: None,
+         battery_provider: BatteryProviderConfig::default(),
+         tray_icon: false,
+         passthrough: PassthroughConfig::default(),
+         rules: RulesConfig::default(),
+         hooks: HooksConfig::default(),
+      }
+   }
+}
+
+impl Default for BatteryProviderConfig {
+   fn default() -> Self {
+      Self {
+         enabled: default_true(),
+         policy: BatteryPolicy::default(),
       }
    }
 }
 
 impl Config {
    /// Loads configuration from disk or creates default if not exists.
-   pub fn load() -> Result<Self> {
+   pub fn load(system_bus: bool) -> Result<Self> {
       let config_path = Self::config_path()?;
 
       if config_path.exists() {
-         let contents = fs::read_to_string(&config_path)?;
-         Ok(toml::from_str(&contents)?)
+         Self::read(&config_path, system_bus)
       } else {
          // Create default config
          let config = Self::default();
@@ -93,13 +521,127 @@ impl Config {
          fs::create_dir_all(parent)?;
       }
 
-      let contents = toml::to_string_pretty(self)?;
-      fs::write(&config_path, contents)?;
+      Self::write(&config_path, &toml::to_string_pretty(self)?)
+   }
+
+   /// Replaces the known devices in the configuration file, leaving the rest of
+   /// the file, comments included, as the user wrote it.
+   ///
+   /// Refused if the file does not hold the running configuration, i.e. it
+   /// failed to load at startup or was edited and rejected since, as saving
+   /// would overwrite the known devices in it.
+   pub fn save_known_devices(&self, known_devices: &[KnownDevice]) -> Result<()> {
+      let config_path = Self::config_path()?;
+      let contents = fs::read_to_string(&config_path)?;
+      let on_disk = toml::from_str::<Self>(&contents)?;
+      if on_disk != *self {
+         return Err(AirPodsError::PreconditionFailed(
+            "The configuration file differs from the running configuration",
+         ));
+      }
+
+      Self::write(
+         &config_path,
+         &patch_known_devices(&contents, known_devices)?,
+      )
+   }
+
+   /// Writes `contents` to `config_path`, replacing the file atomically so a
+   /// crash never leaves a truncated config.
+   fn write(config_path: &Path, contents: &str) -> Result<()> {
+      let tmp_path = config_path.with_extension("toml.tmp");
+      let mut file = File::create(&tmp_path)?;
+      file.write_all(contents.as_bytes())?;
+      file.sync_all()?;
+      fs::rename(&tmp_path, config_path)?;
 
       Ok(())
    }
 
-   fn config_path() -> Result<PathBuf> {
+   /// Reads and validates a configuration file, without falling back to the defaults.
+   pub fn read(path: &Path, system_bus: bool) -> Result<Self> {
+      let config: Self = toml::from_str(&fs::read_to_string(path)?)?;
+      config.validate(system_bus)?;
+      Ok(config)
+   }
+
+   /// Checks the values that parse but cannot be used, on the system bus if
+   /// `system_bus` is set.
+   pub fn validate(&self, system_bus: bool) -> Result<()> {
+      let invalid = |message: String| Err(AirPodsError::InvalidConfig(message));
+      let check_feature = |name: &str| {
+         if name.parse::<FeatureId>().is_err() {
+            return invalid(format!("unknown feature {name:?}"));
+         }
+         Ok(())
+      };
+      let check_threshold = |rule: &Rule, threshold: u8| {
+         if threshold > 100 {
+            return invalid(format!(
+               "rule {:?} has a battery threshold of {threshold}%",
+               rule.name
+            ));
+         }
+         Ok(())
+      };
+
+      for (i, device) in self.known_devices.iter().enumerate() {
+         if device.address.parse::<bluer::Address>().is_err() {
+            return invalid(format!("invalid device address {:?}", device.address));
+         }
+         if self.known_devices[..i]
+            .iter()
+            .any(|d| d.address.eq_ignore_ascii_case(&device.address))
+         {
+            return invalid(format!("device {} is listed twice", device.address));
+         }
+         for name in device.profile.features.keys() {
+            check_feature(name)?;
+         }
+         if let Err(e) = device
+            .profile
+            .transparency(TransparencySettings::default())
+            .validate()
+         {
+            return invalid(format!("profile of {}: {e}", device.address));
+         }
+      }
+      if self.hooks.timeout_sec == 0 {
+         return invalid("hooks timeout_sec must be at least 1".to_string());
+      }
+      for rule in &self.rules.rules {
+         if rule.on.is_empty() {
+            return invalid(format!("rule {:?} has no trigger", rule.name));
+         }
+         for trigger in &rule.on {
+            if let Trigger::BatteryBelow(threshold) | Trigger::BatteryAbove(threshold) = *trigger {
+               check_threshold(rule, threshold)?;
+            }
+         }
+         for threshold in [rule.conditions.battery_above, rule.conditions.battery_below]
+            .into_iter()
+            .flatten()
+         {
+            check_threshold(rule, threshold)?;
+         }
+         for action in &rule.actions {
+            match action {
+               Action::Feature { name, .. } => check_feature(name)?,
+               // Desktop notifications go to the session of a user
+               Action::Notify(_) if system_bus => {
+                  return invalid(format!(
+                     "rule {:?} uses notify, which needs the session bus",
+                     rule.name
+                  ));
+               },
+               _ => {},
+            }
+         }
+      }
+      Ok(())
+   }
+
+   pub fn config_path() -> Result<PathBuf> {
       // Check for override environment variable first
       if let Ok(path) = env::var("AIRPODS_CONFIG_PATH") {
          return Ok(PathBuf::from(path));
@@ -112,12 +654,290 @@ impl Config {
          .join("config.toml"))
    }
 
-   /// Checks if the given address is a known device and returns its name.
-   pub fn is_known_device(&self, address: &str) -> Option<&str> {
+   /// Finds the known device with the given address.
+   pub fn known_device(&self, address: &str) -> Option<&KnownDevice> {
       self
          .known_devices
          .iter()
-         .find(|d| d.address == address)
-         .map(|d| d.name.as_str())
+         .find(|d| d.address.eq_ignore_ascii_case(address))
+   }
+
+   /// Checks if the service should connect the given address itself on `trigger`.
+   pub fn auto_connects(&self, address: &str, trigger: ConnectTrigger) -> bool {
+      self
+         .known_device(address)
+         .is_some_and(|d| d.auto_connect.contains(&trigger))
+   }
+
+   /// Gets the profile of the known device with the given address.
+   pub fn profile(&self, address: &str) -> Option<DeviceProfile> {
+      self.known_device(address).map(|d| d.profile.clone())
+   }
+
+   /// Checks if the given address is a known device and returns its name.
+   pub fn is_known_device(&self, address: &str) -> Option<&str> {
+      self.known_device(address).map(|d| d.name.as_str())
+   }
+}
+
+/// Replaces the `known_devices` of a configuration document, keeping everything else.
+fn patch_known_devices(contents: &str, known_devices: &[KnownDevice]) -> Result<String> {
+   #[derive(Serialize)]
+   struct KnownDevices<'a> {
+      known_devices: &'a [KnownDevice],
+   }
+
+   let mut document = contents.parse::<DocumentMut>()?;
+   let mut patch =
+      toml::to_string_pretty(&KnownDevices { known_devices })?.parse::<DocumentMut>()?;
+   let Some(mut item) = patch.remove("known_devices") else {
+      document.remove("known_devices");
+      return Ok(document.to_string());
+   };
+
+   // Keep the devices where they were in the file, along with the comments above them
+   if let Some(tables) = item.as_array_of_tables_mut() {
+      let old = document
+         .get("known_devices")
+         .and_then(Item::as_array_of_tables);
+      let position = old
+         .and_then(|old| old.iter().find_map(Table::position))
+         .unwrap_or(usize::MAX);
+      for table in tables.iter_mut() {
+         let address = table.get("address").and_then(Item::as_str);
+         let previous = old.and_then(|old| {
+            old.iter().find(|t| {
+               t.get("address")
+                  .and_then(Item::as_str)
+                  .zip(address)
+                  .is_some_and(|(a, b)| a.eq_ignore_ascii_case(b))
+            })
+         });
+         match previous {
+            Some(previous) => *table.decor_mut() = previous.decor().clone(),
+            None => table.decor_mut().set_prefix("\n"),
+         }
+         set_position(table, position);
+      }
+   }
+   document.insert("known_devices", item);
+   Ok(document.to_string())
+}
+
+/// Places a table and its subtables at `position` of the document.
+fn set_position(table: &mut Table, position: usize) {
+   table.set_position(position);
+   for (_, item) in table.iter_mut() {
+      if let Some(table) = item.as_table_mut() {
+         set_position(table, position);
+      }
+   }
+}
+
+#[cfg(test)]
+mod tests {
+   use super::*;
+
+   #[test]
+   fn test_known_devices() {
+      let config: Config = toml::from_str(
+         r#"
+         [[known_devices]]
+         address = "aa:bb:cc:dd:ee:ff"
+         name = "AirPods Pro"
+         auto_connect = ["lid_open", "startup"]
+
+         [[known_devices]]
+         address = "11:22:33:44:55:66"
+         name = "AirPods Max"
+         "#,
+      )
+      .unwrap();
+
+      assert_eq!(
+         config.is_known_device("AA:BB:CC:DD:EE:FF"),
+         Some("AirPods Pro")
+      );
+      assert!(config.auto_connects("AA:BB:CC:DD:EE:FF", ConnectTrigger::LidOpen));
+      assert!(!config.auto_connects("AA:BB:CC:DD:EE:FF", ConnectTrigger::Request));
+      assert!(!config.auto_connects("11:22:33:44:55:66", ConnectTrigger::Startup));
+      assert!(config.known_device("00:00:00:00:00:00").is_none());
+   }
+
+   #[test]
+   fn test_device_profile() {
+      let config: Config = toml::from_str(
+         r#"
+         [[known_devices]]
+         address = "aa:bb:cc:dd:ee:ff"
+         name = "AirPods Pro"
+
+         [known_devices.profile]
+         noise_mode = "anc"
+         once_per_session = true
+         features = { conversational = false, one_bud_anc = true }
+         transparency = { amplification = 0.5 }
+         "#,
+      )
+      .unwrap();
+      let profile = &config.known_devices[0].profile;
+      assert!(profile.only_if_different);
+      assert!(profile.once_per_session);
+
+      assert_eq!(
+         profile.settings(),
+         [
+            Setting::NoiseMode(NoiseControlMode::Active),
+            Setting::Feature(FeatureId::ONE_BUD_ANC, true),
+            Setting::Feature(FeatureId::CONVERSATIONAL, false),
+         ]
+      );
+
+      let current = TransparencySettings {
+         tone: 0.25,
+         ..Default::default()
+      };
+      let applied = profile.transparency(current);
+      assert_eq!(applied.amplification, 0.5);
+      assert_eq!(applied.tone, 0.25);
+
+      let saved = toml::to_string(&config).unwrap();
+      let reloaded: Config = toml::from_str(&saved).unwrap();
+      assert_eq!(&reloaded.known_devices[0].profile, profile);
+      assert!(DeviceProfile::default().is_empty());
+   }
+
+   #[test]
+   fn test_patch_known_devices() {
+      let contents = r#"# Managed by hand
+poll_interval = 60 # seconds
+
+[passthrough]
+enabled = true
+
+# Work
+[[known_devices]]
+address = "AA:BB:CC:DD:EE:FF"
+name = "AirPods Pro"
+
+[battery_provider]
+policy = "primary"
+"#;
+      let mut config: Config = toml::from_str(contents).unwrap();
+      config.known_devices[0].auto_connect = vec![ConnectTrigger::Startup];
+      config.known_devices.push(KnownDevice {
+         address: "11:22:33:44:55:66".to_string(),
+         name: "AirPods Max".to_string(),
+         auto_connect: vec![],
+         profile: DeviceProfile {
+            noise_mode: Some(NoiseControlMode::Active),
+            ..Default::default()
+         },
+      });
+
+      let patched = patch_known_devices(contents, &config.known_devices).unwrap();
+      assert_eq!(
+         patched,
+         r#"# Managed by hand
+poll_interval = 60 # seconds
+
+[passthrough]
+enabled = true
+
+# Work
+[[known_devices]]
+address = "AA:BB:CC:DD:EE:FF"
+name = "AirPods Pro"
+auto_connect = ["startup"]
+
+[[known_devices]]
+address = "11:22:33:44:55:66"
+name = "AirPods Max"
+
+[known_devices.profile]
+noise_mode = "anc"
+
+[battery_provider]
+policy = "primary"
+"#
+      );
+      let reloaded: Config = toml::from_str(&patched).unwrap();
+      assert_eq!(reloaded, config);
+
+      let removed = patch_known_devices(contents, &[]).unwrap();
+      let reloaded: Config = toml::from_str(&removed).unwrap();
+      assert!(reloaded.known_devices.is_empty());
+      assert!(reloaded.passthrough.enabled);
+   }
+
+   #[test]
+   fn test_validate() {
+      let parse = |s: &str| toml::from_str::<Config>(s).unwrap().validate(false);
+      assert!(parse("").is_ok());
+      assert!(
+         parse(
+            r#"
+            [[known_devices]]
+            address = "not an address"
+            name = "AirPods"
+            "#
+         )
+         .is_err()
+      );
+      assert!(
+         parse(
+            r#"
+            [[known_devices]]
+            address = "AA:BB:CC:DD:EE:FF"
+            name = "AirPods"
+            profile = { transparency = { tone = 2.0 } }
+            "#
+         )
+         .is_err()
+      );
+      assert!(
+         parse(
+            r#"
+            [[rules.rule]]
+            name = "bogus"
+            on = [{ event = "device_connected" }]
+            do = [{ feature = { name = "bogus", enabled = true } }]
+            "#
+         )
+         .is_err()
+      );
+      assert!(parse("hooks = { timeout_sec = 0 }").is_err());
+      assert!(
+         parse("rules.rule = [{ name = \"full\", on = [{ battery_above = 100 }], do = [] }]")
+            .is_ok()
+      );
+      assert!(
+         parse("rules.rule = [{ name = \"over\", on = [{ battery_above = 101 }], do = [] }]")
+            .is_err()
+      );
+      assert!(
+         parse(
+            r#"
+            [[rules.rule]]
+            name = "over"
+            on = [{ event = "device_connected" }]
+            if = { battery_below = 150 }
+            do = []
+            "#
+         )
+         .is_err()
+      );
+
+      let notify: Config = toml::from_str(
+         r#"
+         [[rules.rule]]
+         name = "notify"
+         on = [{ event = "device_connected" }]
+         do = [{ notify = "Connected" }]
+         "#,
+      )
+      .unwrap();
+      assert!(notify.validate(false).is_ok());
+      assert!(notify.validate(true).is_err());
    }
 }
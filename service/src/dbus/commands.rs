@@ -0,0 +1,504 @@
+//! Registry of the actions accepted by `SendCommand`.
+//!
+//! Every action describes its parameters, their types and accepted values, and
+//! the devices supporting it. The same description validates the `a{sv}`
+//! parameters of a call and is returned by `ListCommands`, so clients do not
+//! need to hard-code action names or parameter types.
+
+use std::collections::HashMap;
+
+use serde_json::{Value, json};
+use strum::IntoEnumIterator;
+use thiserror::Error;
+use zbus::{fdo, zvariant};
+
+use crate::{
+   airpods::{
+      device::AirPods,
+      protocol::{FeatureId, KNOWN_FEATURES, NoiseControlMode, RangeError, Setting},
+   },
+   error::ServiceError,
+};
+
+/// Operation performed by a command.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Action {
+   NoiseMode,
+   Feature,
+   Transparency,
+}
+
+/// Type of a command parameter.
+#[derive(Debug, Clone, Copy)]
+pub enum ParamType {
+   Bool,
+   /// A string out of a fixed set, matched case-insensitively.
+   Choice(fn() -> Vec<&'static str>),
+   /// A double within `min..=max`.
+   Double {
+      min: f32,
+      max: f32,
+   },
+}
+
+impl ParamType {
+   /// D-Bus signature of the value.
+   pub const fn signature(self) -> &'static str {
+      match self {
+         Self::Bool => "b",
+         Self::Choice(_) => "s",
+         Self::Double { .. } => "d",
+      }
+   }
+}
+
+/// Describes a single parameter of a command.
+#[derive(Debug)]
+pub struct Param {
+   pub name: &'static str,
+   pub ty: ParamType,
+   pub required: bool,
+   pub description: &'static str,
+}
+
+/// Devices a command applies to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, strum::IntoStaticStr)]
+#[strum(serialize_all = "snake_case")]
+pub enum Support {
+   All,
+   /// Models with separate buds, i.e. not `AirPods` Max.
+   Buds,
+}
+
+impl Support {
+   /// Returns whether `device` supports the command.
+   ///
+   /// Devices whose model is not known yet are assumed to support it.
+   pub fn supports(self, device: &AirPods) -> bool {
+      match self {
+         Self::All => true,
+         Self::Buds => !device
+            .battery_info()
+            .is_some_and(|b| b.headphone.is_available()),
+      }
+   }
+}
+
+/// Describes an action of `SendCommand`.
+#[derive(Debug)]
+pub struct Command {
+   pub name: &'static str,
+   pub action: Action,
+   pub description: &'static str,
+   pub params: &'static [Param],
+   pub support: Support,
+}
+
+const fn level(name: &'static str, min: f32, max: f32, description: &'static str) -> Param {
+   Param {
+      name,
+      ty: ParamType::Double { min, max },
+      required: false,
+      description,
+   }
+}
+
+/// All commands, in the order they are listed.
+pub const COMMANDS: &[Command] = &[
+   Command {
+      name: "set_noise_mode",
+      action: Action::NoiseMode,
+      description: "Switch the noise control mode",
+      params: &[Param {
+         name: "value",
+         ty: ParamType::Choice(noise_modes),
+         required: true,
+         description: "Noise control mode",
+      }],
+      support: Support::All,
+   },
+   Command {
+      name: "set_feature",
+      action: Action::Feature,
+      description: "Enable or disable a device feature",
+      params: &[
+         Param {
+            name: "feature",
+            ty: ParamType::Choice(feature_names),
+            required: true,
+            description: "Feature name",
+         },
+         Param {
+            name: "enabled",
+            ty: ParamType::Bool,
+            required: true,
+            description: "Whether to enable the feature",
+         },
+      ],
+      support: Support::All,
+   },
+   Command {
+      name: "set_transparency",
+      action: Action::Transparency,
+      description: "Customize transparency mode; omitted parameters keep their current value",
+      params: &[
+         Param {
+            name: "enabled",
+            ty: ParamType::Bool,
+            required: false,
+            description: "Apply the customization",
+         },
+         Param {
+            name: "conversation_boost",
+            ty: ParamType::Bool,
+            required: false,
+            description: "Focus on voices in front of the wearer",
+         },
+         level("amplification", -1.0, 1.0, "Amplification"),
+         level("balance", -1.0, 1.0, "Balance between left and right"),
+         level("tone", -1.0, 1.0, "Tone, from darker to brighter"),
+         level(
+            "ambient_noise_reduction",
+            0.0,
+            1.0,
+            "Ambient noise reduction",
+         ),
+      ],
+      support: Support::Buds,
+   },
+];
+
+fn noise_modes() -> Vec<&'static str> {
+   NoiseControlMode::iter()
+      .map(NoiseControlMode::to_str)
+      .collect()
+}
+
+fn feature_names() -> Vec<&'static str> {
+   KNOWN_FEATURES.iter().map(|(_, name)| *name).collect()
+}
+
+/// Looks up a command by name.
+pub fn find(name: &str) -> Option<&'static Command> {
+   COMMANDS.iter().find(|c| c.name == name)
+}
+
+/// Key of the noise control mode in `ApplySettings`; any other key is a feature name.
+const NOISE_MODE_SETTING: Param = Param {
+   name: "noise_mode",
+   ty: ParamType::Choice(noise_modes),
+   required: false,
+   description: "Noise control mode",
+};
+
+/// Validates the entries of an `ApplySettings` call, in a stable order.
+///
+/// The noise control mode comes first, followed by the features in the order of
+/// [`KNOWN_FEATURES`].
+pub fn parse_settings(
+   settings: &HashMap<String, zvariant::Value<'_>>,
+) -> Result<Vec<Setting>, ParamError> {
+   if let Some(key) = settings
+      .keys()
+      .find(|key| *key != NOISE_MODE_SETTING.name && key.parse::<FeatureId>().is_err())
+   {
+      return Err(ParamError::Unknown(key.clone()));
+   }
+
+   let mut parsed = Vec::with_capacity(settings.len());
+   if let Some(value) = settings.get(NOISE_MODE_SETTING.name)
+      && let Arg::Str(mode) = NOISE_MODE_SETTING.parse(value)?
+   {
+      let mode = mode.parse().expect("choice of noise_modes()");
+      parsed.push(Setting::NoiseMode(mode));
+   }
+   for &(id, name) in KNOWN_FEATURES {
+      let Some((_, value)) = settings
+         .iter()
+         .find(|(key, _)| key.eq_ignore_ascii_case(name))
+      else {
+         continue;
+      };
+      let param = Param {
+         name,
+         ty: ParamType::Bool,
+         required: false,
+         description: "",
+      };
+      if let Arg::Bool(enabled) = param.parse(value)? {
+         parsed.push(Setting::Feature(FeatureId::from_id(id), enabled));
+      }
+   }
+   Ok(parsed)
+}
+
+/// Errors of the parameters of a command.
+#[derive(Error, Debug)]
+pub enum ParamError {
+   #[error("Missing '{0}' parameter")]
+   Missing(&'static str),
+
+   #[error("Unknown parameter: {0:?}")]
+   Unknown(String),
+
+   #[error("Invalid '{name}' parameter: expected type '{expected}'")]
+   Type {
+      name: &'static str,
+      expected: &'static str,
+   },
+
+   #[error("Invalid '{name}' parameter: {value:?} (expected one of {})", .allowed.join(", "))]
+   Choice {
+      name: &'static str,
+      value: String,
+      allowed: Vec<&'static str>,
+   },
+
+   #[error(transparent)]
+   OutOfRange(#[from] RangeError),
+}
+
+impl From<ParamError> for ServiceError {
+   fn from(error: ParamError) -> Self {
+      fdo::Error::InvalidArgs(error.to_string()).into()
+   }
+}
+
+/// A validated parameter value.
+#[derive(Debug, Clone, PartialEq)]
+enum Arg {
+   Bool(bool),
+   Str(&'static str),
+   Double(f64),
+}
+
+/// Parameters of a call, validated against a [`Command`].
+#[derive(Debug, Default)]
+pub struct Args(HashMap<&'static str, Arg>);
+
+impl Args {
+   /// Gets a boolean parameter, `None` if omitted.
+   pub fn bool(&self, name: &'static str) -> Result<Option<bool>, ServiceError> {
+      self.get(name, "b", |arg| match arg {
+         Arg::Bool(b) => Some(*b),
+         _ => None,
+      })
+   }
+
+   /// Gets a choice parameter in its canonical spelling, `None` if omitted.
+   pub fn choice(&self, name: &'static str) -> Result<Option<&'static str>, ServiceError> {
+      self.get(name, "s", |arg| match arg {
+         Arg::Str(s) => Some(*s),
+         _ => None,
+      })
+   }
+
+   /// Gets a double parameter, `None` if omitted.
+   pub fn double(&self, name: &'static str) -> Result<Option<f64>, ServiceError> {
+      self.get(name, "d", |arg| match arg {
+         Arg::Double(d) => Some(*d),
+         _ => None,
+      })
+   }
+
+   /// Gets a parameter, failing if the command declared it with another type.
+   fn get<T>(
+      &self,
+      name: &'static str,
+      expected: &'static str,
+      value: impl FnOnce(&Arg) -> Option<T>,
+   ) -> Result<Option<T>, ServiceError> {
+      let Some(arg) = self.0.get(name) else {
+         return Ok(None);
+      };
+      value(arg)
+         .map(Some)
+         .ok_or_else(|| ParamError::Type { name, expected }.into())
+   }
+}
+
+impl Param {
+   /// Validates the value of the parameter.
+   fn parse(&self, value: &zvariant::Value<'_>) -> Result<Arg, ParamError> {
+      let type_error = |_| ParamError::Type {
+         name: self.name,
+         expected: self.ty.signature(),
+      };
+      Ok(match self.ty {
+         ParamType::Bool => Arg::Bool(value.downcast_ref().map_err(type_error)?),
+         ParamType::Choice(values) => {
+            let value: &str = value.downcast_ref().map_err(type_error)?;
+            let allowed = values();
+            match allowed.iter().find(|v| v.eq_ignore_ascii_case(value)) {
+               Some(v) => Arg::Str(v),
+               None => {
+                  return Err(ParamError::Choice {
+                     name: self.name,
+                     value: value.to_string(),
+                     allowed,
+                  });
+               },
+            }
+         },
+         ParamType::Double { min, max } => {
+            let value: f64 = value.downcast_ref().map_err(type_error)?;
+            RangeError::check(self.name, value as f32, min, max)?;
+            Arg::Double(value)
+         },
+      })
+   }
+}
+
+impl Command {
+   /// Validates the parameters of a call.
+   pub fn parse(&self, params: &HashMap<String, zvariant::Value<'_>>) -> Result<Args, ParamError> {
+      if let Some(key) = params
+         .keys()
+         .find(|key| !self.params.iter().any(|p| p.name == key.as_str()))
+      {
+         return Err(ParamError::Unknown(key.clone()));
+      }
+
+      let mut args = Args::default();
+      for param in self.params {
+         let Some(value) = params.get(param.name) else {
+            if param.required {
+               return Err(ParamError::Missing(param.name));
+            }
+            continue;
+         };
+         let arg = param.parse(value)?;
+         args.0.insert(param.name, arg);
+      }
+      Ok(args)
+   }
+
+   /// Describes the command, listing the `devices` supporting it.
+   pub fn to_json(&self, devices: &[AirPods]) -> Value {
+      let params: Vec<Value> = self
+         .params
+         .iter()
+         .map(|p| {
+            let mut param = json!({
+               "name": p.name,
+               "type": p.ty.signature(),
+               "required": p.required,
+               "description": p.description,
+            });
+            match p.ty {
+               ParamType::Choice(values) => param["values"] = json!(values()),
+               ParamType::Double { min, max } => {
+                  param["min"] = json!(min);
+                  param["max"] = json!(max);
+               },
+               ParamType::Bool => {},
+            }
+            param
+         })
+         .collect();
+      let supported: Vec<&str> = devices
+         .iter()
+         .filter(|d| self.support.supports(d))
+         .map(|d| d.address_str().as_str())
+         .collect();
+
+      json!({
+         "name": self.name,
+         "description": self.description,
+         "params": params,
+         "support": <&str>::from(self.support),
+         "devices": supported,
+      })
+   }
+}
+
+#[cfg(test)]
+mod tests {
+   use super::*;
+
+   fn params(
+      entries: &[(&str, zvariant::Value<'static>)],
+   ) -> HashMap<String, zvariant::Value<'static>> {
+      entries
+         .iter()
+         .map(|(k, v)| (k.to_string(), v.try_clone().unwrap()))
+         .collect()
+   }
+
+   #[test]
+   fn test_parse() {
+      let noise = find("set_noise_mode").unwrap();
+      let args = noise.parse(&params(&[("value", "ANC".into())])).unwrap();
+      assert_eq!(args.choice("value").unwrap(), Some("anc"));
+      assert!(args.bool("value").is_err());
+      assert!(matches!(
+         noise.parse(&params(&[])),
+         Err(ParamError::Missing("value"))
+      ));
+      assert!(matches!(
+         noise.parse(&params(&[("value", "loud".into())])),
+         Err(ParamError::Choice { .. })
+      ));
+      assert!(matches!(
+         noise.parse(&params(&[("value", true.into())])),
+         Err(ParamError::Type { expected: "s", .. })
+      ));
+
+      let transparency = find("set_transparency").unwrap();
+      let args = transparency
+         .parse(&params(&[("tone", 0.5.into()), ("enabled", true.into())]))
+         .unwrap();
+      assert_eq!(args.double("tone").unwrap(), Some(0.5));
+      assert_eq!(args.double("balance").unwrap(), None);
+      assert_eq!(args.bool("enabled").unwrap(), Some(true));
+      assert!(matches!(
+         transparency.parse(&params(&[("ambient_noise_reduction", (-0.5).into())])),
+         Err(ParamError::OutOfRange(_))
+      ));
+      assert!(matches!(
+         transparency.parse(&params(&[("volume", 1.0.into())])),
+         Err(ParamError::Unknown(_))
+      ));
+   }
+
+   #[test]
+   fn test_parse_settings() {
+      let settings = parse_settings(&params(&[
+         ("adaptive_volume", true.into()),
+         ("Conversational", false.into()),
+         ("noise_mode", "transparency".into()),
+      ]))
+      .unwrap();
+      assert_eq!(
+         settings,
+         [
+            Setting::NoiseMode(NoiseControlMode::Transparency),
+            Setting::Feature(FeatureId::ADAPTIVE_VOLUME, true),
+            Setting::Feature(FeatureId::CONVERSATIONAL, false),
+         ]
+      );
+
+      assert!(matches!(
+         parse_settings(&params(&[("volume", true.into())])),
+         Err(ParamError::Unknown(_))
+      ));
+      assert!(matches!(
+         parse_settings(&params(&[("adaptive_volume", "on".into())])),
+         Err(ParamError::Type { expected: "b", .. })
+      ));
+   }
+
+   #[test]
+   fn test_schema() {
+      let schema = find("set_feature").unwrap().to_json(&[]);
+      assert_eq!(schema["params"][0]["type"], "s");
+      assert!(
+         schema["params"][0]["values"]
+            .as_array()
+            .unwrap()
+            .contains(&json!("adaptive_volume"))
+      );
+      assert_eq!(schema["params"][1]["type"], "b");
+      assert_eq!(schema["support"], "all");
+   }
+}
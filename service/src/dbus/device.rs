@@ -0,0 +1,427 @@
+//! Per-device D-Bus objects.
+//!
+//! Every managed `AirPods` device is exported at its own object path under
+//! `/org/kairpods/devices` with the `org.kairpods.Device1` interface, and
+//! announced through the `org.freedesktop.DBus.ObjectManager` at `/org/kairpods`.
+//! Property reads and `GetManagedObjects` are checked like the query methods.
+
+use std::collections::HashMap;
+
+use bluer::Address;
+use log::info;
+use zbus::{
+   Connection, ObjectServer, fdo, interface,
+   message::Header,
+   names::InterfaceName,
+   object_server::{Interface, SignalEmitter},
+   zvariant::{ObjectPath, OwnedObjectPath, Value},
+};
+
+use crate::{
+   airpods::{
+      device::AirPods,
+      protocol::{BatteryInfo, BatteryState, FeatureId, NoiseControlMode},
+   },
+   bluetooth::manager::BluetoothManager,
+   dbus::{
+      AirPodsService, MANAGER_PATH,
+      polkit::{Access, AccessControl},
+   },
+   error::ServiceError,
+   event::AirPodsEvent,
+};
+
+/// Path of the object manager all device objects are announced through.
+pub const OBJECT_MANAGER_PATH: &str = "/org/kairpods";
+
+/// Returns the object path of a device, e.g. `/org/kairpods/devices/AA_BB_CC_DD_EE_FF`.
+pub fn device_path(address: Address) -> OwnedObjectPath {
+   let path = format!(
+      "{OBJECT_MANAGER_PATH}/devices/{}",
+      address.to_string().replace(':', "_")
+   );
+   ObjectPath::try_from(path)
+      .expect("address is a valid path element")
+      .into()
+}
+
+/// D-Bus object exposing a single device with typed properties.
+pub struct DeviceObject {
+   device: AirPods,
+   access: AccessControl,
+}
+
+impl DeviceObject {
+   pub const fn new(device: AirPods, access: AccessControl) -> Self {
+      Self { device, access }
+   }
+
+   fn battery(&self, f: impl FnOnce(&BatteryInfo) -> BatteryState) -> BatteryState {
+      self
+         .device
+         .battery_info()
+         .map(|b| f(&b))
+         .unwrap_or_default()
+   }
+
+   async fn notify_manager(server: &ObjectServer) -> zbus::Result<()> {
+      let iface = server.interface::<_, AirPodsService>(MANAGER_PATH).await?;
+      iface
+         .get()
+         .await
+         .devices_changed(iface.signal_emitter())
+         .await
+   }
+}
+
+#[interface(name = "org.kairpods.Device1")]
+impl DeviceObject {
+   async fn set_noise_mode(
+      &self,
+      mode: String,
+      #[zbus(header)] header: Header<'_>,
+      #[zbus(object_server)] server: &ObjectServer,
+      #[zbus(signal_emitter)] emitter: SignalEmitter<'_>,
+   ) -> Result<(), ServiceError> {
+      self.access.check(&header, Access::Control).await?;
+      let mode: NoiseControlMode = mode
+         .parse()
+         .map_err(|_| fdo::Error::InvalidArgs(format!("Invalid noise mode: {mode:?}")))?;
+      self.device.set_noise_control(mode).await?;
+      info!("Set noise mode to {mode} for {}", self.device.address());
+
+      self.noise_mode_changed(&emitter).await?;
+      Self::notify_manager(server).await?;
+      Ok(())
+   }
+
+   async fn set_feature(
+      &self,
+      feature: String,
+      enabled: bool,
+      #[zbus(header)] header: Header<'_>,
+      #[zbus(object_server)] server: &ObjectServer,
+      #[zbus(signal_emitter)] emitter: SignalEmitter<'_>,
+   ) -> Result<(), ServiceError> {
+      self.access.check(&header, Access::Control).await?;
+      let feature: FeatureId = feature
+         .parse()
+         .map_err(|_| fdo::Error::InvalidArgs(format!("Invalid feature: {feature:?}")))?;
+      self.device.set_feature(feature, enabled).await?;
+      info!(
+         "Set feature {feature} to {enabled} for {}",
+         self.device.address()
+      );
+
+      self.features_changed(&emitter).await?;
+      Self::notify_manager(server).await?;
+      Ok(())
+   }
+
+   #[zbus(property)]
+   async fn address(&self, #[zbus(header)] header: Option<Header<'_>>) -> fdo::Result<String> {
+      self.access.check_read(header.as_ref()).await?;
+      Ok(self.device.address_str().to_string())
+   }
+
+   #[zbus(property)]
+   async fn name(&self, #[zbus(header)] header: Option<Header<'_>>) -> fdo::Result<String> {
+      self.access.check_read(header.as_ref()).await?;
+      Ok(self.device.name().to_string())
+   }
+
+   #[zbus(property)]
+   async fn connected(&self, #[zbus(header)] header: Option<Header<'_>>) -> fdo::Result<bool> {
+      self.access.check_read(header.as_ref()).await?;
+      Ok(self.device.is_connected())
+   }
+
+   /// Battery level in percent, 0 when the component is not reported.
+   #[zbus(property)]
+   async fn battery_left(&self, #[zbus(header)] header: Option<Header<'_>>) -> fdo::Result<u8> {
+      self.access.check_read(header.as_ref()).await?;
+      Ok(self.battery(|b| b.left).level)
+   }
+
+   #[zbus(property)]
+   async fn battery_right(&self, #[zbus(header)] header: Option<Header<'_>>) -> fdo::Result<u8> {
+      self.access.check_read(header.as_ref()).await?;
+      Ok(self.battery(|b| b.right).level)
+   }
+
+   #[zbus(property)]
+   async fn battery_case(&self, #[zbus(header)] header: Option<Header<'_>>) -> fdo::Result<u8> {
+      self.access.check_read(header.as_ref()).await?;
+      Ok(self.battery(|b| b.case).level)
+   }
+
+   #[zbus(property)]
+   async fn battery_headphone(
+      &self,
+      #[zbus(header)] header: Option<Header<'_>>,
+   ) -> fdo::Result<u8> {
+      self.access.check_read(header.as_ref()).await?;
+      Ok(self.battery(|b| b.headphone).level)
+   }
+
+   #[zbus(property)]
+   async fn charging_left(&self, #[zbus(header)] header: Option<Header<'_>>) -> fdo::Result<bool> {
+      self.access.check_read(header.as_ref()).await?;
+      Ok(self.battery(|b| b.left).is_charging())
+   }
+
+   #[zbus(property)]
+   async fn charging_right(&self, #[zbus(header)] header: Option<Header<'_>>) -> fdo::Result<bool> {
+      self.access.check_read(header.as_ref()).await?;
+      Ok(self.battery(|b| b.right).is_charging())
+   }
+
+   #[zbus(property)]
+   async fn charging_case(&self, #[zbus(header)] header: Option<Header<'_>>) -> fdo::Result<bool> {
+      self.access.check_read(header.as_ref()).await?;
+      Ok(self.battery(|b| b.case).is_charging())
+   }
+
+   #[zbus(property)]
+   async fn charging_headphone(
+      &self,
+      #[zbus(header)] header: Option<Header<'_>>,
+   ) -> fdo::Result<bool> {
+      self.access.check_read(header.as_ref()).await?;
+      Ok(self.battery(|b| b.headphone).is_charging())
+   }
+
+   /// Noise control mode, empty when not reported yet.
+   #[zbus(property)]
+   async fn noise_mode(&self, #[zbus(header)] header: Option<Header<'_>>) -> fdo::Result<String> {
+      self.access.check_read(header.as_ref()).await?;
+      Ok(self
+         .device
+         .noise_mode()
+         .map(|m| m.to_str().to_string())
+         .unwrap_or_default())
+   }
+
+   #[zbus(property)]
+   async fn in_ear_left(&self, #[zbus(header)] header: Option<Header<'_>>) -> fdo::Result<bool> {
+      self.access.check_read(header.as_ref()).await?;
+      Ok(self
+         .device
+         .ear_detection()
+         .is_some_and(|e| e.is_left_in_ear()))
+   }
+
+   #[zbus(property)]
+   async fn in_ear_right(&self, #[zbus(header)] header: Option<Header<'_>>) -> fdo::Result<bool> {
+      self.access.check_read(header.as_ref()).await?;
+      Ok(self
+         .device
+         .ear_detection()
+         .is_some_and(|e| e.is_right_in_ear()))
+   }
+
+   /// Bud holding the host link and the microphone, empty when unknown.
+   #[zbus(property)]
+   async fn primary_bud(&self, #[zbus(header)] header: Option<Header<'_>>) -> fdo::Result<String> {
+      self.access.check_read(header.as_ref()).await?;
+      Ok(self
+         .device
+         .primary_bud()
+         .map(|c| c.to_str().to_string())
+         .unwrap_or_default())
+   }
+
+   /// State of the AAP connection, e.g. `connected` or `waiting_to_reconnect`.
+   #[zbus(property)]
+   async fn connection_state(
+      &self,
+      #[zbus(header)] header: Option<Header<'_>>,
+   ) -> fdo::Result<String> {
+      self.access.check_read(header.as_ref()).await?;
+      Ok(self.device.connection_info().state.to_str().to_string())
+   }
+
+   /// Failed connection attempts since the last successful one.
+   #[zbus(property)]
+   async fn retry_count(&self, #[zbus(header)] header: Option<Header<'_>>) -> fdo::Result<u32> {
+      self.access.check_read(header.as_ref()).await?;
+      Ok(self.device.connection_info().retry_count)
+   }
+
+   /// Last connection error, empty when none.
+   #[zbus(property)]
+   async fn last_error(&self, #[zbus(header)] header: Option<Header<'_>>) -> fdo::Result<String> {
+      self.access.check_read(header.as_ref()).await?;
+      Ok(self.device.connection_info().last_error.unwrap_or_default())
+   }
+
+   /// Unix timestamp of the next reconnect attempt, 0 when none is scheduled.
+   #[zbus(property)]
+   async fn next_retry(&self, #[zbus(header)] header: Option<Header<'_>>) -> fdo::Result<u64> {
+      self.access.check_read(header.as_ref()).await?;
+      Ok(self.device.connection_info().next_retry.unwrap_or_default())
+   }
+
+   /// Name of the Bluetooth adapter the device is reached through.
+   #[zbus(property)]
+   async fn adapter(&self, #[zbus(header)] header: Option<Header<'_>>) -> fdo::Result<String> {
+      self.access.check_read(header.as_ref()).await?;
+      Ok(self.device.connection_info().adapter.to_string())
+   }
+
+   #[zbus(property)]
+   async fn features(
+      &self,
+      #[zbus(header)] header: Option<Header<'_>>,
+   ) -> fdo::Result<HashMap<String, bool>> {
+      self.access.check_read(header.as_ref()).await?;
+      Ok(self
+         .device
+         .features()
+         .into_iter()
+         .map(|(k, v)| (k.to_str().to_string(), v))
+         .collect())
+   }
+}
+
+/// Object manager announcing the device objects.
+///
+/// Stands in for `fdo::ObjectManager`, which answers `GetManagedObjects`
+/// without knowing about polkit. zbus still emits `InterfacesAdded` and
+/// `InterfacesRemoved` for it, as it goes by the interface name.
+pub struct DeviceObjectManager {
+   bluetooth_manager: BluetoothManager,
+   access: AccessControl,
+}
+
+impl DeviceObjectManager {
+   pub const fn new(bluetooth_manager: BluetoothManager, access: AccessControl) -> Self {
+      Self {
+         bluetooth_manager,
+         access,
+      }
+   }
+}
+
+#[interface(name = "org.freedesktop.DBus.ObjectManager")]
+impl DeviceObjectManager {
+   async fn get_managed_objects(
+      &self,
+      #[zbus(header)] header: Header<'_>,
+      #[zbus(object_server)] server: &ObjectServer,
+      #[zbus(connection)] connection: &Connection,
+   ) -> Result<fdo::ManagedObjects, ServiceError> {
+      self.access.check(&header, Access::Read).await?;
+      let mut objects = fdo::ManagedObjects::new();
+      for device in self.bluetooth_manager.all_devices().await {
+         let path = device_path(device.address());
+         // The object is registered a moment after the manager picks the device up
+         let Ok(iface) = server.interface::<_, DeviceObject>(&path).await else {
+            continue;
+         };
+         let properties = iface
+            .get()
+            .await
+            .get_all(server, connection, None, iface.signal_emitter())
+            .await?;
+         objects.insert(
+            path,
+            HashMap::from([(<DeviceObject as Interface>::name().into(), properties)]),
+         );
+      }
+      Ok(objects)
+   }
+
+   #[zbus(signal)]
+   async fn interfaces_added(
+      emitter: &SignalEmitter<'_>,
+      object_path: ObjectPath<'_>,
+      interfaces_and_properties: HashMap<InterfaceName<'_>, HashMap<&str, Value<'_>>>,
+   ) -> zbus::Result<()>;
+
+   #[zbus(signal)]
+   async fn interfaces_removed(
+      emitter: &SignalEmitter<'_>,
+      object_path: ObjectPath<'_>,
+      interfaces: Vec<InterfaceName<'_>>,
+   ) -> zbus::Result<()>;
+}
+
+/// Registers, unregisters or refreshes the device object an event refers to.
+pub async fn dispatch(
+   server: &ObjectServer,
+   device: &AirPods,
+   event: &AirPodsEvent,
+) -> zbus::Result<()> {
+   let path = device_path(device.address());
+   match event {
+      AirPodsEvent::DeviceAdded => {
+         let access = server
+            .interface::<_, AirPodsService>(MANAGER_PATH)
+            .await?
+            .get()
+            .await
+            .access()
+            .clone();
+         server
+            .at(&path, DeviceObject::new(device.clone(), access))
+            .await?;
+         return Ok(());
+      },
+      AirPodsEvent::DeviceRemoved => {
+         server.remove::<DeviceObject, _>(&path).await?;
+         return Ok(());
+      },
+      _ => {},
+   }
+
+   // Events may race with the object being removed, nothing to refresh then
+   let Ok(iface) = server.interface::<_, DeviceObject>(&path).await else {
+      return Ok(());
+   };
+   let obj = iface.get().await;
+   let emitter = iface.signal_emitter();
+   match event {
+      AirPodsEvent::DeviceConnected | AirPodsEvent::DeviceDisconnected => {
+         obj.connected_changed(emitter).await?;
+      },
+      AirPodsEvent::BatteryUpdated(_) => {
+         obj.battery_left_changed(emitter).await?;
+         obj.battery_right_changed(emitter).await?;
+         obj.battery_case_changed(emitter).await?;
+         obj.battery_headphone_changed(emitter).await?;
+         obj.charging_left_changed(emitter).await?;
+         obj.charging_right_changed(emitter).await?;
+         obj.charging_case_changed(emitter).await?;
+         obj.charging_headphone_changed(emitter).await?;
+      },
+      AirPodsEvent::NoiseControlChanged(_) => {
+         obj.noise_mode_changed(emitter).await?;
+      },
+      AirPodsEvent::FeatureChanged(..) | AirPodsEvent::ProfileApplied(_) => {
+         obj.features_changed(emitter).await?;
+      },
+      AirPodsEvent::EarDetectionChanged(_) => {
+         obj.in_ear_left_changed(emitter).await?;
+         obj.in_ear_right_changed(emitter).await?;
+      },
+      AirPodsEvent::DeviceNameChanged(_) => {
+         obj.name_changed(emitter).await?;
+      },
+      AirPodsEvent::PrimaryBudChanged(_) => {
+         obj.primary_bud_changed(emitter).await?;
+      },
+      AirPodsEvent::ConnectionStateChanged(_) => {
+         obj.connection_state_changed(emitter).await?;
+         obj.retry_count_changed(emitter).await?;
+         obj.last_error_changed(emitter).await?;
+         obj.next_retry_changed(emitter).await?;
+         obj.adapter_changed(emitter).await?;
+      },
+      AirPodsEvent::DeviceAdded
+      | AirPodsEvent::DeviceRemoved
+      | AirPodsEvent::DeviceError
+      | AirPodsEvent::TransparencyChanged(_) => {},
+   }
+   Ok(())
+}
@@ -0,0 +1,584 @@
+use std::{collections::HashMap, fmt, str::FromStr};
+
+use bluer::Address;
+use log::info;
+use zbus::{Connection, fdo, interface, message::Header, object_server::SignalEmitter, zvariant};
+
+use crate::{
+   airpods::{
+      device::AirPods,
+      hearing::{Audiogram, HearingAidProfile},
+      protocol::{FeatureId, NoiseControlMode},
+   },
+   bluetooth::{manager::BluetoothManager, pairing::Candidate},
+   config::{Config, DeviceProfile, KnownDevice},
+   dbus::{
+      commands::{Action, ParamError},
+      passthrough::{Outcome, PassthroughGate},
+      polkit::{Access, AccessControl},
+   },
+   error::{AirPodsError, ServiceError},
+};
+
+pub mod commands;
+pub mod device;
+pub mod passthrough;
+pub mod polkit;
+pub mod tray;
+
+/// Path of the manager object.
+pub const MANAGER_PATH: &str = "/org/kairpods/manager";
+
+pub struct AirPodsService {
+   bluetooth_manager: BluetoothManager,
+   passthrough: PassthroughGate,
+   access: AccessControl,
+}
+
+impl AirPodsService {
+   pub const fn new(
+      bluetooth_manager: BluetoothManager,
+      passthrough: PassthroughGate,
+      access: AccessControl,
+   ) -> Self {
+      Self {
+         bluetooth_manager,
+         passthrough,
+         access,
+      }
+   }
+
+   /// Gets the access control applied to the method calls.
+   pub const fn access(&self) -> &AccessControl {
+      &self.access
+   }
+
+   /// Managed devices, followed by the devices that are not around: the ones
+   /// seen before with their last known state, then the other known devices.
+   async fn devices_json(&self) -> String {
+      let devices = self.bluetooth_manager.all_devices().await;
+      let mut known = self.bluetooth_manager.known_devices().await;
+      let is_present = |address: &str| {
+         devices
+            .iter()
+            .any(|d| d.address_str().eq_ignore_ascii_case(address))
+      };
+
+      let mut states: Vec<serde_json::Value> = devices.iter().map(AirPods::to_json).collect();
+      for (addr, state) in self.bluetooth_manager.device_states().await {
+         let address = addr.to_string();
+         if is_present(&address) {
+            continue;
+         }
+         let mut info = state.to_json(addr);
+         let position = known
+            .iter()
+            .position(|d| d.address.eq_ignore_ascii_case(&address));
+         info["known"] = position.is_some().into();
+         if let Some(position) = position {
+            info["name"] = known.remove(position).name.into();
+         }
+         states.push(info);
+      }
+      for device in known.iter().filter(|d| !is_present(&d.address)) {
+         states.push(serde_json::json!({
+            "address": device.address,
+            "name": device.name,
+            "connected": false,
+            "known": true,
+         }));
+      }
+      serde_json::to_string(&states).unwrap()
+   }
+
+   async fn notify_known_devices_changed(&self, emitter: &SignalEmitter<'_>) -> zbus::Result<()> {
+      Self::known_devices_changed(emitter).await?;
+      self.devices_changed(emitter).await
+   }
+
+   /// Applies a reloaded configuration and announces it to the clients.
+   pub async fn apply_config(
+      &mut self,
+      config: &Config,
+      emitter: &SignalEmitter<'_>,
+   ) -> zbus::Result<()> {
+      self.passthrough.reconfigure(&config.passthrough);
+      Self::config_reloaded(emitter).await?;
+      self.notify_known_devices_changed(emitter).await
+   }
+}
+
+fn to_arg_error<T: fmt::Display>(e: T) -> ServiceError {
+   fdo::Error::InvalidArgs(e.to_string()).into()
+}
+
+#[interface(name = "org.kairpods.manager")]
+impl AirPodsService {
+   async fn get_devices(&self, #[zbus(header)] header: Header<'_>) -> Result<String, ServiceError> {
+      self.access.check(&header, Access::Read).await?;
+      Ok(self.devices_json().await)
+   }
+
+   async fn get_device(
+      &self,
+      address: String,
+      #[zbus(header)] header: Header<'_>,
+   ) -> Result<String, ServiceError> {
+      self.access.check(&header, Access::Read).await?;
+      let addr = Address::from_str(&address).map_err(to_arg_error)?;
+      let dev = self.bluetooth_manager.get_device(addr).await?;
+      Ok(dev.to_json().to_string())
+   }
+
+   async fn passthrough(
+      &self,
+      address: String,
+      packet: String,
+      #[zbus(connection)] connection: &Connection,
+      #[zbus(header)] header: Header<'_>,
+   ) -> Result<bool, ServiceError> {
+      self.access.check(&header, Access::Control).await?;
+      let result = async {
+         let bytes = self.passthrough.check(&packet)?;
+         let addr = Address::from_str(&address).map_err(|e| (Outcome::Invalid, to_arg_error(e)))?;
+         let failed = |e: AirPodsError| (Outcome::Failed, e.into());
+         let dev = self
+            .bluetooth_manager
+            .get_device(addr)
+            .await
+            .map_err(failed)?;
+         dev.passthrough(&bytes).await.map_err(failed)
+      }
+      .await;
+
+      let outcome = result
+         .as_ref()
+         .map_or_else(|(outcome, _)| *outcome, |()| Outcome::Sent);
+      self
+         .passthrough
+         .record(connection, &header, &address, &packet, outcome)
+         .await;
+      result.map_err(|(_, e)| e)?;
+      Ok(true)
+   }
+
+   /// Returns the recorded passthrough attempts as a JSON array, oldest first.
+   async fn get_passthrough_log(
+      &self,
+      #[zbus(header)] header: Header<'_>,
+   ) -> Result<String, ServiceError> {
+      self.access.check(&header, Access::Control).await?;
+      let entries: Vec<serde_json::Value> = self
+         .passthrough
+         .entries()
+         .iter()
+         .map(|e| e.to_json())
+         .collect();
+      Ok(serde_json::Value::from(entries).to_string())
+   }
+
+   /// Describes the actions accepted by `SendCommand` as a JSON array.
+   async fn list_commands(
+      &self,
+      #[zbus(header)] header: Header<'_>,
+   ) -> Result<String, ServiceError> {
+      self.access.check(&header, Access::Read).await?;
+      let devices = self.bluetooth_manager.all_devices().await;
+      let commands: Vec<serde_json::Value> = commands::COMMANDS
+         .iter()
+         .map(|c| c.to_json(&devices))
+         .collect();
+      Ok(serde_json::Value::from(commands).to_string())
+   }
+
+   async fn send_command(
+      &self,
+      address: String,
+      action: String,
+      params: HashMap<String, zvariant::Value<'_>>,
+      #[zbus(signal_emitter)] emitter: SignalEmitter<'_>,
+      #[zbus(header)] header: Header<'_>,
+   ) -> Result<bool, ServiceError> {
+      self.access.check(&header, Access::Control).await?;
+      let addr = Address::from_str(&address).map_err(to_arg_error)?;
+      let command = commands::find(&action)
+         .ok_or_else(|| to_arg_error(format_args!("Unknown action: {action}")))?;
+      let args = command.parse(&params).map_err(to_arg_error)?;
+
+      let dev = self.bluetooth_manager.get_device(addr).await?;
+      if !command.support.supports(&dev) {
+         return Err(AirPodsError::FeatureNotSupported(action).into());
+      }
+
+      match command.action {
+         Action::NoiseMode => {
+            let mode: NoiseControlMode = args
+               .choice("value")?
+               .ok_or(ParamError::Missing("value"))?
+               .parse()
+               .map_err(to_arg_error)?;
+
+            dev.set_noise_control(mode).await?;
+
+            info!("Set noise mode to {mode} for {address}");
+         },
+
+         Action::Feature => {
+            let feature: FeatureId = args
+               .choice("feature")?
+               .ok_or(ParamError::Missing("feature"))?
+               .parse()
+               .map_err(to_arg_error)?;
+            let enabled = args
+               .bool("enabled")?
+               .ok_or(ParamError::Missing("enabled"))?;
+
+            dev.set_feature(feature, enabled).await?;
+            info!("Set feature {feature} to {enabled} for {address}");
+         },
+
+         Action::Transparency => {
+            let mut settings = dev.transparency_settings().unwrap_or_default();
+            if let Some(enabled) = args.bool("enabled")? {
+               settings.enabled = enabled;
+            }
+            if let Some(boost) = args.bool("conversation_boost")? {
+               settings.conversation_boost = boost;
+            }
+            for (name, value) in [
+               ("amplification", &mut settings.amplification),
+               ("balance", &mut settings.balance),
+               ("tone", &mut settings.tone),
+               (
+                  "ambient_noise_reduction",
+                  &mut settings.ambient_noise_reduction,
+               ),
+            ] {
+               if let Some(level) = args.double(name)? {
+                  *value = level as f32;
+               }
+            }
+
+            dev.set_transparency(settings).await?;
+            info!("Set transparency settings to {settings:?} for {address}");
+         },
+      }
+
+      // Emit property change immediately so UI updates
+      self.devices_changed(&emitter).await?;
+      Ok(true)
+   }
+
+   /// Applies several settings at once, e.g. `noise_mode` and feature toggles.
+   ///
+   /// Every entry is validated before anything is sent. If the device does not
+   /// confirm a setting, the ones applied before it are rolled back.
+   async fn apply_settings(
+      &self,
+      address: String,
+      settings: HashMap<String, zvariant::Value<'_>>,
+      #[zbus(signal_emitter)] emitter: SignalEmitter<'_>,
+      #[zbus(header)] header: Header<'_>,
+   ) -> Result<bool, ServiceError> {
+      self.access.check(&header, Access::Control).await?;
+      let addr = Address::from_str(&address).map_err(to_arg_error)?;
+      let settings = commands::parse_settings(&settings).map_err(to_arg_error)?;
+      if settings.is_empty() {
+         return Ok(true);
+      }
+
+      let dev = self.bluetooth_manager.get_device(addr).await?;
+      let result = dev.apply_settings(&settings).await;
+
+      // Notify once, also after a rollback since it may have been partial
+      self.devices_changed(&emitter).await?;
+      result?;
+      info!("Applied {settings:?} for {address}");
+      Ok(true)
+   }
+
+   #[allow(clippy::too_many_arguments)]
+   async fn set_hearing_aid_profile(
+      &self,
+      address: String,
+      format: String,
+      audiogram: String,
+      gain: f64,
+      tone: f64,
+      #[zbus(signal_emitter)] emitter: SignalEmitter<'_>,
+      #[zbus(header)] header: Header<'_>,
+   ) -> Result<bool, ServiceError> {
+      self.access.check(&header, Access::Control).await?;
+      let addr = Address::from_str(&address).map_err(to_arg_error)?;
+      let audiogram = Audiogram::import(&format, &audiogram).map_err(to_arg_error)?;
+      let profile = HearingAidProfile {
+         audiogram,
+         gain: gain as f32,
+         tone: tone as f32,
+      };
+      profile.validate().map_err(to_arg_error)?;
+
+      let dev = self.bluetooth_manager.get_device(addr).await?;
+      dev.set_hearing_aid_profile(profile).await?;
+      info!("Applied hearing aid profile for {address}");
+
+      self.devices_changed(&emitter).await?;
+      Ok(true)
+   }
+
+   /// Returns the hearing-aid profile as JSON, or `null` if it is not known.
+   ///
+   /// The profile cannot be requested from the buds: it is only known once they
+   /// report it on their own or after `SetHearingAidProfile`, so a restarted
+   /// daemon may return `null` although the buds hold a profile.
+   async fn get_hearing_aid_profile(
+      &self,
+      address: String,
+      #[zbus(header)] header: Header<'_>,
+   ) -> Result<String, ServiceError> {
+      self.access.check(&header, Access::Read).await?;
+      let addr = Address::from_str(&address).map_err(to_arg_error)?;
+      let dev = self.bluetooth_manager.get_device(addr).await?;
+      Ok(dev
+         .hearing_aid_profile()
+         .map_or(serde_json::Value::Null, |p| p.to_json())
+         .to_string())
+   }
+
+   async fn run_fit_test(
+      &self,
+      address: String,
+      #[zbus(header)] header: Header<'_>,
+   ) -> Result<HashMap<String, String>, ServiceError> {
+      self.access.check(&header, Access::Control).await?;
+      let addr = Address::from_str(&address).map_err(to_arg_error)?;
+      let dev = self.bluetooth_manager.get_device(addr).await?;
+      let result = dev.run_fit_test().await?;
+      Ok(result
+         .into_iter()
+         .map(|(bud, fit)| (bud.to_str().to_string(), fit.to_str().to_string()))
+         .collect())
+   }
+
+   async fn connect_device(
+      &self,
+      address: String,
+      #[zbus(header)] header: Header<'_>,
+   ) -> Result<bool, ServiceError> {
+      self.access.check(&header, Access::Control).await?;
+      let addr = Address::from_str(&address).map_err(to_arg_error)?;
+      self.bluetooth_manager.connect(addr).await?;
+      Ok(true)
+   }
+
+   async fn start_pairing(&self, #[zbus(header)] header: Header<'_>) -> Result<bool, ServiceError> {
+      self.access.check(&header, Access::Control).await?;
+      self.bluetooth_manager.start_pairing().await?;
+      Ok(true)
+   }
+
+   async fn stop_pairing(&self, #[zbus(header)] header: Header<'_>) -> Result<bool, ServiceError> {
+      self.access.check(&header, Access::Control).await?;
+      Ok(self.bluetooth_manager.stop_pairing().await)
+   }
+
+   async fn get_pairing_candidates(
+      &self,
+      #[zbus(header)] header: Header<'_>,
+   ) -> Result<String, ServiceError> {
+      self.access.check(&header, Access::Read).await?;
+      let (discovering, candidates) = self.bluetooth_manager.pairing_candidates().await.ok_or(
+         AirPodsError::PreconditionFailed("Pairing discovery not started"),
+      )?;
+      let candidates: Vec<_> = candidates.iter().map(Candidate::to_json).collect();
+      Ok(serde_json::json!({
+         "discovering": discovering,
+         "candidates": candidates,
+      })
+      .to_string())
+   }
+
+   async fn pair_device(
+      &self,
+      address: String,
+      #[zbus(header)] header: Header<'_>,
+      #[zbus(signal_emitter)] emitter: SignalEmitter<'_>,
+   ) -> Result<bool, ServiceError> {
+      self.access.check(&header, Access::Control).await?;
+      let addr = Address::from_str(&address).map_err(to_arg_error)?;
+      self.bluetooth_manager.pair(addr).await?;
+      self.notify_known_devices_changed(&emitter).await?;
+      Ok(true)
+   }
+
+   async fn get_known_devices(
+      &self,
+      #[zbus(header)] header: Header<'_>,
+   ) -> Result<String, ServiceError> {
+      self.access.check(&header, Access::Read).await?;
+      let known = self.bluetooth_manager.known_devices().await;
+      Ok(serde_json::to_string(&known).unwrap())
+   }
+
+   async fn add_known_device(
+      &self,
+      address: String,
+      name: String,
+      #[zbus(header)] header: Header<'_>,
+      #[zbus(signal_emitter)] emitter: SignalEmitter<'_>,
+   ) -> Result<bool, ServiceError> {
+      self.access.check(&header, Access::Control).await?;
+      let addr = Address::from_str(&address).map_err(to_arg_error)?;
+      self
+         .bluetooth_manager
+         .add_known_device(KnownDevice {
+            address: addr.to_string(),
+            name,
+            auto_connect: Vec::new(),
+            profile: DeviceProfile::default(),
+         })
+         .await?;
+      self.notify_known_devices_changed(&emitter).await?;
+      Ok(true)
+   }
+
+   async fn rename_known_device(
+      &self,
+      address: String,
+      name: String,
+      #[zbus(header)] header: Header<'_>,
+      #[zbus(signal_emitter)] emitter: SignalEmitter<'_>,
+   ) -> Result<bool, ServiceError> {
+      self.access.check(&header, Access::Control).await?;
+      let addr = Address::from_str(&address).map_err(to_arg_error)?;
+      self
+         .bluetooth_manager
+         .rename_known_device(addr, name)
+         .await?;
+      self.notify_known_devices_changed(&emitter).await?;
+      Ok(true)
+   }
+
+   /// Forgets a device, also unpairing it from `BlueZ` if `unpair` is set.
+   async fn remove_known_device(
+      &self,
+      address: String,
+      unpair: bool,
+      #[zbus(header)] header: Header<'_>,
+      #[zbus(signal_emitter)] emitter: SignalEmitter<'_>,
+   ) -> Result<bool, ServiceError> {
+      self.access.check(&header, Access::Control).await?;
+      let addr = Address::from_str(&address).map_err(to_arg_error)?;
+      self
+         .bluetooth_manager
+         .remove_known_device(addr, unpair)
+         .await?;
+      self.notify_known_devices_changed(&emitter).await?;
+      Ok(true)
+   }
+
+   async fn disconnect_device(
+      &self,
+      address: String,
+      #[zbus(header)] header: Header<'_>,
+   ) -> Result<bool, ServiceError> {
+      self.access.check(&header, Access::Control).await?;
+      let addr = Address::from_str(&address).map_err(to_arg_error)?;
+      self.bluetooth_manager.disconnect_aap(addr).await?;
+      Ok(true)
+   }
+
+   // Signals
+   #[zbus(signal)]
+   pub async fn device_connected(emitter: &SignalEmitter<'_>, address: &str) -> zbus::Result<()>;
+
+   #[zbus(signal)]
+   pub async fn device_disconnected(emitter: &SignalEmitter<'_>, address: &str)
+   -> zbus::Result<()>;
+
+   #[zbus(signal)]
+   pub async fn battery_updated(
+      emitter: &SignalEmitter<'_>,
+      address: &str,
+      battery: &str,
+   ) -> zbus::Result<()>;
+
+   #[zbus(signal)]
+   pub async fn noise_control_changed(
+      emitter: &SignalEmitter<'_>,
+      address: &str,
+      mode: &str,
+   ) -> zbus::Result<()>;
+
+   #[zbus(signal)]
+   pub async fn feature_changed(
+      emitter: &SignalEmitter<'_>,
+      address: &str,
+      feature: &str,
+      enabled: bool,
+   ) -> zbus::Result<()>;
+
+   #[zbus(signal)]
+   pub async fn ear_detection_changed(
+      emitter: &SignalEmitter<'_>,
+      address: &str,
+      ear_detection: &str,
+   ) -> zbus::Result<()>;
+
+   #[zbus(signal)]
+   pub async fn device_name_changed(
+      emitter: &SignalEmitter<'_>,
+      address: &str,
+      name: &str,
+   ) -> zbus::Result<()>;
+
+   #[zbus(signal)]
+   pub async fn primary_bud_changed(
+      emitter: &SignalEmitter<'_>,
+      address: &str,
+      primary: &str,
+   ) -> zbus::Result<()>;
+
+   #[zbus(signal)]
+   pub async fn transparency_changed(
+      emitter: &SignalEmitter<'_>,
+      address: &str,
+      settings: &str,
+   ) -> zbus::Result<()>;
+
+   #[zbus(signal)]
+   pub async fn connection_state_changed(
+      emitter: &SignalEmitter<'_>,
+      address: &str,
+      connection: &str,
+   ) -> zbus::Result<()>;
+
+   #[zbus(signal)]
+   pub async fn profile_applied(
+      emitter: &SignalEmitter<'_>,
+      address: &str,
+      report: &str,
+   ) -> zbus::Result<()>;
+
+   #[zbus(signal)]
+   pub async fn known_devices_changed(emitter: &SignalEmitter<'_>) -> zbus::Result<()>;
+
+   #[zbus(signal)]
+   pub async fn config_reloaded(emitter: &SignalEmitter<'_>) -> zbus::Result<()>;
+
+   #[zbus(signal)]
+   pub async fn device_error(emitter: &SignalEmitter<'_>, address: &str) -> zbus::Result<()>;
+
+   // Properties for polling-free updates
+   #[zbus(property)]
+   async fn devices(&self, #[zbus(header)] header: Option<Header<'_>>) -> fdo::Result<String> {
+      self.access.check_read(header.as_ref()).await?;
+      Ok(self.devices_json().await)
+   }
+
+   #[zbus(property)]
+   async fn connected_count(&self, #[zbus(header)] header: Option<Header<'_>>) -> fdo::Result<u32> {
+      self.access.check_read(header.as_ref()).await?;
+      Ok(self.bluetooth_manager.count_devices().await)
+   }
+}
@@ -0,0 +1,194 @@
+//! Access control and audit log for raw passthrough packets.
+//!
+//! Passthrough sends arbitrary bytes to the device, so it is disabled unless
+//! enabled in the configuration, and can be restricted to packets starting
+//! with one of a list of prefixes. Every attempt is recorded along with the
+//! calling process, including packets that are not valid hex.
+
+use std::{
+   collections::VecDeque,
+   time::{SystemTime, UNIX_EPOCH},
+};
+
+use log::{info, warn};
+use serde_json::{Value, json};
+use zbus::{Connection, fdo, message::Header, names::BusName};
+
+use crate::{config::PassthroughConfig, error::ServiceError};
+
+/// Number of audit entries kept in memory.
+const AUDIT_LOG_CAPACITY: usize = 256;
+
+/// Outcome of a passthrough attempt.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, strum::IntoStaticStr)]
+#[strum(serialize_all = "snake_case")]
+pub enum Outcome {
+   Sent,
+   Denied,
+   /// The packet or address could not be parsed.
+   Invalid,
+   Failed,
+}
+
+/// A single passthrough attempt.
+#[derive(Debug, Clone)]
+pub struct AuditEntry {
+   pub timestamp: u64, // Unix timestamp
+   pub sender: String,
+   pub pid: Option<u32>,
+   pub address: String,
+   /// Packet in hex, as given by the caller.
+   pub packet: String,
+   pub outcome: Outcome,
+}
+
+impl AuditEntry {
+   pub fn to_json(&self) -> Value {
+      json!({
+         "timestamp": self.timestamp,
+         "sender": self.sender,
+         "pid": self.pid,
+         "address": self.address,
+         "packet": self.packet,
+         "outcome": <&str>::from(self.outcome),
+      })
+   }
+}
+
+/// Decides which passthrough packets are allowed and records every attempt.
+pub struct PassthroughGate {
+   enabled: bool,
+   allowed_prefixes: Vec<Vec<u8>>,
+   log: parking_lot::Mutex<VecDeque<AuditEntry>>,
+}
+
+impl PassthroughGate {
+   pub fn new(config: &PassthroughConfig) -> Self {
+      let allowed_prefixes = config
+         .allowed_prefixes
+         .iter()
+         .filter_map(|prefix| {
+            hex::decode(prefix)
+               .inspect_err(|e| warn!("Ignoring passthrough prefix {prefix:?}: {e}"))
+               .ok()
+         })
+         .collect();
+      Self {
+         enabled: config.enabled,
+         allowed_prefixes,
+         log: parking_lot::Mutex::new(VecDeque::with_capacity(AUDIT_LOG_CAPACITY)),
+      }
+   }
+
+   /// Applies a new configuration, keeping the audit log.
+   pub fn reconfigure(&mut self, config: &PassthroughConfig) {
+      let log = std::mem::take(&mut self.log);
+      *self = Self {
+         log,
+         ..Self::new(config)
+      };
+   }
+
+   /// Checks whether the hex `packet` may be sent, returning its bytes.
+   pub fn check(&self, packet: &str) -> Result<Vec<u8>, (Outcome, ServiceError)> {
+      let denied = |reason: &str| {
+         (
+            Outcome::Denied,
+            fdo::Error::AccessDenied(reason.to_string()).into(),
+         )
+      };
+      if !self.enabled {
+         return Err(denied("Passthrough is disabled"));
+      }
+      let packet = hex::decode(packet).map_err(|e| {
+         (
+            Outcome::Invalid,
+            fdo::Error::InvalidArgs(e.to_string()).into(),
+         )
+      })?;
+      if !self.allowed_prefixes.is_empty()
+         && !self
+            .allowed_prefixes
+            .iter()
+            .any(|prefix| packet.starts_with(prefix))
+      {
+         return Err(denied("Packet not in the passthrough allowlist"));
+      }
+      Ok(packet)
+   }
+
+   /// Records an attempt, looking up the PID of the caller.
+   pub async fn record(
+      &self,
+      connection: &Connection,
+      header: &Header<'_>,
+      address: &str,
+      packet: &str,
+      outcome: Outcome,
+   ) {
+      let sender = header
+         .sender()
+         .map_or_else(String::new, ToString::to_string);
+      let pid = caller_pid(connection, header).await;
+      let timestamp = SystemTime::now()
+         .duration_since(UNIX_EPOCH)
+         .map_or(0, |d| d.as_secs());
+      info!("Passthrough {outcome:?} for {address} from {sender} (pid {pid:?}): {packet}");
+
+      let mut log = self.log.lock();
+      if log.len() == AUDIT_LOG_CAPACITY {
+         log.pop_front();
+      }
+      log.push_back(AuditEntry {
+         timestamp,
+         sender,
+         pid,
+         address: address.to_string(),
+         packet: packet.to_string(),
+         outcome,
+      });
+   }
+
+   /// Returns the recorded attempts, oldest first.
+   pub fn entries(&self) -> Vec<AuditEntry> {
+      self.log.lock().iter().cloned().collect()
+   }
+}
+
+async fn caller_pid(connection: &Connection, header: &Header<'_>) -> Option<u32> {
+   let sender = header.sender()?;
+   let proxy = fdo::DBusProxy::new(connection).await.ok()?;
+   proxy
+      .get_connection_unix_process_id(BusName::Unique(sender.clone()))
+      .await
+      .inspect_err(|e| warn!("Failed to get the PID of {sender}: {e}"))
+      .ok()
+}
+
+#[cfg(test)]
+mod tests {
+   use super::*;
+
+   #[test]
+   fn test_check() {
+      let disabled = PassthroughGate::new(&PassthroughConfig::default());
+      assert!(matches!(disabled.check("0400"), Err((Outcome::Denied, _))));
+
+      let open = PassthroughGate::new(&PassthroughConfig {
+         enabled: true,
+         allowed_prefixes: vec![],
+      });
+      assert_eq!(open.check("0400").unwrap(), [0x04, 0x00]);
+      assert!(matches!(open.check("04z"), Err((Outcome::Invalid, _))));
+
+      let restricted = PassthroughGate::new(&PassthroughConfig {
+         enabled: true,
+         allowed_prefixes: vec!["04000400".into(), "zz".into()],
+      });
+      assert!(restricted.check("0400040009").is_ok());
+      assert!(matches!(
+         restricted.check("040005"),
+         Err((Outcome::Denied, _))
+      ));
+   }
+}
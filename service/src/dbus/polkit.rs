@@ -0,0 +1,117 @@
+//! Caller authorization for system-bus mode.
+//!
+//! On the session bus every caller is the user running the service. On the
+//! system bus, method calls are checked against the polkit actions installed
+//! with the service: `org.kairpods.read` for queries and
+//! `org.kairpods.control` for anything changing the devices. Property reads
+//! and `GetManagedObjects` need `org.kairpods.read` as well. Signals are not
+//! checked; the bus policy limits them to the `kairpods` group.
+
+use std::collections::HashMap;
+
+use log::warn;
+use zbus::{Connection, fdo, message::Header, proxy, zvariant::Value};
+
+use crate::error::ServiceError;
+
+/// `AllowUserInteraction` flag of `CheckAuthorization`.
+const ALLOW_USER_INTERACTION: u32 = 1;
+
+#[proxy(
+   interface = "org.freedesktop.PolicyKit1.Authority",
+   default_service = "org.freedesktop.PolicyKit1",
+   default_path = "/org/freedesktop/PolicyKit1/Authority"
+)]
+trait Authority {
+   #[allow(clippy::type_complexity)]
+   fn check_authorization(
+      &self,
+      subject: &(&str, HashMap<&str, Value<'_>>),
+      action_id: &str,
+      details: HashMap<&str, &str>,
+      flags: u32,
+      cancellation_id: &str,
+   ) -> zbus::Result<(bool, bool, HashMap<String, String>)>;
+}
+
+/// Kind of operation a caller asks for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Access {
+   /// Reading device state.
+   Read,
+   /// Changing settings or connections.
+   Control,
+}
+
+impl Access {
+   /// Polkit action ID, as declared in `org.kairpods.policy`.
+   pub const fn action_id(self) -> &'static str {
+      match self {
+         Self::Read => "org.kairpods.read",
+         Self::Control => "org.kairpods.control",
+      }
+   }
+}
+
+/// Checks callers of the D-Bus methods.
+#[derive(Clone)]
+pub struct AccessControl(Option<AuthorityProxy<'static>>);
+
+impl AccessControl {
+   /// Allows every caller, for the session bus.
+   pub const fn unrestricted() -> Self {
+      Self(None)
+   }
+
+   /// Checks callers with polkit, for the system bus.
+   pub async fn polkit(connection: &Connection) -> zbus::Result<Self> {
+      Ok(Self(Some(AuthorityProxy::new(connection).await?)))
+   }
+
+   /// Returns an `AccessDenied` error unless the sender of `header` is allowed `access`.
+   pub async fn check(&self, header: &Header<'_>, access: Access) -> Result<(), ServiceError> {
+      self.authorize(header, access).await.map_err(Into::into)
+   }
+
+   /// Checks read access for a property get. `None` is the service reading its
+   /// own properties to emit `PropertiesChanged` or answer `GetManagedObjects`.
+   pub async fn check_read(&self, header: Option<&Header<'_>>) -> fdo::Result<()> {
+      match header {
+         Some(header) => self.authorize(header, Access::Read).await,
+         None => Ok(()),
+      }
+   }
+
+   async fn authorize(&self, header: &Header<'_>, access: Access) -> fdo::Result<()> {
+      let Some(authority) = &self.0 else {
+         return Ok(());
+      };
+      let Some(sender) = header.sender() else {
+         return Err(fdo::Error::AccessDenied("Unknown caller".into()));
+      };
+
+      let subject = (
+         "system-bus-name",
+         HashMap::from([("name", Value::from(sender.as_str()))]),
+      );
+      let flags = match access {
+         Access::Read => 0,
+         Access::Control => ALLOW_USER_INTERACTION,
+      };
+      let (authorized, _challenge, _details) = authority
+         .check_authorization(&subject, access.action_id(), HashMap::new(), flags, "")
+         .await
+         .map_err(|e| {
+            warn!("polkit check for {sender} failed: {e}");
+            fdo::Error::AccessDenied(format!("Authorization check failed: {e}"))
+         })?;
+      if authorized {
+         Ok(())
+      } else {
+         Err(fdo::Error::AccessDenied(format!(
+            "Not authorized for {}",
+            access.action_id()
+         )))
+      }
+   }
+}
@@ -0,0 +1,577 @@
+//! Tray icon for desktops without the Plasma widget.
+//!
+//! Exports a `StatusNotifierItem` showing the battery of the active device,
+//! with a `com.canonical.dbusmenu` menu to switch the noise control mode and
+//! toggle features. The active device is the first connected one.
+
+use std::{
+   collections::HashMap,
+   sync::atomic::{AtomicU32, Ordering},
+};
+
+use futures::StreamExt;
+use log::{debug, info, warn};
+use strum::IntoEnumIterator;
+use zbus::{
+   Connection, ObjectServer, fdo, interface,
+   object_server::SignalEmitter,
+   proxy,
+   zvariant::{ObjectPath, StructureBuilder, Type, Value},
+};
+
+use crate::{
+   airpods::{
+      device::AirPods,
+      protocol::{BatteryInfo, BatteryState, FeatureId, NoiseControlMode},
+   },
+   bluetooth::manager::BluetoothManager,
+   error::ServiceError,
+   event::AirPodsEvent,
+};
+
+/// Object path of the status notifier item.
+pub const ITEM_PATH: &str = "/StatusNotifierItem";
+/// Object path of the menu.
+pub const MENU_PATH: &str = "/MenuBar";
+
+const WATCHER_SERVICE: &str = "org.kde.StatusNotifierWatcher";
+
+/// Noise control modes cycled through by a click on the icon.
+const CYCLED_MODES: [NoiseControlMode; 3] = [
+   NoiseControlMode::Active,
+   NoiseControlMode::Transparency,
+   NoiseControlMode::Off,
+];
+
+/// Features offered as checkboxes in the menu, when the device reports them.
+const MENU_FEATURES: [(FeatureId, &str); 5] = [
+   (FeatureId::CONVERSATIONAL, "Conversation Awareness"),
+   (FeatureId::ADAPTIVE_VOLUME, "Personalized Volume"),
+   (FeatureId::ONE_BUD_ANC, "Noise Control with One AirPod"),
+   (FeatureId::VOLUME_SWIPE, "Volume Swipe"),
+   (FeatureId::IN_CASE_TONE, "Case Sounds"),
+];
+
+// Menu item ids
+const ID_ROOT: i32 = 0;
+const ID_DEVICE: i32 = 1;
+const ID_SEPARATOR_NOISE: i32 = 2;
+const ID_SEPARATOR_FEATURES: i32 = 3;
+const ID_NOISE_BASE: i32 = 10;
+const ID_FEATURE_BASE: i32 = 100;
+
+#[proxy(
+   interface = "org.kde.StatusNotifierWatcher",
+   default_service = "org.kde.StatusNotifierWatcher",
+   default_path = "/StatusNotifierWatcher"
+)]
+trait StatusNotifierWatcher {
+   fn register_status_notifier_item(&self, service: &str) -> zbus::Result<()>;
+}
+
+async fn active_device(manager: &BluetoothManager) -> Option<AirPods> {
+   manager
+      .all_devices()
+      .await
+      .into_iter()
+      .find(AirPods::is_connected)
+}
+
+/// Level shown for the device: the headphone level, or the lower bud.
+fn battery_level(battery: &BatteryInfo) -> Option<(u8, bool)> {
+   if battery.headphone.is_available() {
+      return Some((battery.headphone.level, battery.headphone.is_charging()));
+   }
+   [battery.left, battery.right]
+      .into_iter()
+      .filter(|b| b.is_available())
+      .min_by_key(|b| b.level)
+      .map(|b| (b.level, b.is_charging()))
+}
+
+fn battery_label(state: BatteryState) -> String {
+   if state.is_available() {
+      format!("{}%", state.level)
+   } else {
+      "-".into()
+   }
+}
+
+/// Freedesktop icon name reflecting the battery level of `device`.
+fn icon_name(device: Option<&AirPods>) -> String {
+   let Some((level, charging)) = device
+      .and_then(AirPods::battery_info)
+      .as_ref()
+      .and_then(battery_level)
+   else {
+      return "audio-headphones".into();
+   };
+   let name = match level {
+      90.. => "battery-full",
+      50.. => "battery-good",
+      20.. => "battery-low",
+      5.. => "battery-caution",
+      _ => "battery-empty",
+   };
+   if charging {
+      format!("{name}-charging")
+   } else {
+      name.into()
+   }
+}
+
+/// Tooltip as `(icon name, icon pixmaps, title, description)`.
+type ToolTip = (String, Vec<(i32, i32, Vec<u8>)>, String, String);
+
+/// `org.kde.StatusNotifierItem` implementation.
+pub struct StatusNotifierItem {
+   bluetooth_manager: BluetoothManager,
+}
+
+#[interface(name = "org.kde.StatusNotifierItem")]
+impl StatusNotifierItem {
+   /// Left click: switch to the next noise control mode.
+   async fn activate(&self, _x: i32, _y: i32) -> Result<(), ServiceError> {
+      let Some(device) = active_device(&self.bluetooth_manager).await else {
+         return Ok(());
+      };
+      let next = device
+         .noise_mode()
+         .and_then(|mode| CYCLED_MODES.iter().position(|&m| m == mode))
+         .map_or(0, |i| (i + 1) % CYCLED_MODES.len());
+      device.set_noise_control(CYCLED_MODES[next]).await?;
+      Ok(())
+   }
+
+   async fn secondary_activate(&self, _x: i32, _y: i32) {}
+
+   async fn context_menu(&self, _x: i32, _y: i32) {}
+
+   async fn scroll(&self, _delta: i32, _orientation: String) {}
+
+   #[zbus(property)]
+   fn category(&self) -> &str {
+      "Hardware"
+   }
+
+   #[zbus(property)]
+   fn id(&self) -> &str {
+      "kairpods"
+   }
+
+   #[zbus(property)]
+   fn title(&self) -> &str {
+      "AirPods"
+   }
+
+   #[zbus(property)]
+   async fn status(&self) -> &str {
+      if active_device(&self.bluetooth_manager).await.is_some() {
+         "Active"
+      } else {
+         "Passive"
+      }
+   }
+
+   #[zbus(property)]
+   fn window_id(&self) -> i32 {
+      0
+   }
+
+   #[zbus(property)]
+   async fn icon_name(&self) -> String {
+      icon_name(active_device(&self.bluetooth_manager).await.as_ref())
+   }
+
+   #[zbus(property)]
+   async fn tool_tip(&self) -> ToolTip {
+      let device = active_device(&self.bluetooth_manager).await;
+      let icon = icon_name(device.as_ref());
+      let Some(device) = device else {
+         return (icon, vec![], "AirPods".into(), "Not connected".into());
+      };
+
+      let mut description = match device.battery_info() {
+         Some(b) if b.headphone.is_available() => format!("Battery {}", battery_label(b.headphone)),
+         Some(b) => format!(
+            "Left {} · Right {} · Case {}",
+            battery_label(b.left),
+            battery_label(b.right),
+            battery_label(b.case)
+         ),
+         None => "Battery unknown".into(),
+      };
+      if let Some(mode) = device.noise_mode() {
+         description.push_str(&format!("\nNoise control: {mode}"));
+      }
+      (icon, vec![], device.name().to_string(), description)
+   }
+
+   #[zbus(property)]
+   fn item_is_menu(&self) -> bool {
+      false
+   }
+
+   #[zbus(property)]
+   fn menu(&self) -> ObjectPath<'_> {
+      ObjectPath::from_static_str_unchecked(MENU_PATH)
+   }
+
+   #[zbus(signal)]
+   async fn new_icon(emitter: &SignalEmitter<'_>) -> zbus::Result<()>;
+
+   #[zbus(signal)]
+   async fn new_tool_tip(emitter: &SignalEmitter<'_>) -> zbus::Result<()>;
+
+   #[zbus(signal)]
+   async fn new_status(emitter: &SignalEmitter<'_>, status: &str) -> zbus::Result<()>;
+}
+
+/// A menu item with its children, as `(ia{sv}av)`.
+#[derive(Debug, Type, serde::Serialize)]
+struct MenuLayout {
+   id: i32,
+   properties: HashMap<&'static str, Value<'static>>,
+   children: Vec<Value<'static>>,
+}
+
+impl From<MenuLayout> for Value<'static> {
+   fn from(item: MenuLayout) -> Self {
+      StructureBuilder::new()
+         .add_field(item.id)
+         .add_field(item.properties)
+         .add_field(item.children)
+         .build()
+         .expect("structure has fields")
+         .into()
+   }
+}
+
+impl MenuLayout {
+   fn new(id: i32, properties: HashMap<&'static str, Value<'static>>) -> Self {
+      Self {
+         id,
+         properties,
+         children: vec![],
+      }
+   }
+}
+
+/// `com.canonical.dbusmenu` implementation.
+pub struct DbusMenu {
+   bluetooth_manager: BluetoothManager,
+   revision: AtomicU32,
+}
+
+impl DbusMenu {
+   /// Builds the flat list of menu items below the root, in display order.
+   async fn items(&self) -> Vec<MenuLayout> {
+      let separator = |id| MenuLayout::new(id, HashMap::from([("type", "separator".into())]));
+
+      let Some(device) = active_device(&self.bluetooth_manager).await else {
+         return vec![MenuLayout::new(
+            ID_DEVICE,
+            HashMap::from([
+               ("label", "No AirPods connected".into()),
+               ("enabled", false.into()),
+            ]),
+         )];
+      };
+
+      let mut items = vec![
+         MenuLayout::new(
+            ID_DEVICE,
+            HashMap::from([
+               ("label", device.name().to_string().into()),
+               ("enabled", false.into()),
+            ]),
+         ),
+         separator(ID_SEPARATOR_NOISE),
+      ];
+
+      let current = device.noise_mode();
+      for mode in NoiseControlMode::iter() {
+         let label = match mode {
+            NoiseControlMode::Off => "Off",
+            NoiseControlMode::Active => "Noise Cancellation",
+            NoiseControlMode::Transparency => "Transparency",
+            NoiseControlMode::Adaptive => "Adaptive",
+         };
+         items.push(MenuLayout::new(
+            ID_NOISE_BASE + mode.index() as i32,
+            HashMap::from([
+               ("label", label.into()),
+               ("toggle-type", "radio".into()),
+               ("toggle-state", i32::from(current == Some(mode)).into()),
+            ]),
+         ));
+      }
+
+      let features: HashMap<FeatureId, bool> = device.features().into_iter().collect();
+      let mut separated = false;
+      for (feature, label) in MENU_FEATURES {
+         let Some(&enabled) = features.get(&feature) else {
+            continue;
+         };
+         if !separated {
+            items.push(separator(ID_SEPARATOR_FEATURES));
+            separated = true;
+         }
+         items.push(MenuLayout::new(
+            ID_FEATURE_BASE + i32::from(feature.id()),
+            HashMap::from([
+               ("label", label.into()),
+               ("toggle-type", "checkmark".into()),
+               ("toggle-state", i32::from(enabled).into()),
+            ]),
+         ));
+      }
+      items
+   }
+
+   /// Performs the action of a clicked menu item.
+   async fn activate(&self, id: i32) -> Result<(), ServiceError> {
+      let Some(device) = active_device(&self.bluetooth_manager).await else {
+         return Ok(());
+      };
+      if let Some(mode) = id
+         .checked_sub(ID_NOISE_BASE)
+         .and_then(|i| NoiseControlMode::from_index(usize::try_from(i).ok()?))
+      {
+         device.set_noise_control(mode).await?;
+      } else if let Some(&(feature, _)) = MENU_FEATURES
+         .iter()
+         .find(|(f, _)| ID_FEATURE_BASE + i32::from(f.id()) == id)
+      {
+         device
+            .set_feature(feature, !device.feature_enabled(feature))
+            .await?;
+      }
+      Ok(())
+   }
+
+   /// Bumps the layout revision, returning the new one.
+   fn invalidate(&self) -> u32 {
+      self.revision.fetch_add(1, Ordering::Relaxed) + 1
+   }
+}
+
+#[interface(name = "com.canonical.dbusmenu")]
+impl DbusMenu {
+   async fn get_layout(
+      &self,
+      parent_id: i32,
+      _recursion_depth: i32,
+      _property_names: Vec<String>,
+   ) -> (u32, MenuLayout) {
+      let revision = self.revision.load(Ordering::Relaxed);
+      let mut root = MenuLayout::new(
+         ID_ROOT,
+         HashMap::from([("children-display", "submenu".into())]),
+      );
+      // The menu is flat, only the root has children
+      if parent_id == ID_ROOT {
+         root.children = self.items().await.into_iter().map(Value::from).collect();
+      } else {
+         root.id = parent_id;
+      }
+      (revision, root)
+   }
+
+   async fn get_group_properties(
+      &self,
+      ids: Vec<i32>,
+      _property_names: Vec<String>,
+   ) -> Vec<(i32, HashMap<&'static str, Value<'static>>)> {
+      self
+         .items()
+         .await
+         .into_iter()
+         .filter(|item| ids.is_empty() || ids.contains(&item.id))
+         .map(|item| (item.id, item.properties))
+         .collect()
+   }
+
+   async fn get_property(&self, id: i32, name: String) -> Result<Value<'static>, ServiceError> {
+      self
+         .items()
+         .await
+         .into_iter()
+         .find(|item| item.id == id)
+         .and_then(|mut item| item.properties.remove(name.as_str()))
+         .ok_or_else(|| {
+            fdo::Error::InvalidArgs(format!("No property {name:?} on item {id}")).into()
+         })
+   }
+
+   async fn event(
+      &self,
+      id: i32,
+      event_id: String,
+      _data: Value<'_>,
+      _timestamp: u32,
+      #[zbus(signal_emitter)] emitter: SignalEmitter<'_>,
+   ) -> Result<(), ServiceError> {
+      if event_id == "clicked" {
+         self.activate(id).await?;
+         Self::layout_updated(&emitter, self.invalidate(), ID_ROOT).await?;
+      }
+      Ok(())
+   }
+
+   async fn event_group(
+      &self,
+      events: Vec<(i32, String, Value<'_>, u32)>,
+      #[zbus(signal_emitter)] emitter: SignalEmitter<'_>,
+   ) -> Result<Vec<i32>, ServiceError> {
+      let mut errors = Vec::new();
+      for (id, event_id, _, _) in events {
+         if event_id == "clicked" && self.activate(id).await.is_err() {
+            errors.push(id);
+         }
+      }
+      Self::layout_updated(&emitter, self.invalidate(), ID_ROOT).await?;
+      Ok(errors)
+   }
+
+   fn about_to_show(&self, _id: i32) -> bool {
+      false
+   }
+
+   fn about_to_show_group(&self, _ids: Vec<i32>) -> (Vec<i32>, Vec<i32>) {
+      (vec![], vec![])
+   }
+
+   #[zbus(property)]
+   fn version(&self) -> u32 {
+      3
+   }
+
+   #[zbus(property)]
+   fn text_direction(&self) -> &str {
+      "ltr"
+   }
+
+   #[zbus(property)]
+   fn status(&self) -> &str {
+      "normal"
+   }
+
+   #[zbus(property)]
+   fn icon_theme_path(&self) -> Vec<String> {
+      vec![]
+   }
+
+   #[zbus(signal)]
+   async fn layout_updated(
+      emitter: &SignalEmitter<'_>,
+      revision: u32,
+      parent: i32,
+   ) -> zbus::Result<()>;
+}
+
+/// Exports the tray icon and registers it with the status notifier watcher,
+/// again whenever the watcher (re)appears.
+pub async fn serve(
+   connection: &Connection,
+   bluetooth_manager: BluetoothManager,
+) -> zbus::Result<()> {
+   let server = connection.object_server();
+   server
+      .at(
+         ITEM_PATH,
+         StatusNotifierItem {
+            bluetooth_manager: bluetooth_manager.clone(),
+         },
+      )
+      .await?;
+   server
+      .at(
+         MENU_PATH,
+         DbusMenu {
+            bluetooth_manager,
+            revision: AtomicU32::new(1),
+         },
+      )
+      .await?;
+
+   let name = format!("org.kde.StatusNotifierItem-{}-1", std::process::id());
+   connection.request_name(name.as_str()).await?;
+
+   let dbus = fdo::DBusProxy::new(connection).await?;
+   let mut owner_changes = dbus
+      .receive_name_owner_changed_with_args(&[(0, WATCHER_SERVICE)])
+      .await?;
+   let connection = connection.clone();
+   tokio::spawn(async move {
+      register(&connection, &name).await;
+      while let Some(signal) = owner_changes.next().await {
+         if signal.args().is_ok_and(|args| args.new_owner().is_some()) {
+            register(&connection, &name).await;
+         }
+      }
+   });
+   Ok(())
+}
+
+async fn register(connection: &Connection, name: &str) {
+   let result = async {
+      StatusNotifierWatcherProxy::new(connection)
+         .await?
+         .register_status_notifier_item(name)
+         .await
+   }
+   .await;
+   match result {
+      Ok(()) => info!("Registered tray icon as {name}"),
+      Err(zbus::Error::MethodError(error, ..))
+         if error == "org.freedesktop.DBus.Error.ServiceUnknown" =>
+      {
+         debug!("No status notifier watcher running, waiting for one");
+      },
+      Err(e) => warn!("Failed to register tray icon: {e}"),
+   }
+}
+
+/// Refreshes the tray icon and menu after a device event.
+pub async fn dispatch(server: &ObjectServer, event: &AirPodsEvent) -> zbus::Result<()> {
+   if matches!(
+      event,
+      AirPodsEvent::EarDetectionChanged(_)
+         | AirPodsEvent::TransparencyChanged(_)
+         | AirPodsEvent::PrimaryBudChanged(_)
+   ) {
+      return Ok(());
+   }
+
+   if let Ok(iface) = server.interface::<_, StatusNotifierItem>(ITEM_PATH).await {
+      let emitter = iface.signal_emitter();
+      StatusNotifierItem::new_icon(emitter).await?;
+      StatusNotifierItem::new_tool_tip(emitter).await?;
+      let item = iface.get().await;
+      StatusNotifierItem::new_status(emitter, item.status().await).await?;
+   }
+   if let Ok(iface) = server.interface::<_, DbusMenu>(MENU_PATH).await {
+      let revision = iface.get().await.invalidate();
+      DbusMenu::layout_updated(iface.signal_emitter(), revision, ID_ROOT).await?;
+   }
+   Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+   use zbus::zvariant::{LE, serialized::Context, to_bytes};
+
+   use super::*;
+
+   #[test]
+   fn test_layout_encoding() {
+      let mut root = MenuLayout::new(ID_ROOT, HashMap::new());
+      let item = MenuLayout::new(ID_DEVICE, HashMap::from([("label", "AirPods".into())]));
+      root.children.push(item.into());
+
+      assert_eq!(MenuLayout::SIGNATURE.to_string(), "(ia{sv}av)");
+      assert_eq!(root.children[0].value_signature().to_string(), "(ia{sv}av)");
+      to_bytes(Context::new_dbus(LE, 0), &(1u32, root)).unwrap();
+   }
+}
@@ -9,8 +9,15 @@ use std::io;
 use bluer::Address;
 use thiserror::Error;
 use tokio::task::JoinError;
+use zbus::{DBusError, fdo};
 
-use crate::{airpods::parser, battery_study};
+use crate::{
+   airpods::{
+      parser,
+      protocol::{FeatureId, RangeError},
+   },
+   battery_study,
+};
 
 /// Main error type for the `AirPods` service.
 #[derive(Error, Debug)]
@@ -22,7 +29,7 @@ pub enum AirPodsError {
    DBus(#[from] zbus::Error),
 
    #[error("D-Bus connection error: {0}")]
-   DBusConnection(#[from] zbus::fdo::Error),
+   DBusConnection(#[from] fdo::Error),
 
    #[error("I/O error: {0}")]
    Io(#[from] io::Error),
@@ -39,6 +46,12 @@ pub enum AirPodsError {
    #[error("Invalid packet: {0}")]
    InvalidPacket(#[from] parser::ProtoError),
 
+   #[error("Invalid setting: {0}")]
+   InvalidSetting(#[from] RangeError),
+
+   #[error("Precondition failed: {0}")]
+   PreconditionFailed(&'static str),
+
    #[error("Feature not supported: {0}")]
    FeatureNotSupported(String),
 
@@ -54,12 +67,21 @@ pub enum AirPodsError {
    #[error("Request timeout")]
    RequestTimeout,
 
+   #[error("Setting not applied by the device: {0}")]
+   SettingRejected(FeatureId),
+
    #[error("Could not determine config directory")]
    ConfigDirNotFound,
 
    #[error("TOML parsing error: {0}")]
    TomlParse(#[from] toml::de::Error),
 
+   #[error("TOML parsing error: {0}")]
+   TomlEdit(#[from] toml_edit::TomlError),
+
+   #[error("Invalid configuration: {0}")]
+   InvalidConfig(String),
+
    #[error("TOML serialization error: {0}")]
    TomlSerialize(#[from] toml::ser::Error),
 
@@ -82,8 +104,61 @@ pub enum AirPodsError {
 /// Convenience type alias for Results with `AirPodsError`.
 pub type Result<T, E = AirPodsError> = std::result::Result<T, E>;
 
-impl From<AirPodsError> for zbus::fdo::Error {
+/// Errors returned to D-Bus clients.
+///
+/// Failures that clients may want to react to get their own
+/// `org.kairpods.Error.*` name, invalid arguments are reported as
+/// `org.freedesktop.DBus.Error.InvalidArgs`.
+#[derive(DBusError, Debug)]
+#[zbus(prefix = "org.kairpods.Error")]
+pub enum ServiceError {
+   #[zbus(error)]
+   ZBus(zbus::Error),
+   /// The device is not managed by the service.
+   NotFound(String),
+   /// The device is not connected, or the connection was lost.
+   NotConnected(String),
+   /// The device is not paired with this computer.
+   NotPaired(String),
+   /// The device did not answer in time.
+   Timeout(String),
+   /// The device does not support the requested feature.
+   NotSupported(String),
+   /// The Bluetooth adapter of the device is missing or unusable.
+   AdapterUnavailable(String),
+   /// The device is not in a state allowing the operation.
+   PreconditionFailed(String),
+   /// Another operation on the device is already in progress.
+   Busy(String),
+   /// Any other failure.
+   Failed(String),
+}
+
+impl From<fdo::Error> for ServiceError {
+   fn from(error: fdo::Error) -> Self {
+      Self::ZBus(error.into())
+   }
+}
+
+impl From<AirPodsError> for ServiceError {
    fn from(error: AirPodsError) -> Self {
-      Self::Failed(error.to_string())
+      let message = error.to_string();
+      match error {
+         AirPodsError::DeviceNotFound(_) => Self::NotFound(message),
+         AirPodsError::DeviceNotConnected
+         | AirPodsError::ConnectionLost
+         | AirPodsError::ConnectionClosed => Self::NotConnected(message),
+         AirPodsError::DeviceNotPaired => Self::NotPaired(message),
+         AirPodsError::RequestTimeout => Self::Timeout(message),
+         AirPodsError::FeatureNotSupported(_) => Self::NotSupported(message),
+         AirPodsError::AdapterNotFound | AirPodsError::AdapterNotAvailable => {
+            Self::AdapterUnavailable(message)
+         },
+         AirPodsError::PreconditionFailed(_) => Self::PreconditionFailed(message),
+         AirPodsError::AlreadyConnecting => Self::Busy(message),
+         AirPodsError::InvalidSetting(_) => fdo::Error::InvalidArgs(message).into(),
+         AirPodsError::DBusConnection(e) => e.into(),
+         _ => Self::Failed(message),
+      }
    }
 }
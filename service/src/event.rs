@@ -6,23 +6,43 @@
 
 use std::sync::Arc;
 
+use serde::{Deserialize, Serialize};
+use serde_json::{Value, json};
 use smol_str::SmolStr;
 
 use crate::airpods::{
-   device::AirPods,
-   protocol::{BatteryInfo, EarDetectionStatus, NoiseControlMode},
+   device::{AirPods, ConnectionInfo, ProfileReport},
+   protocol::{
+      BatteryInfo, Component, EarDetectionStatus, FeatureId, NoiseControlMode, TransparencySettings,
+   },
 };
 
 /// Events that can be emitted by the `AirPods` service.
-#[derive(Debug, Clone)]
+///
+/// [`EventKind`] names the variants without their payload, as used in the config.
+#[derive(Debug, Clone, strum::EnumDiscriminants)]
+#[strum_discriminants(
+   name(EventKind),
+   derive(Serialize, Deserialize, Hash, PartialOrd, Ord, strum::IntoStaticStr),
+   serde(rename_all = "snake_case"),
+   strum(serialize_all = "snake_case")
+)]
 pub enum AirPodsEvent {
+   DeviceAdded,
+   DeviceRemoved,
    DeviceConnected,
    DeviceDisconnected,
    DeviceError,
    BatteryUpdated(BatteryInfo),
    NoiseControlChanged(NoiseControlMode),
+   /// A feature was toggled by a rule.
+   FeatureChanged(FeatureId, bool),
    EarDetectionChanged(EarDetectionStatus),
    DeviceNameChanged(SmolStr),
+   PrimaryBudChanged(Component),
+   TransparencyChanged(TransparencySettings),
+   ConnectionStateChanged(ConnectionInfo),
+   ProfileApplied(ProfileReport),
 }
 
 /// Trait for implementing event emission.
@@ -33,3 +53,54 @@ pub trait EventBus: Send + Sync {
 
 /// Type alias for a thread-safe event sender.
 pub type EventSender = Arc<dyn EventBus>;
+
+impl AirPodsEvent {
+   pub fn kind(&self) -> EventKind {
+      self.into()
+   }
+
+   /// Gets the payload of the event as JSON, `null` for the events without one.
+   pub fn payload_json(&self) -> Value {
+      match self {
+         Self::BatteryUpdated(battery) => battery.to_json(),
+         Self::NoiseControlChanged(mode) => json!(mode.to_str()),
+         Self::FeatureChanged(feature, enabled) => {
+            json!({ "feature": feature.to_str(), "enabled": enabled })
+         },
+         Self::EarDetectionChanged(ear) => ear.to_json(),
+         Self::DeviceNameChanged(name) => json!(name),
+         Self::PrimaryBudChanged(primary) => json!(primary.to_str()),
+         Self::TransparencyChanged(settings) => settings.to_json(),
+         Self::ConnectionStateChanged(info) => info.to_json(),
+         Self::ProfileApplied(report) => report.to_json(),
+         Self::DeviceAdded
+         | Self::DeviceRemoved
+         | Self::DeviceConnected
+         | Self::DeviceDisconnected
+         | Self::DeviceError => Value::Null,
+      }
+   }
+}
+
+impl EventKind {
+   pub fn to_str(self) -> &'static str {
+      self.into()
+   }
+}
+
+/// Forwards every event to several buses, in order.
+pub struct Broadcast(Vec<EventSender>);
+
+impl Broadcast {
+   pub fn new(buses: Vec<EventSender>) -> Arc<Self> {
+      Arc::new(Self(buses))
+   }
+}
+
+impl EventBus for Broadcast {
+   fn emit(&self, device: &AirPods, event: AirPodsEvent) {
+      for bus in &self.0 {
+         bus.emit(device, event.clone());
+      }
+   }
+}
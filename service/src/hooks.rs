@@ -0,0 +1,287 @@
+//! Hook commands run on device events.
+//!
+//! Every command configured for the kind of an event runs in the background,
+//! with the event described in `KAIRPODS_*` environment variables and as JSON
+//! on stdin. Hooks are killed after the configured timeout along with the
+//! processes they started, and their stderr goes to the log. Hooks fired while
+//! the maximum number of them is already running are skipped.
+
+use std::{io, process::Stdio, sync::Arc, time::Duration};
+
+use log::{info, warn};
+use serde_json::json;
+use tokio::{
+   io::{AsyncReadExt, AsyncWriteExt},
+   process::Command,
+   sync::Semaphore,
+   time,
+};
+
+use crate::{
+   airpods::device::AirPods,
+   config::HooksConfig,
+   event::{AirPodsEvent, EventBus},
+};
+
+/// Event bus sink running the hook commands.
+pub struct HookRunner(parking_lot::RwLock<Hooks>);
+
+struct Hooks {
+   config: HooksConfig,
+   permits: Arc<Semaphore>,
+}
+
+impl Hooks {
+   fn new(config: HooksConfig) -> Self {
+      let hooks: usize = config.commands.values().map(Vec::len).sum();
+      if hooks > 0 {
+         info!("Loaded {hooks} event hooks");
+      }
+      Self {
+         permits: Arc::new(Semaphore::new(config.max_concurrent.max(1))),
+         config,
+      }
+   }
+}
+
+impl HookRunner {
+   pub fn new(config: HooksConfig) -> Arc<Self> {
+      Arc::new(Self(parking_lot::RwLock::new(Hooks::new(config))))
+   }
+
+   /// Replaces the hooks; the ones already running are not affected.
+   pub fn set_config(&self, config: HooksConfig) {
+      *self.0.write() = Hooks::new(config);
+   }
+}
+
+impl EventBus for HookRunner {
+   fn emit(&self, device: &AirPods, event: AirPodsEvent) {
+      let hooks = self.0.read();
+      let Some(commands) = hooks.config.commands.get(&event.kind()) else {
+         return;
+      };
+      if commands.is_empty() {
+         return;
+      }
+
+      let env: Arc<[(&str, String)]> = environment(device, &event).into();
+      let payload: Arc<str> = json!({
+         "event": event.kind().to_str(),
+         "address": device.address_str().as_str(),
+         "name": device.name().as_str(),
+         "data": event.payload_json(),
+      })
+      .to_string()
+      .into();
+      let timeout = Duration::from_secs(hooks.config.timeout_sec);
+      for command in commands {
+         let Ok(permit) = hooks.permits.clone().try_acquire_owned() else {
+            warn!(
+               "Skipped hook {command:?} for {}, {} hooks are already running",
+               event.kind().to_str(),
+               hooks.config.max_concurrent.max(1)
+            );
+            continue;
+         };
+         let command = command.clone();
+         let env = env.clone();
+         let payload = payload.clone();
+         tokio::spawn(async move {
+            let _permit = permit;
+            if let Err(e) = run("Hook", &command, &env, &payload, timeout).await {
+               warn!("Hook {command:?} failed to run: {e}");
+            }
+         });
+      }
+   }
+}
+
+/// Runs a command to completion, killing it and the processes it started once
+/// `timeout` elapses.
+///
+/// `payload` is written to its stdin, and the log lines about it start with `label`.
+pub(crate) async fn run(
+   label: &str,
+   command: &str,
+   env: &[(&str, String)],
+   payload: &str,
+   timeout: Duration,
+) -> io::Result<()> {
+   let mut child = Command::new("sh")
+      .arg("-c")
+      .arg(command)
+      .envs(env.iter().map(|(k, v)| (k, v)))
+      .stdin(Stdio::piped())
+      .stdout(Stdio::null())
+      .stderr(Stdio::piped())
+      .process_group(0)
+      .kill_on_drop(true)
+      .spawn()?;
+
+   let stdin = child.stdin.take();
+   let stderr = child.stderr.take();
+   let completion = async {
+      let input = async move {
+         if let Some(mut stdin) = stdin {
+            // Hooks are free to ignore their input
+            let _ = stdin.write_all(payload.as_bytes()).await;
+         }
+      };
+      let errors = async move {
+         let mut errors = Vec::new();
+         if let Some(mut stderr) = stderr {
+            stderr.read_to_end(&mut errors).await?;
+         }
+         Ok::<_, io::Error>(errors)
+      };
+      let ((), errors, status) = tokio::join!(input, errors, child.wait());
+      Ok::<_, io::Error>((errors?, status?))
+   };
+   // The child is kept out of the timeout so that its group is killed while it is still around
+   let Ok(output) = time::timeout(timeout, completion).await else {
+      if let Some(group) = child.id() {
+         // SAFETY: killpg only sends a signal. The shell leading the group is not
+         // reaped until `child` is, so the group ID cannot have been reused.
+         unsafe { libc::killpg(group as libc::pid_t, libc::SIGKILL) };
+      }
+      let _ = child.kill().await;
+      warn!("{label} {command:?} timed out after {timeout:?} and was killed");
+      return Ok(());
+   };
+   let (errors, status) = output?;
+
+   for line in String::from_utf8_lossy(&errors).lines() {
+      info!("{label} {command:?}: {line}");
+   }
+   if !status.success() {
+      warn!("{label} {command:?} exited with {status}");
+   }
+   Ok(())
+}
+
+/// Describes an event in the environment variables of its hooks.
+fn environment(device: &AirPods, event: &AirPodsEvent) -> Vec<(&'static str, String)> {
+   let flag = |b: bool| if b { "1" } else { "0" }.to_string();
+   let mut env = vec![
+      ("KAIRPODS_EVENT", event.kind().to_str().to_string()),
+      ("KAIRPODS_ADDRESS", device.address_str().to_string()),
+      ("KAIRPODS_NAME", device.name().to_string()),
+      ("KAIRPODS_ADAPTER", device.adapter().to_string()),
+   ];
+   match event {
+      AirPodsEvent::BatteryUpdated(battery) => {
+         for (level, charging, state) in [
+            (
+               "KAIRPODS_BATTERY_LEFT",
+               "KAIRPODS_CHARGING_LEFT",
+               battery.left,
+            ),
+            (
+               "KAIRPODS_BATTERY_RIGHT",
+               "KAIRPODS_CHARGING_RIGHT",
+               battery.right,
+            ),
+            (
+               "KAIRPODS_BATTERY_CASE",
+               "KAIRPODS_CHARGING_CASE",
+               battery.case,
+            ),
+            (
+               "KAIRPODS_BATTERY_HEADPHONE",
+               "KAIRPODS_CHARGING_HEADPHONE",
+               battery.headphone,
+            ),
+         ] {
+            if state.is_available() {
+               env.push((level, state.level.to_string()));
+               env.push((charging, flag(state.is_charging())));
+            }
+         }
+      },
+      AirPodsEvent::NoiseControlChanged(mode) => {
+         env.push(("KAIRPODS_NOISE_MODE", mode.to_str().to_string()));
+      },
+      AirPodsEvent::FeatureChanged(feature, enabled) => {
+         env.push(("KAIRPODS_FEATURE", feature.to_str().to_string()));
+         env.push(("KAIRPODS_FEATURE_ENABLED", flag(*enabled)));
+      },
+      AirPodsEvent::EarDetectionChanged(ear) => {
+         env.push(("KAIRPODS_IN_EAR_LEFT", flag(ear.is_left_in_ear())));
+         env.push(("KAIRPODS_IN_EAR_RIGHT", flag(ear.is_right_in_ear())));
+      },
+      AirPodsEvent::PrimaryBudChanged(primary) => {
+         env.push(("KAIRPODS_PRIMARY_BUD", primary.to_str().to_string()));
+      },
+      AirPodsEvent::ConnectionStateChanged(info) => {
+         env.push(("KAIRPODS_CONNECTION_STATE", info.state.to_str().to_string()));
+      },
+      _ => {},
+   }
+   env
+}
+
+#[cfg(test)]
+mod tests {
+   use super::*;
+   use crate::{
+      airpods::protocol::{BatteryInfo, BatteryState, BatteryStatus},
+      event::EventKind,
+   };
+
+   #[test]
+   fn test_hooks_config() {
+      let config: HooksConfig = toml::from_str(
+         r#"
+         timeout_sec = 5
+         battery_updated = ["~/bin/battery.sh"]
+         device_connected = ["notify-send connected", "true"]
+         "#,
+      )
+      .unwrap();
+      assert_eq!(config.timeout_sec, 5);
+      assert_eq!(config.max_concurrent, 4);
+      assert_eq!(config.commands[&EventKind::DeviceConnected].len(), 2);
+      assert!(toml::from_str::<HooksConfig>("bogus_event = []").is_err());
+   }
+
+   #[tokio::test]
+   async fn test_timeout_kills_group() {
+      let dir = tempfile::TempDir::new().unwrap();
+      let pid_file = dir.path().join("pid");
+      let command = format!("sleep 30 & echo $! > {}; wait", pid_file.display());
+      run("Hook", &command, &[], "", Duration::from_millis(500))
+         .await
+         .unwrap();
+
+      let pid = std::fs::read_to_string(&pid_file).unwrap();
+      time::sleep(Duration::from_millis(100)).await;
+      let stat = std::fs::read_to_string(format!("/proc/{}/stat", pid.trim())).ok();
+      // Gone, or a zombie left for init to reap
+      assert!(stat.is_none_or(|stat| stat.contains(") Z ")));
+   }
+
+   #[test]
+   fn test_environment() {
+      let device = AirPods::new(
+         "AA:BB:CC:DD:EE:FF".parse().unwrap(),
+         "AirPods Pro".to_string(),
+         "hci0".into(),
+         None,
+      );
+      let event = AirPodsEvent::BatteryUpdated(BatteryInfo {
+         left: BatteryState {
+            level: 80,
+            status: BatteryStatus::Charging,
+         },
+         ..BatteryInfo::new()
+      });
+      let env = environment(&device, &event);
+      let get = |key| env.iter().find(|(k, _)| *k == key).map(|(_, v)| v.as_str());
+      assert_eq!(get("KAIRPODS_EVENT"), Some("battery_updated"));
+      assert_eq!(get("KAIRPODS_ADDRESS"), Some("AA:BB:CC:DD:EE:FF"));
+      assert_eq!(get("KAIRPODS_BATTERY_LEFT"), Some("80"));
+      assert_eq!(get("KAIRPODS_CHARGING_LEFT"), Some("1"));
+      assert_eq!(get("KAIRPODS_BATTERY_RIGHT"), None);
+   }
+}
@@ -0,0 +1,51 @@
+//! Logger whose filter can be changed while the service runs.
+//!
+//! `env_logger` fixes its filter when built, so the logger is wrapped and
+//! rebuilt whenever the `log_filter` setting changes. `RUST_LOG` still takes
+//! precedence over the setting.
+
+use std::sync::OnceLock;
+
+use log::{Log, Metadata, Record};
+
+static LOGGER: OnceLock<LiveLogger> = OnceLock::new();
+
+struct LiveLogger(parking_lot::RwLock<env_logger::Logger>);
+
+impl Log for LiveLogger {
+   fn enabled(&self, metadata: &Metadata<'_>) -> bool {
+      self.0.read().enabled(metadata)
+   }
+
+   fn log(&self, record: &Record<'_>) {
+      self.0.read().log(record);
+   }
+
+   fn flush(&self) {
+      self.0.read().flush();
+   }
+}
+
+fn build(filter: Option<&str>) -> env_logger::Logger {
+   env_logger::Builder::from_env(
+      env_logger::Env::default().default_filter_or(filter.unwrap_or("info")),
+   )
+   .build()
+}
+
+/// Installs the logger with the given default filter.
+pub fn init(filter: Option<&str>) {
+   let logger = LOGGER.get_or_init(|| LiveLogger(parking_lot::RwLock::new(build(filter))));
+   if log::set_logger(logger).is_ok() {
+      log::set_max_level(logger.0.read().filter());
+   }
+}
+
+/// Replaces the default filter of the installed logger.
+pub fn set_filter(filter: Option<&str>) {
+   if let Some(logger) = LOGGER.get() {
+      let rebuilt = build(filter);
+      log::set_max_level(rebuilt.filter());
+      *logger.0.write() = rebuilt;
+   }
+}
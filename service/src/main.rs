@@ -8,12 +8,21 @@ use std::{sync::Arc, time::Duration};
 
 use crossbeam::queue::SegQueue;
 use log::{info, warn};
-use tokio::{signal, sync::Notify, time};
+use tokio::{
+   signal::{
+      self,
+      unix::{SignalKind, signal},
+   },
+   sync::Notify,
+   time,
+};
 use zbus::{Connection, connection, object_server::InterfaceRef};
 
-use bluetooth::manager::BluetoothManager;
-use dbus::AirPodsService;
-use event::{AirPodsEvent, EventBus};
+use bluetooth::{battery_provider::BatteryProvider, manager::BluetoothManager};
+use dbus::{AirPodsService, passthrough::PassthroughGate, polkit::AccessControl};
+use event::{AirPodsEvent, Broadcast, EventBus, EventSender};
+use hooks::HookRunner;
+use rules::RulesEngine;
 
 mod airpods;
 mod battery_study;
@@ -22,7 +31,11 @@ mod config;
 mod dbus;
 mod error;
 mod event;
+mod hooks;
+mod logging;
+mod reload;
 mod ringbuf;
+mod rules;
 
 use crate::{airpods::device::AirPods, dbus::AirPodsServiceSignals, error::Result};
 
@@ -30,8 +43,10 @@ use crate::{airpods::device::AirPods, dbus::AirPodsServiceSignals, error::Result
 async fn main() -> Result<()> {
    // Parse command line arguments
    let args: Vec<String> = std::env::args().collect();
-   if args.len() > 1 {
-      match args[1].as_str() {
+   let mut system_bus = false;
+   for arg in &args[1..] {
+      match arg.as_str() {
+         "--system" => system_bus = true,
          "--version" | "-v" => {
             println!("kairpodsd {}", env!("CARGO_PKG_VERSION"));
             return Ok(());
@@ -40,6 +55,7 @@ async fn main() -> Result<()> {
             println!("Usage: {} [OPTIONS]", args[0]);
             println!();
             println!("Options:");
+            println!("  --system         Serve on the system bus, polkit checks method calls");
             println!("  -v, --version    Print version information and exit");
             println!("  -h, --help       Print this help message and exit");
             return Ok(());
@@ -52,14 +68,15 @@ async fn main() -> Result<()> {
       }
    }
 
-   let (config, config_err) = match config::Config::load() {
+   // Handle SIGHUP before anything else, so that a reload never terminates the service
+   let hangup = signal(SignalKind::hangup())?;
+
+   let (config, config_err) = match config::Config::load(system_bus) {
       Ok(config) => (config, None),
       Err(e) => (config::Config::default(), Some(e)),
    };
 
-   let default_filter = config.log_filter.as_deref().unwrap_or("info");
-   env_logger::Builder::from_env(env_logger::Env::default().default_filter_or(default_filter))
-      .init();
+   logging::init(config.log_filter.as_deref());
    info!("Starting kAirPods D-Bus service...");
 
    if let Some(err) = config_err {
@@ -71,8 +88,12 @@ async fn main() -> Result<()> {
       );
    }
 
-   // Create event channel
+   // Create event channel, shared by the D-Bus dispatcher, the automation rules and the hooks
    let event_bus = EventProcessor::new();
+   let rules = RulesEngine::new(config.rules.clone());
+   let hooks = HookRunner::new(config.hooks.clone());
+   let events: EventSender = Broadcast::new(vec![event_bus.clone(), rules.clone(), hooks.clone()]);
+   rules.start(Broadcast::new(vec![event_bus.clone(), hooks.clone()]));
 
    // Initialize battery study database
    let battery_study = match battery_study::BatteryStudy::open() {
@@ -86,23 +107,85 @@ async fn main() -> Result<()> {
       },
    };
 
+   // Publish battery levels to BlueZ
+   let battery_provider = if config.battery_provider.enabled {
+      match BatteryProvider::new(config.battery_provider.policy).await {
+         Ok(provider) => Some(Arc::new(provider)),
+         Err(e) => {
+            warn!("Failed to start BlueZ battery provider: {e}");
+            None
+         },
+      }
+   } else {
+      None
+   };
+
+   let tray_icon = config.tray_icon;
+   let battery_provider_config = config.battery_provider.clone();
+   let passthrough = PassthroughGate::new(&config.passthrough);
+
    // Create Bluetooth manager with event sender and config
-   let bluetooth_manager = BluetoothManager::new(event_bus.clone(), config, battery_study).await?;
+   let bluetooth_manager =
+      BluetoothManager::new(events, config, battery_study, battery_provider.clone()).await?;
 
-   // Create D-Bus service
-   let service = AirPodsService::new(bluetooth_manager);
+   // Build D-Bus connection, and claim the name once the objects are served
+   let connection = if system_bus {
+      connection::Builder::system()?
+   } else {
+      connection::Builder::session()?
+   }
+   .build()
+   .await?;
+   let access = if system_bus {
+      AccessControl::polkit(&connection).await?
+   } else {
+      AccessControl::unrestricted()
+   };
 
-   // Build D-Bus connection
-   let connection = connection::Builder::session()?
-      .name("org.kairpods")?
-      .serve_at("/org/kairpods/manager", service)?
-      .build()
+   // Create D-Bus service
+   let objects = dbus::device::DeviceObjectManager::new(bluetooth_manager.clone(), access.clone());
+   let service = AirPodsService::new(bluetooth_manager.clone(), passthrough, access);
+   let server = connection.object_server();
+   server.at(dbus::MANAGER_PATH, service).await?;
+   server
+      .at(dbus::device::OBJECT_MANAGER_PATH, objects)
       .await?;
+   connection.request_name("org.kairpods").await?;
+
+   if system_bus {
+      info!("kAirPods D-Bus service started at org.kairpods on the system bus");
+   } else {
+      info!("kAirPods D-Bus service started at org.kairpods");
+   }
+
+   // Reload the configuration when the file changes or on SIGHUP
+   if let Err(e) = reload::Reloader::spawn(
+      &connection,
+      system_bus,
+      hangup,
+      bluetooth_manager.clone(),
+      rules,
+      hooks,
+      tray_icon,
+      battery_provider_config,
+   )
+   .await
+   {
+      warn!("Failed to set up configuration reload: {e}");
+   }
 
-   info!("kAirPods D-Bus service started at org.kairpods");
+   if tray_icon {
+      if system_bus {
+         warn!("The tray icon is not available on the system bus");
+      } else if let Err(e) = dbus::tray::serve(&connection, bluetooth_manager).await {
+         warn!("Failed to export tray icon: {e}");
+      }
+   }
 
    // Start event processor
-   event_bus.spawn_dispatcher(connection).await?;
+   event_bus
+      .spawn_dispatcher(connection, battery_provider)
+      .await?;
 
    // Wait for shutdown signal
    signal::ctrl_c().await?;
@@ -144,11 +227,44 @@ impl EventProcessor {
 
    async fn dispatch(
       &self,
+      connection: &Connection,
       iface: &InterfaceRef<AirPodsService>,
+      battery_provider: Option<&BatteryProvider>,
       (device, event): (AirPods, AirPodsEvent),
    ) -> Result<()> {
+      if let Some(provider) = battery_provider
+         && let Err(e) = provider.dispatch(&device, &event).await
+      {
+         warn!(
+            "Failed to update BlueZ battery for {}: {e}",
+            device.address()
+         );
+      }
+      if let Err(e) = dbus::device::dispatch(connection.object_server(), &device, &event).await {
+         warn!(
+            "Failed to update the D-Bus object of {}: {e}",
+            device.address()
+         );
+      }
+      if let Err(e) = dbus::tray::dispatch(connection.object_server(), &event).await {
+         warn!("Failed to update the tray icon: {e}");
+      }
+
       let addr_str = device.address_str();
       match event {
+         AirPodsEvent::DeviceAdded | AirPodsEvent::DeviceRemoved => {
+            // Emit property changes
+            iface
+               .get_mut()
+               .await
+               .devices_changed(iface.signal_emitter())
+               .await?;
+            iface
+               .get_mut()
+               .await
+               .connected_count_changed(iface.signal_emitter())
+               .await?;
+         },
          AirPodsEvent::DeviceConnected => {
             iface.device_connected(addr_str).await?;
             // Emit property changes
@@ -197,6 +313,17 @@ impl EventProcessor {
                .devices_changed(iface.signal_emitter())
                .await?;
          },
+         AirPodsEvent::FeatureChanged(feature, enabled) => {
+            iface
+               .feature_changed(addr_str, feature.to_str(), enabled)
+               .await?;
+            // Emit property change for devices (feature toggled)
+            iface
+               .get_mut()
+               .await
+               .devices_changed(iface.signal_emitter())
+               .await?;
+         },
          AirPodsEvent::EarDetectionChanged(ear_detection) => {
             iface
                .ear_detection_changed(addr_str, &ear_detection.to_json().to_string())
@@ -217,6 +344,50 @@ impl EventProcessor {
                .devices_changed(iface.signal_emitter())
                .await?;
          },
+         AirPodsEvent::PrimaryBudChanged(primary) => {
+            iface
+               .primary_bud_changed(addr_str, primary.to_str())
+               .await?;
+            // Emit property change for devices (primary bud changed)
+            iface
+               .get_mut()
+               .await
+               .devices_changed(iface.signal_emitter())
+               .await?;
+         },
+         AirPodsEvent::TransparencyChanged(settings) => {
+            iface
+               .transparency_changed(addr_str, &settings.to_json().to_string())
+               .await?;
+            // Emit property change for devices (transparency settings changed)
+            iface
+               .get_mut()
+               .await
+               .devices_changed(iface.signal_emitter())
+               .await?;
+         },
+         AirPodsEvent::ConnectionStateChanged(info) => {
+            iface
+               .connection_state_changed(addr_str, &info.to_json().to_string())
+               .await?;
+            // Emit property change for devices (connection state changed)
+            iface
+               .get_mut()
+               .await
+               .devices_changed(iface.signal_emitter())
+               .await?;
+         },
+         AirPodsEvent::ProfileApplied(report) => {
+            iface
+               .profile_applied(addr_str, &report.to_json().to_string())
+               .await?;
+            // Emit property change for devices (features changed by the profile)
+            iface
+               .get_mut()
+               .await
+               .devices_changed(iface.signal_emitter())
+               .await?;
+         },
          AirPodsEvent::DeviceError => {
             iface.device_error(addr_str).await?;
             // Emit property change for devices (error state might affect device info)
@@ -230,14 +401,21 @@ impl EventProcessor {
       Ok(())
    }
 
-   async fn spawn_dispatcher(self: Arc<Self>, connection: Connection) -> Result<()> {
+   async fn spawn_dispatcher(
+      self: Arc<Self>,
+      connection: Connection,
+      battery_provider: Option<Arc<BatteryProvider>>,
+   ) -> Result<()> {
       let iface = connection
          .object_server()
-         .interface::<_, AirPodsService>("/org/kairpods/manager")
+         .interface::<_, AirPodsService>(dbus::MANAGER_PATH)
          .await?;
       tokio::spawn(async move {
          while let Some(event) = self.recv().await {
-            if let Err(e) = self.dispatch(&iface, event).await {
+            if let Err(e) = self
+               .dispatch(&connection, &iface, battery_provider.as_deref(), event)
+               .await
+            {
                warn!("Error dispatching event: {e}");
             }
          }
@@ -0,0 +1,192 @@
+//! Hot reload of the configuration file.
+//!
+//! The directory of the configuration is watched with inotify, since the file
+//! is replaced rather than written in place by the service and by most
+//! editors, and `SIGHUP` forces a reload. The file is read once it has not
+//! changed for a moment, so the bursts of events of a save cause one reload. A
+//! file that fails to parse or validate is rejected and the running
+//! configuration is kept.
+
+use std::{
+   ffi::OsString,
+   io,
+   path::{Path, PathBuf},
+   sync::Arc,
+   time::Duration,
+};
+
+use futures::StreamExt;
+use inotify::{EventOwned, EventStream, Inotify, WatchMask};
+use log::{debug, info, warn};
+use tokio::{
+   signal::unix::Signal,
+   time::{self, Instant},
+};
+use zbus::{Connection, object_server::InterfaceRef};
+
+use crate::{
+   bluetooth::manager::BluetoothManager,
+   config::{BatteryProviderConfig, Config},
+   dbus::{self, AirPodsService},
+   error::Result,
+   hooks::HookRunner,
+   logging,
+   rules::RulesEngine,
+};
+
+/// Time without changes to the file letting an editor finish saving before it is read
+const RELOAD_DEBOUNCE: Duration = Duration::from_millis(200);
+
+type ConfigEvents = EventStream<[u8; 1024]>;
+
+/// Pushes the configuration file into the running components whenever it changes.
+pub struct Reloader {
+   path: PathBuf,
+   manager: BluetoothManager,
+   rules: Arc<RulesEngine>,
+   hooks: Arc<HookRunner>,
+   iface: InterfaceRef<AirPodsService>,
+   system_bus: bool,
+   /// Settings only read at startup.
+   tray_icon: bool,
+   battery_provider: BatteryProviderConfig,
+}
+
+impl Reloader {
+   /// Starts reloading on changes of the file and on `hangup`, the `SIGHUP` stream.
+   #[allow(clippy::too_many_arguments)]
+   pub async fn spawn(
+      connection: &Connection,
+      system_bus: bool,
+      hangup: Signal,
+      manager: BluetoothManager,
+      rules: Arc<RulesEngine>,
+      hooks: Arc<HookRunner>,
+      tray_icon: bool,
+      battery_provider: BatteryProviderConfig,
+   ) -> Result<()> {
+      let iface = connection
+         .object_server()
+         .interface::<_, AirPodsService>(dbus::MANAGER_PATH)
+         .await?;
+      let path = Config::config_path()?;
+      let events = watch(&path)
+         .inspect_err(|e| {
+            warn!(
+               "Failed to watch {}, reload it with SIGHUP: {e}",
+               path.display()
+            );
+         })
+         .ok();
+
+      let reloader = Self {
+         path,
+         manager,
+         rules,
+         hooks,
+         iface,
+         system_bus,
+         tray_icon,
+         battery_provider,
+      };
+      tokio::spawn(reloader.run(hangup, events));
+      Ok(())
+   }
+
+   async fn run(self, mut hangup: Signal, mut events: Option<ConfigEvents>) {
+      let file_name: Option<OsString> = self.path.file_name().map(Into::into);
+      // Deadline of the reload, pushed back by every change of the file
+      let mut pending: Option<Instant> = None;
+      loop {
+         tokio::select! {
+            _ = hangup.recv() => {
+               info!("Received SIGHUP, reloading the configuration");
+               pending = None;
+               self.reload(true).await;
+            },
+            Some(event) = next_event(&mut events) => match event {
+               Ok(event) if event.name == file_name => {
+                  pending = Some(Instant::now() + RELOAD_DEBOUNCE);
+               },
+               Ok(_) => {},
+               Err(e) => {
+                  warn!("Stopped watching the configuration: {e}");
+                  events = None;
+               },
+            },
+            () = sleep_until(pending) => {
+               pending = None;
+               self.reload(false).await;
+            },
+         }
+      }
+   }
+
+   /// Reloads the configuration, skipping the rest if the manager already has it
+   /// unless `forced`.
+   async fn reload(&self, forced: bool) {
+      let config = match Config::read(&self.path, self.system_bus) {
+         Ok(config) => config,
+         Err(e) => {
+            warn!("Rejected the new configuration, keeping the current one: {e}");
+            return;
+         },
+      };
+      match self.manager.reload_config(config.clone()).await {
+         Ok(true) => {},
+         Ok(false) if forced => {},
+         Ok(false) => {
+            debug!("Configuration file rewritten without changes");
+            return;
+         },
+         Err(e) => {
+            warn!("Failed to reload the configuration: {e}");
+            return;
+         },
+      }
+
+      logging::set_filter(config.log_filter.as_deref());
+      self.rules.set_config(config.rules.clone());
+      self.hooks.set_config(config.hooks.clone());
+      if config.tray_icon != self.tray_icon || config.battery_provider != self.battery_provider {
+         warn!("Changes to tray_icon and battery_provider take effect after a restart");
+      }
+      if let Err(e) = self
+         .iface
+         .get_mut()
+         .await
+         .apply_config(&config, self.iface.signal_emitter())
+         .await
+      {
+         warn!("Failed to announce the reloaded configuration: {e}");
+      }
+      info!(
+         "Reloaded configuration with {} known devices",
+         config.known_devices.len()
+      );
+   }
+}
+
+/// Watches the directory of `path` for files being written or moved in.
+fn watch(path: &Path) -> io::Result<ConfigEvents> {
+   let dir = path
+      .parent()
+      .ok_or_else(|| io::Error::other("configuration path has no directory"))?;
+   let mut inotify = Inotify::init()?;
+   inotify.add_watch(dir, WatchMask::CLOSE_WRITE | WatchMask::MOVED_TO)?;
+   inotify.event_stream([0; 1024])
+}
+
+async fn next_event(events: &mut Option<ConfigEvents>) -> Option<io::Result<EventOwned>> {
+   match events {
+      Some(events) => events.next().await,
+      None => std::future::pending().await,
+   }
+}
+
+async fn sleep_until(deadline: Option<Instant>) {
+   match deadline {
+      Some(deadline) => time::sleep_until(deadline).await,
+      None => std::future::pending().await,
+   }
+}
@@ -0,0 +1,448 @@
+//! Automation rules run on device events.
+//!
+//! The rules of the `[rules]` config section are evaluated on every event of the
+//! bus and once a minute for time-of-day triggers. A rule runs its actions when
+//! one of its triggers fires and all of its conditions hold for the device; in
+//! dry-run mode the actions are only logged.
+
+use std::{collections::HashMap, sync::Arc, time::Duration};
+
+use bluer::Address;
+use log::{debug, info, warn};
+use tokio::{
+   sync::{OnceCell, mpsc},
+   time,
+};
+use zbus::{Connection, zvariant};
+
+use crate::{
+   airpods::{device::AirPods, protocol::FeatureId},
+   bluetooth::battery_provider,
+   config::{Action, BatteryPolicy, Conditions, InEar, Rule, RulesConfig, TimeOfDay, Trigger},
+   error::{AirPodsError, Result},
+   event::{AirPodsEvent, EventBus, EventSender},
+   hooks,
+};
+
+/// Time after which the command of a `run` action is killed.
+const RUN_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Event bus sink evaluating the automation rules.
+pub struct RulesEngine {
+   tx: mpsc::UnboundedSender<(AirPods, AirPodsEvent)>,
+   rx: parking_lot::Mutex<Option<mpsc::UnboundedReceiver<(AirPods, AirPodsEvent)>>>,
+   config: Arc<parking_lot::RwLock<Arc<RulesConfig>>>,
+}
+
+impl RulesEngine {
+   pub fn new(config: RulesConfig) -> Arc<Self> {
+      let (tx, rx) = mpsc::unbounded_channel();
+      Arc::new(Self {
+         tx,
+         rx: parking_lot::Mutex::new(Some(rx)),
+         config: Arc::new(parking_lot::RwLock::new(Arc::new(config))),
+      })
+   }
+
+   /// Replaces the rules, starting with the next event.
+   pub fn set_config(&self, config: RulesConfig) {
+      log_rules(&config);
+      *self.config.write() = Arc::new(config);
+   }
+
+   /// Starts evaluating the rules, emitting the changes made by actions to `events`.
+   ///
+   /// `events` must not lead back to the engine: rules are not triggered by the
+   /// changes of other rules, which could otherwise undo each other in a loop.
+   pub fn start(&self, events: EventSender) {
+      let Some(rx) = self.rx.lock().take() else {
+         return;
+      };
+      log_rules(&self.config.read());
+      let engine = Engine {
+         config: self.config.clone(),
+         events,
+         devices: HashMap::new(),
+         battery: HashMap::new(),
+         last_tick: None,
+         session: OnceCell::new(),
+      };
+      tokio::spawn(engine.run(rx));
+   }
+}
+
+impl EventBus for RulesEngine {
+   fn emit(&self, device: &AirPods, event: AirPodsEvent) {
+      let _ = self.tx.send((device.clone(), event));
+   }
+}
+
+struct Engine {
+   config: Arc<parking_lot::RwLock<Arc<RulesConfig>>>,
+   events: EventSender,
+   devices: HashMap<Address, AirPods>,
+   /// Battery level of each device at its previous update, to detect crossings.
+   battery: HashMap<Address, u8>,
+   last_tick: Option<TimeOfDay>,
+   session: OnceCell<Connection>,
+}
+
+impl Engine {
+   async fn run(mut self, mut rx: mpsc::UnboundedReceiver<(AirPods, AirPodsEvent)>) {
+      loop {
+         let (_, until_next_minute) = local_time();
+         tokio::select! {
+            event = rx.recv() => {
+               let Some((device, event)) = event else {
+                  return;
+               };
+               self.handle_event(&device, &event).await;
+            },
+            () = time::sleep(until_next_minute) => self.handle_tick().await,
+         }
+      }
+   }
+
+   async fn handle_event(&mut self, device: &AirPods, event: &AirPodsEvent) {
+      let addr = device.address();
+      let mut previous = None;
+      let mut level = None;
+      match event {
+         AirPodsEvent::DeviceRemoved => {
+            self.devices.remove(&addr);
+            self.battery.remove(&addr);
+         },
+         AirPodsEvent::DeviceDisconnected => {
+            self.battery.remove(&addr);
+         },
+         AirPodsEvent::BatteryUpdated(battery) => {
+            level = battery_provider::percentage(BatteryPolicy::Min, battery);
+            previous = match level {
+               Some(level) => self.battery.insert(addr, level),
+               None => self.battery.remove(&addr),
+            };
+         },
+         _ => {},
+      }
+      if !matches!(event, AirPodsEvent::DeviceRemoved) {
+         self.devices.insert(addr, device.clone());
+      }
+
+      let kind = event.kind();
+      let (now, _) = local_time();
+      let config = self.config.read().clone();
+      for rule in &config.rules {
+         let fired = rule.on.iter().find(|trigger| match **trigger {
+            Trigger::Event(k) => k == kind,
+            Trigger::BatteryBelow(_) | Trigger::BatteryAbove(_) => {
+               crosses(**trigger, previous, level)
+            },
+            Trigger::Time(_) => false,
+         });
+         if let Some(trigger) = fired {
+            self.evaluate(rule, *trigger, device, now).await;
+         }
+      }
+   }
+
+   async fn handle_tick(&mut self) {
+      let (now, _) = local_time();
+      if self.last_tick.replace(now) == Some(now) {
+         return;
+      }
+      let config = self.config.read().clone();
+      for rule in &config.rules {
+         let Some(trigger) = rule.on.iter().find(|t| **t == Trigger::Time(now)) else {
+            continue;
+         };
+         for device in self.devices.values().filter(|d| d.is_connected()) {
+            self.evaluate(rule, *trigger, device, now).await;
+         }
+      }
+   }
+
+   async fn evaluate(&self, rule: &Rule, trigger: Trigger, device: &AirPods, now: TimeOfDay) {
+      let addr = device.address();
+      if let Err(reason) = check(&rule.conditions, device, now) {
+         debug!(
+            "Rule '{}' triggered by {trigger:?} on {addr}, skipped: {reason}",
+            rule.name
+         );
+         return;
+      }
+      if self.config.read().dry_run {
+         info!(
+            "Rule '{}' triggered by {trigger:?} on {addr}, would run {:?}",
+            rule.name, rule.actions
+         );
+         return;
+      }
+
+      info!("Rule '{}' triggered by {trigger:?} on {addr}", rule.name);
+      for action in &rule.actions {
+         if let Err(e) = self.execute(rule, action, device).await {
+            warn!(
+               "Rule '{}' failed to run {action:?} on {addr}: {e}",
+               rule.name
+            );
+         }
+      }
+   }
+
+   async fn execute(&self, rule: &Rule, action: &Action, device: &AirPods) -> Result<()> {
+      match action {
+         Action::NoiseMode(mode) => {
+            if device.noise_mode() != Some(*mode) {
+               device.set_noise_control(*mode).await?;
+               self
+                  .events
+                  .emit(device, AirPodsEvent::NoiseControlChanged(*mode));
+            }
+         },
+         Action::Feature { name, enabled } => {
+            let feature: FeatureId = name
+               .parse()
+               .map_err(|_| AirPodsError::FeatureNotSupported(name.to_string()))?;
+            if !device.features().contains(&(feature, *enabled)) {
+               device.set_feature(feature, *enabled).await?;
+               self
+                  .events
+                  .emit(device, AirPodsEvent::FeatureChanged(feature, *enabled));
+            }
+         },
+         Action::Notify(body) => {
+            let session = self.session.get_or_try_init(Connection::session).await?;
+            session
+               .call_method(
+                  Some("org.freedesktop.Notifications"),
+                  "/org/freedesktop/Notifications",
+                  Some("org.freedesktop.Notifications"),
+                  "Notify",
+                  &(
+                     "kAirPods",
+                     0u32,
+                     "audio-headphones",
+                     device.name().as_str(),
+                     body.as_str(),
+                     Vec::<&str>::new(),
+                     HashMap::<&str, zvariant::Value<'_>>::new(),
+                     -1i32,
+                  ),
+               )
+               .await?;
+         },
+         Action::Run(command) => {
+            let label = format!("Rule '{}':", rule.name);
+            let command = command.clone();
+            let env = [
+               ("KAIRPODS_RULE", rule.name.to_string()),
+               ("KAIRPODS_ADDRESS", device.address_str().to_string()),
+               ("KAIRPODS_NAME", device.name().to_string()),
+            ];
+            tokio::spawn(async move {
+               if let Err(e) = hooks::run(&label, &command, &env, "", RUN_TIMEOUT).await {
+                  warn!("{label} failed to run {command:?}: {e}");
+               }
+            });
+         },
+      }
+      Ok(())
+   }
+}
+
+fn log_rules(config: &RulesConfig) {
+   if !config.rules.is_empty() {
+      info!(
+         "Loaded {} automation rules{}",
+         config.rules.len(),
+         if config.dry_run { " (dry run)" } else { "" }
+      );
+   }
+}
+
+/// Gets the local time of day and the time left until the next minute.
+fn local_time() -> (TimeOfDay, Duration) {
+   let now = jiff::Zoned::now();
+   let time = TimeOfDay {
+      hour: now.hour() as u8,
+      minute: now.minute() as u8,
+   };
+   let elapsed = Duration::from_secs(now.second() as u64)
+      + Duration::from_nanos(now.subsec_nanosecond() as u64);
+   (time, Duration::from_secs(60).saturating_sub(elapsed))
+}
+
+/// Checks if a battery level change fires a threshold trigger.
+///
+/// The first level reported after connecting fires it if already past the threshold.
+fn crosses(trigger: Trigger, previous: Option<u8>, level: Option<u8>) -> bool {
+   match (trigger, level) {
+      (Trigger::BatteryBelow(threshold), Some(level)) => {
+         level < threshold && previous.is_none_or(|p| p >= threshold)
+      },
+      (Trigger::BatteryAbove(threshold), Some(level)) => {
+         level > threshold && previous.is_none_or(|p| p <= threshold)
+      },
+      _ => false,
+   }
+}
+
+/// Checks if `now` is within the window, which wraps around midnight if `after` is later than `before`.
+fn in_window(now: TimeOfDay, after: Option<TimeOfDay>, before: Option<TimeOfDay>) -> bool {
+   match (after, before) {
+      (Some(after), Some(before)) if after > before => now >= after || now < before,
+      (after, before) => after.is_none_or(|a| now >= a) && before.is_none_or(|b| now < b),
+   }
+}
+
+/// Checks the conditions of a rule, returning the first one that does not hold.
+fn check(conditions: &Conditions, device: &AirPods, now: TimeOfDay) -> Result<(), &'static str> {
+   if let Some(want) = &conditions.device
+      && !device.address_str().eq_ignore_ascii_case(want)
+      && device.name() != *want
+   {
+      return Err("other device");
+   }
+   if let Some(want) = conditions.connected
+      && device.is_connected() != want
+   {
+      return Err("connection state differs");
+   }
+   if let Some(want) = conditions.in_ear {
+      let ear = device
+         .ear_detection()
+         .ok_or("ear detection state unknown")?;
+      let (left, right) = (ear.is_left_in_ear(), ear.is_right_in_ear());
+      let holds = match want {
+         InEar::Neither => !left && !right,
+         InEar::Any => left || right,
+         InEar::Both => left && right,
+      };
+      if !holds {
+         return Err("buds in ear differ");
+      }
+   }
+   if conditions.battery_above.is_some() || conditions.battery_below.is_some() {
+      let level = device
+         .battery_info()
+         .and_then(|b| battery_provider::percentage(BatteryPolicy::Min, &b))
+         .ok_or("battery level unknown")?;
+      if conditions.battery_above.is_some_and(|t| level <= t) {
+         return Err("battery level too low");
+      }
+      if conditions.battery_below.is_some_and(|t| level >= t) {
+         return Err("battery level too high");
+      }
+   }
+   if let Some(want) = conditions.noise_mode
+      && device.noise_mode() != Some(want)
+   {
+      return Err("noise mode differs");
+   }
+   if !in_window(now, conditions.after, conditions.before) {
+      return Err("outside of the time window");
+   }
+   Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+   use super::*;
+   use crate::airpods::protocol::{BatteryInfo, BatteryState, BatteryStatus, NoiseControlMode};
+
+   fn time(s: &str) -> TimeOfDay {
+      s.parse().unwrap()
+   }
+
+   #[test]
+   fn test_battery_crossing() {
+      let below = Trigger::BatteryBelow(20);
+      assert!(crosses(below, Some(25), Some(19)));
+      assert!(crosses(below, None, Some(10)));
+      assert!(!crosses(below, Some(19), Some(18)));
+      assert!(!crosses(below, Some(25), None));
+
+      let above = Trigger::BatteryAbove(80);
+      assert!(crosses(above, Some(80), Some(81)));
+      assert!(!crosses(above, Some(81), Some(82)));
+   }
+
+   #[test]
+   fn test_time_window() {
+      assert!(in_window(time("12:00"), None, None));
+      assert!(in_window(time("18:30"), Some(time("18:00")), None));
+      assert!(!in_window(
+         time("17:59"),
+         Some(time("18:00")),
+         Some(time("22:00"))
+      ));
+      assert!(!in_window(
+         time("22:00"),
+         Some(time("18:00")),
+         Some(time("22:00"))
+      ));
+      assert!(in_window(
+         time("23:00"),
+         Some(time("22:00")),
+         Some(time("07:00"))
+      ));
+      assert!(in_window(
+         time("06:59"),
+         Some(time("22:00")),
+         Some(time("07:00"))
+      ));
+      assert!(!in_window(
+         time("12:00"),
+         Some(time("22:00")),
+         Some(time("07:00"))
+      ));
+      assert!("24:00".parse::<TimeOfDay>().is_err());
+   }
+
+   #[test]
+   fn test_conditions() {
+      let config: RulesConfig = toml::from_str(
+         r#"
+         [[rule]]
+         name = "anc"
+         on = [{ event = "ear_detection_changed" }, { battery_below = 20 }, { time = "18:00" }]
+         if = { device = "AirPods Pro", battery_above = 20, noise_mode = "transparency" }
+         do = [{ noise_mode = "anc" }, { feature = { name = "conversational", enabled = false } }]
+         "#,
+      )
+      .unwrap();
+      let rule = &config.rules[0];
+      assert_eq!(rule.on[2], Trigger::Time(time("18:00")));
+      assert_eq!(rule.actions[0], Action::NoiseMode(NoiseControlMode::Active));
+
+      let device = AirPods::new(
+         "AA:BB:CC:DD:EE:FF".parse().unwrap(),
+         "AirPods Pro".to_string(),
+         "hci0".into(),
+         None,
+      );
+      let now = time("12:00");
+      assert_eq!(
+         check(&rule.conditions, &device, now),
+         Err("battery level unknown")
+      );
+
+      let bud = |level| BatteryState {
+         level,
+         status: BatteryStatus::Discharging,
+      };
+      device.update_battery_info(BatteryInfo {
+         left: bud(50),
+         right: bud(30),
+         ..BatteryInfo::new()
+      });
+      device.update_noise_mode(NoiseControlMode::Transparency);
+      assert_eq!(check(&rule.conditions, &device, now), Ok(()));
+
+      device.update_noise_mode(NoiseControlMode::Active);
+      assert_eq!(
+         check(&rule.conditions, &device, now),
+         Err("noise mode differs")
+      );
+   }
+}